@@ -2,13 +2,10 @@ extern crate africastalking_gateway;
 #[macro_use]
 extern crate serde_json;
 
-use std::env;
 use africastalking_gateway::AfricasTalkingGateway;
 
 pub fn main() {
-    let username = env::var("AFRICAS_TALKING_USERNAME").unwrap();
-    let apikey = env::var("AFRICAS_TALKING_APIKEY").unwrap();
-    let gateway = AfricasTalkingGateway::new(&username, &apikey, "sandbox");
+    let gateway = AfricasTalkingGateway::from_env().unwrap_or_else(|e| panic!("{}", e));
 
     let recipients = json!([
                            {
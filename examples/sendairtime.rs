@@ -1,21 +1,14 @@
 extern crate africastalking_gateway;
-#[macro_use]
-extern crate serde_json;
 
-use std::env;
 use africastalking_gateway::AfricasTalkingGateway;
+use africastalking_gateway::airtime::AirtimeRecipient;
+use africastalking_gateway::money::Money;
 
 pub fn main() {
-    let username = env::var("AFRICAS_TALKING_USERNAME").unwrap();
-    let apikey = env::var("AFRICAS_TALKING_APIKEY").unwrap();
-    let gateway = AfricasTalkingGateway::new(&username, &apikey, "sandbox");
+    let gateway = AfricasTalkingGateway::from_env().unwrap_or_else(|e| panic!("{}", e));
 
-    let recipients = json!([
-                           {
-     "phoneNumber": "+254702006545",
-     "amount": "KES 500"
-     }
-    ]);
+    let amount = Money::whole("KES", 500);
+    let recipients = vec![AirtimeRecipient::new("+254702006545", amount)];
 
-    println!("{:?}", gateway.send_airtime(&recipients));
+    println!("{:?}", gateway.send_airtime(&recipients, None, None));
 }
@@ -1,14 +1,11 @@
 extern crate africastalking_gateway;
 
-use std::env;
 use africastalking_gateway::{AfricasTalkingGateway, SMSMessage};
 
 pub fn main() {
-    let username = env::var("AFRICAS_TALKING_USERNAME").unwrap();
-    let apikey = env::var("AFRICAS_TALKING_APIKEY").unwrap();
-    let gway = AfricasTalkingGateway::new(&username, &apikey, "sandbox");
+    let gway = AfricasTalkingGateway::from_env().unwrap_or_else(|e| panic!("{}", e));
     let msg = SMSMessage {
-        username,
+        username: gway.username().into(),
         to: "+254702006545".to_string(),
         message: "hello matt".to_string(),
         ..Default::default()
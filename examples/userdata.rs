@@ -1,12 +1,9 @@
 extern crate africastalking_gateway;
 
-use std::env;
 use africastalking_gateway::AfricasTalkingGateway;
 
 pub fn main() {
-    let username = env::var("AFRICAS_TALKING_USERNAME").unwrap();
-    let apikey = env::var("AFRICAS_TALKING_APIKEY").unwrap();
-    let gway = AfricasTalkingGateway::new(&username, &apikey, "sandbox");
+    let gway = AfricasTalkingGateway::from_env().unwrap_or_else(|e| panic!("{}", e));
 
     println!("{}", gway.get_user_data().unwrap());
 }
@@ -0,0 +1,9 @@
+extern crate africastalking_gateway;
+
+use africastalking_gateway::AfricasTalkingGateway;
+
+pub fn main() {
+    let gway = AfricasTalkingGateway::from_env().unwrap_or_else(|e| panic!("{}", e));
+
+    println!("{:?}", gway.get_wallet_balance());
+}
@@ -0,0 +1,291 @@
+//! Airtime API types and callback parsing.
+use json;
+use serde_urlencoded;
+
+use super::Result;
+use money::Money;
+
+/// Airtime Recipient Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct AirtimeRecipient {
+    /// phone number to send airtime to, in international format
+    pub phoneNumber: String,
+
+    /// amount of airtime to send
+    pub amount: Money,
+}
+
+impl AirtimeRecipient {
+    /// creates a new airtime recipient
+    pub fn new(phone_number: &str, amount: Money) -> Self {
+        Self {
+            phoneNumber: phone_number.into(),
+            amount,
+        }
+    }
+
+    /// The recipient as sent to the API, with the amount formatted as `"KES X"`.
+    pub(crate) fn to_value(&self) -> json::Value {
+        json!({
+            "phoneNumber": self.phoneNumber,
+            "amount": format!("{} {}", self.amount.currency, self.amount.amount())
+        })
+    }
+}
+
+/// Airtime Response Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct AirtimeResponse {
+    /// number of requests sent to the provider
+    #[serde(default)]
+    pub numSent: i32,
+
+    /// total airtime sent e.g. `"KES 100.0000"`
+    #[serde(default)]
+    pub totalAmount: String,
+
+    /// total discount applied e.g. `"KES 4.0000"`
+    #[serde(default)]
+    pub totalDiscount: String,
+
+    /// error message, `"None"` on success
+    #[serde(default)]
+    pub errorMessage: String,
+
+    /// one entry per recipient
+    #[serde(default)]
+    pub responses: Vec<AirtimeEntry>,
+}
+
+/// Airtime Entry Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct AirtimeEntry {
+    /// recipient's phone number
+    pub phoneNumber: String,
+
+    /// amount sent e.g. `"KES 100.0000"`
+    #[serde(default)]
+    pub amount: String,
+
+    /// request status e.g. `Sent`, `Failed`
+    pub status: String,
+
+    /// unique request id, used to match status notifications
+    #[serde(default)]
+    pub requestId: String,
+
+    /// discount applied e.g. `"KES 4.0000"`
+    #[serde(default)]
+    pub discount: String,
+
+    /// error message, `"None"` on success
+    #[serde(default)]
+    pub errorMessage: String,
+}
+
+/// Airtime Validation Request Struct
+///
+/// Sent as JSON to the application's validation callback URL before airtime
+/// is dispatched; answer with an `AirtimeValidationResponse`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct AirtimeValidationRequest {
+    /// transaction id
+    pub transactionId: String,
+
+    /// recipient's phone number
+    pub phoneNumber: String,
+
+    /// IP address of the client that made the request
+    #[serde(default)]
+    pub sourceIpAddress: String,
+
+    /// amount of airtime requested
+    pub amount: Money,
+}
+
+/// Validation callback body, with the amount and currency sent separately
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ValidationBody {
+    transactionId: String,
+    phoneNumber: String,
+    #[serde(default)]
+    sourceIpAddress: String,
+    currencyCode: String,
+    amount: json::Value,
+}
+
+impl AirtimeValidationRequest {
+    /// Parses the JSON body of a validation callback, reading its
+    /// `currencyCode` and `amount` into an exact `Money`.
+    pub fn from_json(body: &str) -> Result<Self> {
+        let body: ValidationBody = json::from_str(body)?;
+        let amount = match body.amount {
+            json::Value::String(amount) => amount,
+            amount => amount.to_string(),
+        };
+        Ok(Self {
+            transactionId: body.transactionId,
+            phoneNumber: body.phoneNumber,
+            sourceIpAddress: body.sourceIpAddress,
+            amount: Money::parse_amount(&body.currencyCode, &amount)?,
+        })
+    }
+}
+
+/// Airtime Validation Response Struct
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AirtimeValidationResponse {
+    /// either `Validated` or `Failed`
+    pub status: String,
+}
+
+impl AirtimeValidationResponse {
+    /// accepts the airtime request
+    pub fn validated() -> Self {
+        Self {
+            status: "Validated".into(),
+        }
+    }
+
+    /// rejects the airtime request
+    pub fn failed() -> Self {
+        Self {
+            status: "Failed".into(),
+        }
+    }
+
+    /// JSON body to return to the API
+    pub fn to_json(&self) -> Result<String> {
+        Ok(json::to_string(self)?)
+    }
+}
+
+/// Airtime Status Notification Struct
+///
+/// Posted as form data to the application's status callback URL once the
+/// provider has processed the request.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct AirtimeStatusNotification {
+    /// recipient's phone number
+    pub phoneNumber: String,
+
+    /// description of the final status
+    #[serde(default)]
+    pub description: String,
+
+    /// final status, either `Success` or `Failed`
+    pub status: String,
+
+    /// request id returned when the airtime was sent
+    pub requestId: String,
+
+    /// discount applied e.g. `"KES 0.6000"`
+    #[serde(default)]
+    pub discount: String,
+
+    /// airtime value e.g. `"KES 100.0000"`
+    #[serde(default)]
+    pub value: String,
+}
+
+impl AirtimeStatusNotification {
+    /// Parses the form encoded body of a status callback.
+    pub fn from_form(body: &str) -> Result<Self> {
+        Ok(serde_urlencoded::from_str(body)?)
+    }
+
+    /// true if the airtime was delivered
+    pub fn is_success(&self) -> bool {
+        self.status == "Success"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_recipient_amount() {
+        let recipient = AirtimeRecipient::new("+254711XXXYYY", Money::whole("KES", 100));
+        assert_eq!(
+            recipient.to_value(),
+            json!({"phoneNumber": "+254711XXXYYY", "amount": "KES 100"})
+        );
+    }
+
+    #[test]
+    fn keeps_large_amounts_exact() {
+        let amount = Money::parse_amount("UGX", "16777217.05").unwrap();
+        let recipient = AirtimeRecipient::new("+256711XXXYYY", amount);
+        assert_eq!(recipient.to_value()["amount"], "UGX 16777217.05");
+    }
+
+    #[test]
+    fn send_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(400, "Invalid recipients")]);
+        let amount = Money::whole("KES", 10);
+        let recipients = [AirtimeRecipient::new("+254711XXXYYY", amount)];
+        let err = ::tests::gateway(&url)
+            .send_airtime(&recipients, None, None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid recipients");
+    }
+
+    #[test]
+    fn send_fails_when_no_recipient_accepted() {
+        let (url, _) = ::tests::serve(&[(
+            201,
+            r#"{"errorMessage":"Insufficient balance","numSent":0,"responses":[]}"#,
+        )]);
+        let amount = Money::whole("KES", 10);
+        let recipients = [AirtimeRecipient::new("+254711XXXYYY", amount)];
+        let err = ::tests::gateway(&url)
+            .send_airtime(&recipients, None, None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Insufficient balance");
+    }
+
+    #[test]
+    fn parses_validation_request() {
+        let body = r#"{"transactionId":"ATQid_1","phoneNumber":"+254711XXXYYY",
+            "sourceIpAddress":"127.0.0.1","currencyCode":"KES","amount":16777217.05}"#;
+        let request = AirtimeValidationRequest::from_json(body).unwrap();
+        assert_eq!(request.transactionId, "ATQid_1");
+        assert_eq!(
+            request.amount,
+            Money::parse_amount("KES", "16777217.05").unwrap()
+        );
+
+        let body = r#"{"transactionId":"ATQid_2","phoneNumber":"+254711XXXYYY",
+            "currencyCode":"UGX","amount":"500.00"}"#;
+        let request = AirtimeValidationRequest::from_json(body).unwrap();
+        assert_eq!(request.amount, Money::whole("UGX", 500));
+    }
+
+    #[test]
+    fn validation_request_needs_currency_and_amount() {
+        let body = r#"{"transactionId":"ATQid_1","phoneNumber":"+254711XXXYYY",
+            "currencyCode":"","amount":100}"#;
+        assert!(AirtimeValidationRequest::from_json(body).is_err());
+        let body = r#"{"transactionId":"ATQid_1","phoneNumber":"+254711XXXYYY",
+            "currencyCode":"KES","amount":null}"#;
+        assert!(AirtimeValidationRequest::from_json(body).is_err());
+    }
+
+    #[test]
+    fn parses_status_notification() {
+        let body = "phoneNumber=%2B254711XXXYYY&description=Airtime+Delivered+Successfully\
+                    &status=Success&requestId=ATQid_SampleTxnId123\
+                    &discount=KES+0.6000&value=KES+100.0000";
+        let notification = AirtimeStatusNotification::from_form(body).unwrap();
+        assert_eq!(notification.phoneNumber, "+254711XXXYYY");
+        assert_eq!(notification.requestId, "ATQid_SampleTxnId123");
+        assert!(notification.is_success());
+    }
+}
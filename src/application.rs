@@ -0,0 +1,233 @@
+//! Application API: account data and balance monitoring.
+//!
+//! ```rust,ignore
+//! let gway = Arc::new(AfricasTalkingGateway::new(&username, &apikey, "sandbox"));
+//! let watcher = BalanceWatcher::new(Duration::from_secs(300))
+//!     .threshold(Money::whole("KES", 5000))
+//!     .threshold(Money::whole("KES", 1000))
+//!     .spawn(gway, |alert| match alert {
+//!         Ok(alert) => println!("balance {} under {}", alert.balance, alert.threshold),
+//!         Err(e) => println!("balance check failed: {}", e),
+//!     });
+//! // ...
+//! watcher.stop();
+//! ```
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use json;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use money::Money;
+use secret::redact;
+
+/// Account balance e.g. `KES 1,234.50`
+pub type Balance = Money;
+
+/// Application Data Struct
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApplicationData {
+    /// current account balance
+    pub balance: Balance,
+}
+
+impl AfricasTalkingGateway {
+    /// Fetches the application's data, including its balance.
+    /// [read more..](http://docs.africastalking.com/application)
+    pub fn get_application_data(&self) -> Result<ApplicationData> {
+        let mut resp = self.send_request(
+            &format!("{}?username={}", self.user_data_url, self.username),
+            None,
+        )?;
+        if resp.status().as_u16() == 200 {
+            let jsn: json::Value = resp.json()?;
+            Ok(json::from_value(jsn["UserData"].clone())?)
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+        }
+    }
+
+    /// Fetches the application's balance.
+    pub fn get_balance(&self) -> Result<Balance> {
+        Ok(self.get_application_data()?.balance)
+    }
+}
+
+/// Raised when the balance drops under a threshold
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceAlert {
+    /// balance when the alert was raised
+    pub balance: Balance,
+
+    /// the lowest threshold the balance is under
+    pub threshold: Money,
+}
+
+/// Polls the application balance and alerts when it drops under thresholds.
+///
+/// A balance is only compared with thresholds in its own currency. Each
+/// threshold alerts once as the balance falls through it; alerts are
+/// re-armed once the balance is topped up above every threshold in its
+/// currency.
+#[derive(Debug, Clone)]
+pub struct BalanceWatcher {
+    interval: Duration,
+    thresholds: Vec<Money>,
+    /// lowest threshold alerted, by currency
+    alerted: HashMap<String, i64>,
+}
+
+impl BalanceWatcher {
+    /// creates a watcher polling every `interval`, with no thresholds
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            thresholds: Vec::new(),
+            alerted: HashMap::new(),
+        }
+    }
+
+    /// alerts when a balance in `amount`'s currency drops under it
+    pub fn threshold(mut self, amount: Money) -> Self {
+        self.thresholds.push(amount);
+        self
+    }
+
+    /// Checks a balance against the thresholds, returning an alert for the
+    /// lowest newly crossed threshold.
+    pub fn check(&mut self, balance: &Balance) -> Option<BalanceAlert> {
+        let crossed = self
+            .thresholds
+            .iter()
+            .filter(|t| t.currency == balance.currency && balance.units < t.units)
+            .map(|t| t.units)
+            .min();
+        match crossed {
+            None => {
+                self.alerted.remove(&balance.currency);
+                None
+            }
+            Some(threshold) => {
+                match self.alerted.get(&balance.currency) {
+                    Some(&alerted) if alerted <= threshold => return None,
+                    _ => {}
+                }
+                self.alerted.insert(balance.currency.clone(), threshold);
+                Some(BalanceAlert {
+                    balance: balance.clone(),
+                    threshold: Money::from_units(&balance.currency, threshold),
+                })
+            }
+        }
+    }
+
+    /// Fetches the balance once, calling `callback` if a threshold was crossed.
+    pub fn poll<F>(&mut self, gateway: &AfricasTalkingGateway, mut callback: F) -> Result<Balance>
+    where
+        F: FnMut(BalanceAlert),
+    {
+        let balance = gateway.get_balance()?;
+        if let Some(alert) = self.check(&balance) {
+            callback(alert);
+        }
+
+        Ok(balance)
+    }
+
+    /// Polls in a background thread until the returned handle is stopped
+    /// or dropped. Errors fetching the balance are passed to `callback`.
+    pub fn spawn<F>(mut self, gateway: Arc<AfricasTalkingGateway>, mut callback: F) -> WatchHandle
+    where
+        F: FnMut(Result<BalanceAlert>) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || loop {
+            match self.poll(&gateway, |alert| callback(Ok(alert))) {
+                Ok(_) => {}
+                Err(e) => callback(Err(e)),
+            }
+            match stopped.recv_timeout(self.interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
+        });
+
+        WatchHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Handle to a balance watcher running in the background
+#[derive(Debug)]
+pub struct WatchHandle {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WatchHandle {
+    /// stops the watcher and waits for its thread to finish
+    pub fn stop(mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_once_per_threshold() {
+        let mut watcher = BalanceWatcher::new(Duration::from_secs(60))
+            .threshold(Money::whole("KES", 1000))
+            .threshold(Money::whole("KES", 100));
+        let kes = |amount| Money::whole("KES", amount);
+
+        assert_eq!(watcher.check(&kes(5000)), None);
+        assert_eq!(watcher.check(&kes(900)).unwrap().threshold, kes(1000));
+        assert_eq!(watcher.check(&kes(800)), None);
+        assert_eq!(watcher.check(&kes(50)).unwrap().threshold, kes(100));
+        assert_eq!(watcher.check(&kes(500)), None);
+    }
+
+    #[test]
+    fn rearms_after_top_up() {
+        let mut watcher =
+            BalanceWatcher::new(Duration::from_secs(60)).threshold(Money::whole("KES", 1000));
+        assert!(watcher.check(&Money::whole("KES", 900)).is_some());
+        assert_eq!(watcher.check(&Money::whole("KES", 2000)), None);
+        assert!(watcher.check(&Money::whole("KES", 999)).is_some());
+    }
+
+    #[test]
+    fn ignores_thresholds_in_other_currencies() {
+        let mut watcher = BalanceWatcher::new(Duration::from_secs(60))
+            .threshold(Money::whole("KES", 1000))
+            .threshold(Money::whole("UGX", 50_000));
+        let alert = watcher.check(&Money::whole("UGX", 20_000)).unwrap();
+        assert_eq!(alert.threshold, Money::whole("UGX", 50_000));
+        assert_eq!(watcher.check(&Money::whole("NGN", 10)), None);
+        assert!(watcher.check(&Money::whole("KES", 999)).is_some());
+    }
+
+    #[test]
+    fn parses_application_data() {
+        let data: ApplicationData = json::from_str(r#"{"balance": "KES 1,785.50"}"#).unwrap();
+        assert_eq!(data.balance, "KES 1785.5".parse().unwrap());
+    }
+
+    #[test]
+    fn balance_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(401, "The supplied authentication is invalid")]);
+        let err = ::tests::gateway(&url).get_balance().unwrap_err();
+        assert_eq!(err.to_string(), "The supplied authentication is invalid");
+    }
+}
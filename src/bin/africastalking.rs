@@ -0,0 +1,547 @@
+//! Command line access to the Africa's Talking gateway.
+//!
+//! Credentials are read from the command line, then the
+//! `AFRICAS_TALKING_USERNAME`, `AFRICAS_TALKING_APIKEY` and
+//! `AFRICAS_TALKING_ENV` environment variables, then a profile in the TOML
+//! config file (`--config`, default `~/.africastalking.toml`) chosen with
+//! `--profile` or `AFRICAS_TALKING_PROFILE`. The config file is not read
+//! when a username and API key are already given. See the `config` module
+//! for the file format.
+//!
+//! The API key is never taken as an argument, where it would show in shell
+//! history and process listings; `--api-key-stdin` reads it from stdin.
+//!
+//! ```text
+//! africastalking sms send --to +254711000001,+254711000002 --message "Hello"
+//! pass show africastalking | africastalking --api-key-stdin user balance
+//! africastalking --output table voice queue --phone-numbers +254711000000
+//! ```
+extern crate africastalking_gateway;
+extern crate clap;
+#[macro_use]
+extern crate serde_json as json;
+extern crate zeroize;
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process;
+
+use africastalking_gateway::airtime::AirtimeRecipient;
+use africastalking_gateway::config::{Config, Profile, API_KEY_VAR, ENV_VAR, USERNAME_VAR};
+use africastalking_gateway::money::Money;
+use africastalking_gateway::secret::SecretString;
+use africastalking_gateway::{AfricasTalkingGateway, ErrorKind, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use zeroize::Zeroize;
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let arg = |name: &'a str, help: &'b str| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .required(true)
+            .help(help)
+    };
+    let opt = |name: &'a str, help: &'b str| arg(name, help).required(false);
+
+    App::new("africastalking")
+        .about("Drives the Africa's Talking API from the shell")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(opt("username", "account username"))
+        .arg(
+            Arg::with_name("api-key-stdin")
+                .long("api-key-stdin")
+                .help("reads the account API key from the first line of stdin"),
+        )
+        .arg(opt("env", "`sandbox` or `production`"))
+        .arg(opt("config", "TOML config file with credential profiles"))
+        .arg(opt("profile", "config file profile to use"))
+        .arg(
+            opt("output", "output format")
+                .possible_values(&["json", "table"])
+                .default_value("json"),
+        )
+        .subcommand(
+            SubCommand::with_name("sms")
+                .about("SMS messages")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("send")
+                        .about("sends a message")
+                        .arg(arg("to", "comma separated phone numbers"))
+                        .arg(arg("message", "message text"))
+                        .arg(opt("from", "sender id or shortcode")),
+                )
+                .subcommand(
+                    SubCommand::with_name("fetch")
+                        .about("fetches inbound messages")
+                        .arg(opt("last-received-id", "id of the last message processed")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("subscription")
+                .about("premium SMS subscriptions")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("subscribes a phone number")
+                        .arg(arg("phone", "subscriber's phone number"))
+                        .arg(arg("short-code", "premium shortcode"))
+                        .arg(arg("keyword", "premium keyword"))
+                        .arg(opt("checkout-token", "checkout token for the phone number")),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("unsubscribes a phone number")
+                        .arg(arg("phone", "subscriber's phone number"))
+                        .arg(arg("short-code", "premium shortcode"))
+                        .arg(arg("keyword", "premium keyword")),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("lists subscriptions")
+                        .arg(arg("short-code", "premium shortcode"))
+                        .arg(arg("keyword", "premium keyword"))
+                        .arg(opt(
+                            "last-received-id",
+                            "id of the last subscription processed",
+                        )),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("airtime")
+                .about("airtime")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("send")
+                        .about("sends airtime")
+                        .arg(arg("to", "comma separated phone numbers"))
+                        .arg(arg("amount", "amount per phone number"))
+                        .arg(opt("currency", "currency code").default_value("KES")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("voice")
+                .about("voice calls")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("call")
+                        .about("places a call")
+                        .arg(arg("from", "virtual number to call from"))
+                        .arg(arg("to", "comma separated phone numbers")),
+                )
+                .subcommand(
+                    SubCommand::with_name("queue")
+                        .about("shows queued calls")
+                        .arg(arg("phone-numbers", "comma separated virtual numbers"))
+                        .arg(opt("queue-name", "queue name")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("payments")
+                .about("mobile payments")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("checkout")
+                        .about("requests a mobile checkout")
+                        .arg(arg("product", "payment product name"))
+                        .arg(arg("phone", "phone number to charge"))
+                        .arg(arg("amount", "amount to charge"))
+                        .arg(opt("currency", "currency code").default_value("KES"))
+                        .arg(opt("channel", "provider channel")),
+                )
+                .subcommand(
+                    SubCommand::with_name("b2c")
+                        .about("pays a mobile subscriber")
+                        .arg(arg("product", "payment product name"))
+                        .arg(arg("phone", "phone number to pay"))
+                        .arg(arg("amount", "amount to pay"))
+                        .arg(opt("currency", "currency code").default_value("KES"))
+                        .arg(opt("name", "recipient's name"))
+                        .arg(opt("reason", "payment reason e.g. SalaryPayment")),
+                )
+                .subcommand(
+                    SubCommand::with_name("b2b")
+                        .about("pays a business")
+                        .arg(arg("product", "payment product name"))
+                        .arg(arg("provider", "payment provider e.g. Athena"))
+                        .arg(arg("channel", "destination channel"))
+                        .arg(arg("account", "destination account"))
+                        .arg(arg("transfer-type", "transfer type e.g. BusinessPayBill"))
+                        .arg(arg("amount", "amount to pay"))
+                        .arg(opt("currency", "currency code").default_value("KES")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("user")
+                .about("account")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("balance").about("shows the account balance")),
+        )
+}
+
+/// Builds a gateway from a config file profile, overridden by the
+/// environment and then the command line. The config file is skipped when
+/// both the username and API key are given.
+fn gateway(matches: &ArgMatches) -> Result<AfricasTalkingGateway> {
+    let setting = |arg: &str, var: &str| {
+        matches
+            .value_of(arg)
+            .map(String::from)
+            .or_else(|| env::var(var).ok())
+            .filter(|value| !value.trim().is_empty())
+    };
+    let username = setting("username", USERNAME_VAR);
+    let api_key = if matches.is_present("api-key-stdin") {
+        Some(read_api_key(io::stdin().lock())?)
+    } else {
+        env::var(API_KEY_VAR)
+            .ok()
+            .filter(|key| !key.trim().is_empty())
+            .map(SecretString::from)
+    };
+    let environment = setting("env", ENV_VAR);
+
+    let mut profile = if username.is_some() && api_key.is_some() {
+        Profile::default()
+    } else {
+        config_profile(matches)?
+    };
+    if username.is_some() {
+        profile.username = username;
+    }
+    if api_key.is_some() {
+        profile.api_key = api_key;
+    }
+    if environment.is_some() {
+        profile.environment = environment;
+    }
+
+    // without a config file, point at the environment instead of a profile
+    if profile.name.is_empty() {
+        if profile.username.is_none() {
+            return Err(ErrorKind::MissingConfig(format!("{} or --username", USERNAME_VAR)).into());
+        }
+        if profile.api_key.is_none() {
+            let key = format!("{} or --api-key-stdin", API_KEY_VAR);
+            return Err(ErrorKind::MissingConfig(key).into());
+        }
+    }
+
+    profile.gateway()
+}
+
+/// The profile chosen from the config file, or an empty profile if there is
+/// no config file.
+fn config_profile(matches: &ArgMatches) -> Result<Profile> {
+    let config_path = match matches.value_of("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".africastalking.toml")),
+    };
+    match config_path {
+        Some(ref path) if path.exists() => {
+            let name = matches
+                .value_of("profile")
+                .map(String::from)
+                .or_else(|| env::var(PROFILE_VAR).ok());
+            Ok(Config::load(path)?.profile(name.as_deref())?.clone())
+        }
+        _ => Ok(Profile::default()),
+    }
+}
+
+/// Reads an API key from the first line of `input`.
+fn read_api_key<R: BufRead>(mut input: R) -> Result<SecretString> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let key = SecretString::new(line.trim());
+    line.zeroize();
+    if key.is_blank() {
+        // raise error
+        return Err(ErrorKind::MissingConfig("API key on stdin".into()).into());
+    }
+
+    Ok(key)
+}
+
+/// Environment variable naming the config file profile to use
+const PROFILE_VAR: &str = "AFRICAS_TALKING_PROFILE";
+
+fn list(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<T> {
+    let value = matches.value_of(name).unwrap_or("0");
+    value
+        .parse()
+        .map_err(|_| format!("--{} must be a number, got {:?}", name, value).into())
+}
+
+/// Runs the selected command, returning its result as JSON.
+fn run(gway: &AfricasTalkingGateway, matches: &ArgMatches) -> Result<json::Value> {
+    let value = match matches.subcommand() {
+        ("sms", Some(sms)) => match sms.subcommand() {
+            ("send", Some(m)) => gway.send_bulk_message(
+                &list(m.value_of("to").unwrap()),
+                m.value_of("message").unwrap(),
+                m.value_of("from"),
+            )?["SMSMessageData"]["Recipients"]
+                .take(),
+            ("fetch", Some(m)) => {
+                json::to_value(gway.fetch_messages(number(m, "last-received-id")?)?)?
+            }
+            _ => unreachable!(),
+        },
+        ("subscription", Some(sub)) => match sub.subcommand() {
+            ("create", Some(m)) => json::to_value(gway.create_subscription(
+                m.value_of("phone").unwrap(),
+                m.value_of("short-code").unwrap(),
+                m.value_of("keyword").unwrap(),
+                m.value_of("checkout-token"),
+            )?)?,
+            ("delete", Some(m)) => json::to_value(gway.delete_subscription(
+                m.value_of("phone").unwrap(),
+                m.value_of("short-code").unwrap(),
+                m.value_of("keyword").unwrap(),
+            )?)?,
+            ("list", Some(m)) => json::to_value(gway.fetch_subscriptions(
+                m.value_of("short-code").unwrap(),
+                m.value_of("keyword").unwrap(),
+                number(m, "last-received-id")?,
+            )?)?,
+            _ => unreachable!(),
+        },
+        ("airtime", Some(airtime)) => match airtime.subcommand() {
+            ("send", Some(m)) => {
+                let amount = Money::parse_amount(
+                    m.value_of("currency").unwrap(),
+                    m.value_of("amount").unwrap(),
+                )?;
+                let recipients: Vec<AirtimeRecipient> = list(m.value_of("to").unwrap())
+                    .into_iter()
+                    .map(|to| AirtimeRecipient::new(to, amount.clone()))
+                    .collect();
+                json::to_value(gway.send_airtime(&recipients, None, None)?.responses)?
+            }
+            _ => unreachable!(),
+        },
+        ("voice", Some(voice)) => match voice.subcommand() {
+            ("call", Some(m)) => json::to_value(gway.call(
+                m.value_of("from").unwrap(),
+                &list(m.value_of("to").unwrap()),
+                None,
+            )?)?,
+            ("queue", Some(m)) => json::to_value(gway.get_queued_calls(
+                m.value_of("phone-numbers").unwrap(),
+                m.value_of("queue-name"),
+            )?)?,
+            _ => unreachable!(),
+        },
+        ("payments", Some(payments)) => match payments.subcommand() {
+            ("checkout", Some(m)) => gway.init_mobile_payment_checkout(
+                m.value_of("product").unwrap(),
+                m.value_of("phone").unwrap(),
+                m.value_of("currency").unwrap(),
+                m.value_of("channel").unwrap_or(""),
+                number(m, "amount")?,
+                &HashMap::new(),
+            )?,
+            ("b2c", Some(m)) => {
+                let recipient = json!({
+                    "name": m.value_of("name").unwrap_or(""),
+                    "phoneNumber": m.value_of("phone").unwrap(),
+                    "currencyCode": m.value_of("currency").unwrap(),
+                    "amount": number::<f64>(m, "amount")?,
+                    "reason": m.value_of("reason").unwrap_or("BusinessPayment"),
+                    "metadata": {}
+                });
+                gway.mobile_payment_b2c_request(
+                    m.value_of("product").unwrap(),
+                    &json!([recipient]),
+                )?
+            }
+            ("b2b", Some(m)) => {
+                let mut provider_data = HashMap::new();
+                provider_data.insert("provider", m.value_of("provider").unwrap());
+                provider_data.insert("destination_channel", m.value_of("channel").unwrap());
+                provider_data.insert("destination_account", m.value_of("account").unwrap());
+                provider_data.insert("transfer_type", m.value_of("transfer-type").unwrap());
+                gway.mobile_payment_b2b_request(
+                    m.value_of("product").unwrap(),
+                    &provider_data,
+                    m.value_of("currency").unwrap(),
+                    number(m, "amount")?,
+                    &HashMap::new(),
+                )?
+            }
+            _ => unreachable!(),
+        },
+        ("user", Some(user)) => match user.subcommand() {
+            ("balance", Some(_)) => json::to_value(gway.get_application_data()?)?,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+
+    Ok(value)
+}
+
+/// Renders a value as a plain text table: arrays of objects get one row per
+/// element, objects one row per field.
+fn table(value: &json::Value) -> String {
+    fn cell(value: &json::Value) -> String {
+        match *value {
+            json::Value::String(ref s) => s.clone(),
+            json::Value::Null => String::new(),
+            ref other => other.to_string(),
+        }
+    }
+    fn render(rows: &[Vec<String>]) -> String {
+        let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|i| {
+                rows.iter()
+                    .filter_map(|r| r.get(i))
+                    .map(|c| c.chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        rows.iter()
+            .map(|row| {
+                let cells: Vec<String> = row
+                    .iter()
+                    .zip(&widths)
+                    .map(|(c, &w)| format!("{:width$}", c, width = w))
+                    .collect();
+                cells.join("  ").trim_end().to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    match *value {
+        json::Value::Array(ref items) => {
+            let mut headers: Vec<String> = Vec::new();
+            for item in items {
+                if let json::Value::Object(ref fields) = *item {
+                    for key in fields.keys() {
+                        if !headers.contains(key) {
+                            headers.push(key.clone());
+                        }
+                    }
+                }
+            }
+            if headers.is_empty() {
+                return items.iter().map(cell).collect::<Vec<_>>().join("\n");
+            }
+            let mut rows = vec![headers.clone()];
+            rows.extend(
+                items
+                    .iter()
+                    .map(|item| headers.iter().map(|h| cell(&item[h.as_str()])).collect()),
+            );
+            render(&rows)
+        }
+        json::Value::Object(ref fields) => {
+            let rows: Vec<Vec<String>> = fields
+                .iter()
+                .map(|(k, v)| vec![k.clone(), cell(v)])
+                .collect();
+            render(&rows)
+        }
+        ref other => cell(other),
+    }
+}
+
+fn main() {
+    let matches = app().get_matches();
+    let gway = match gateway(&matches) {
+        Ok(gway) => gway,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+    };
+    match run(&gway, &matches) {
+        Ok(value) => match matches.value_of("output") {
+            Some("table") => println!("{}", table(&value)),
+            _ => println!("{}", json::to_string_pretty(&value).unwrap()),
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<&str> {
+        line.split_whitespace().collect()
+    }
+
+    #[test]
+    fn list_trims_and_drops_empty_values() {
+        let numbers = list(" +254711000001, ,+254711000002,");
+        assert_eq!(numbers, vec!["+254711000001", "+254711000002"]);
+        assert!(list("").is_empty());
+    }
+
+    #[test]
+    fn number_parses_or_names_the_flag() {
+        let last_received_id = |value: &str| -> Result<i32> {
+            let line = format!("africastalking sms fetch --last-received-id {}", value);
+            let matches = app().get_matches_from(args(&line));
+            let sms = matches.subcommand_matches("sms").unwrap();
+            number(sms.subcommand_matches("fetch").unwrap(), "last-received-id")
+        };
+        assert_eq!(last_received_id("42").unwrap(), 42);
+        let err = last_received_id("forty").unwrap_err().to_string();
+        assert_eq!(err, "--last-received-id must be a number, got \"forty\"");
+    }
+
+    #[test]
+    fn table_renders_rows_per_element() {
+        let value = json!([
+            {"number": "+254711000001", "status": "Success"},
+            {"number": "+254711000002", "cost": "KES 0.8000"}
+        ]);
+        assert_eq!(
+            table(&value),
+            "number         status   cost\n\
+             +254711000001  Success\n\
+             +254711000002           KES 0.8000"
+        );
+    }
+
+    #[test]
+    fn table_renders_objects_and_scalars() {
+        let value = json!({"balance": "KES 100", "count": 2, "note": null});
+        assert_eq!(table(&value), "balance  KES 100\ncount    2\nnote");
+        assert_eq!(table(&json!(["a", 1])), "a\n1");
+        assert_eq!(table(&json!("plain")), "plain");
+    }
+
+    #[test]
+    fn reads_api_key_from_first_line() {
+        let key = read_api_key(&b"  secret-key \nignored\n"[..]).unwrap();
+        assert_eq!(key.expose(), "secret-key");
+        assert!(read_api_key(&b"\n"[..]).is_err());
+    }
+
+    #[test]
+    fn api_key_flag_is_not_accepted() {
+        let line = "africastalking --api-key secret user balance";
+        assert!(app().get_matches_from_safe(args(line)).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+//! Loading credentials and account settings from the environment or a
+//! TOML config file.
+//!
+//! A config file holds named profiles, e.g. one per environment or per
+//! country account:
+//!
+//! ```toml
+//! default = "sandbox"
+//!
+//! [profiles.sandbox]
+//! username = "sandbox"
+//! api_key = "..."
+//!
+//! [profiles.kenya]
+//! username = "acme-ke"
+//! api_key = "..."
+//! environment = "production"
+//! sender_id = "ACME"
+//! senders = { UG = "ACME UG", NG = "20880" }
+//! products = { payments = "Acme Store" }
+//! callbacks = { delivery = "https://acme.example/sms/delivery" }
+//! ```
+//!
+//! ```rust,ignore
+//! let gway = AfricasTalkingGateway::from_env()?;
+//!
+//! let config = Config::load("africastalking.toml")?;
+//! let profile = config.profile(Some("kenya"))?;
+//! let gway = profile.gateway()?;
+//! gway.init_mobile_payment_checkout(profile.product("payments")?, ..)?;
+//! ```
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use toml;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use secret::SecretString;
+use sender::{Country, Sender, SenderRegistry};
+
+/// Environment variable holding the account username
+pub const USERNAME_VAR: &str = "AFRICAS_TALKING_USERNAME";
+
+/// Environment variable holding the API key
+pub const API_KEY_VAR: &str = "AFRICAS_TALKING_APIKEY";
+
+/// Environment variable holding the environment, `sandbox` if unset
+pub const ENV_VAR: &str = "AFRICAS_TALKING_ENV";
+
+/// Config file contents
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    /// profile used when none is named
+    #[serde(default)]
+    pub default: Option<String>,
+
+    /// profiles by name
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    /// Reads a config file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Self::parse(&text)
+    }
+
+    /// Parses config file contents.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(text)?;
+        for (name, profile) in &mut config.profiles {
+            profile.name = name.clone();
+        }
+
+        Ok(config)
+    }
+
+    /// The profile called `name`, or the default profile. A config with a
+    /// single profile needs no default.
+    pub fn profile(&self, name: Option<&str>) -> Result<&Profile> {
+        let name = match name.or(self.default.as_deref()) {
+            Some(name) => name,
+            None if self.profiles.len() == 1 => return Ok(self.profiles.values().next().unwrap()),
+            None => return Err(ErrorKind::MissingConfig("default".into()).into()),
+        };
+        self.profiles
+            .get(name)
+            .ok_or_else(|| ErrorKind::MissingConfig(format!("profiles.{}", name)).into())
+    }
+
+    /// Creates a gateway from the profile called `name`, or the default
+    /// profile.
+    pub fn gateway(&self, name: Option<&str>) -> Result<AfricasTalkingGateway> {
+        self.profile(name)?.gateway()
+    }
+}
+
+/// Account settings for one profile
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Profile {
+    /// the profile's name in the config file
+    #[serde(skip)]
+    pub name: String,
+
+    /// Africa's Talking account username
+    #[serde(default)]
+    pub username: Option<String>,
+
+    /// API key
+    #[serde(default)]
+    pub api_key: Option<SecretString>,
+
+    /// `sandbox` or `production`; `sandbox` if unset or blank
+    #[serde(default)]
+    pub environment: Option<String>,
+
+    /// sender id or shortcode for countries without one in `senders`
+    #[serde(default)]
+    pub sender_id: Option<String>,
+
+    /// sender ids or shortcodes by 2-letter ISO country code
+    #[serde(default)]
+    pub senders: HashMap<String, String>,
+
+    /// payment product names, by what the application calls them
+    #[serde(default)]
+    pub products: HashMap<String, String>,
+
+    /// callback URLs, by what the application calls them
+    #[serde(default)]
+    pub callbacks: HashMap<String, String>,
+}
+
+impl Profile {
+    /// Creates a gateway with the profile's credentials, routing messages
+    /// through its senders if it has any.
+    pub fn gateway(&self) -> Result<AfricasTalkingGateway> {
+        let username = self.required("username", &self.username)?;
+        let api_key = match self.api_key {
+            Some(ref key) if !key.is_blank() => key.expose(),
+            _ => return Err(ErrorKind::MissingConfig(self.key("api_key")).into()),
+        };
+        let env = environment(self.environment.as_deref());
+        check_environment(&self.key("environment"), env)?;
+
+        let gway = AfricasTalkingGateway::new(username, api_key, env);
+        Ok(match self.sender_registry()? {
+            Some(registry) => gway.with_sender_registry(registry),
+            None => gway,
+        })
+    }
+
+    /// The profile's senders, `None` if it has none.
+    pub fn sender_registry(&self) -> Result<Option<SenderRegistry>> {
+        if self.sender_id.is_none() && self.senders.is_empty() {
+            return Ok(None);
+        }
+        let mut registry = SenderRegistry::new();
+        for (code, sender) in &self.senders {
+            let country = Country::ALL
+                .iter()
+                .cloned()
+                .find(|c| c.iso_code().eq_ignore_ascii_case(code))
+                .ok_or_else(|| {
+                    ErrorKind::InvalidConfig(
+                        self.key(&format!("senders.{}", code)),
+                        "unsupported country".into(),
+                    )
+                })?;
+            registry = registry.sender(country, parse_sender(sender)?);
+        }
+        if let Some(ref sender) = self.sender_id {
+            registry = registry.fallback(parse_sender(sender)?);
+        }
+
+        Ok(Some(registry))
+    }
+
+    /// the payment product called `name`
+    pub fn product(&self, name: &str) -> Result<&str> {
+        self.lookup("products", &self.products, name)
+    }
+
+    /// the callback URL called `name`
+    pub fn callback(&self, name: &str) -> Result<&str> {
+        self.lookup("callbacks", &self.callbacks, name)
+    }
+
+    fn required<'a>(&self, field: &str, value: &'a Option<String>) -> Result<&'a str> {
+        match value.as_deref() {
+            Some(value) if !value.trim().is_empty() => Ok(value),
+            _ => Err(ErrorKind::MissingConfig(self.key(field)).into()),
+        }
+    }
+
+    fn lookup<'a>(
+        &self,
+        table: &str,
+        values: &'a HashMap<String, String>,
+        name: &str,
+    ) -> Result<&'a str> {
+        values.get(name).map(|v| v.as_str()).ok_or_else(|| {
+            ErrorKind::MissingConfig(self.key(&format!("{}.{}", table, name))).into()
+        })
+    }
+
+    /// the config file key of `field` in this profile
+    fn key(&self, field: &str) -> String {
+        format!("profiles.{}.{}", self.name, field)
+    }
+}
+
+/// Shortcodes are all digits; anything else is an alphanumeric sender id.
+fn parse_sender(sender: &str) -> Result<Sender> {
+    if !sender.is_empty() && sender.chars().all(|c| c.is_ascii_digit()) {
+        Sender::shortcode(sender)
+    } else {
+        Sender::alphanumeric(sender)
+    }
+}
+
+/// The environment to use, `sandbox` if `env` is unset or blank.
+fn environment(env: Option<&str>) -> &str {
+    match env.map(str::trim) {
+        Some(env) if !env.is_empty() => env,
+        _ => "sandbox",
+    }
+}
+
+fn check_environment(key: &str, env: &str) -> Result<()> {
+    match env {
+        "sandbox" | "production" => Ok(()),
+        // raise error
+        _ => Err(ErrorKind::InvalidConfig(
+            key.into(),
+            format!("expected `sandbox` or `production`, got {:?}", env),
+        )
+        .into()),
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Creates a gateway from the `AFRICAS_TALKING_USERNAME`,
+    /// `AFRICAS_TALKING_APIKEY` and `AFRICAS_TALKING_ENV` environment
+    /// variables. The environment defaults to `sandbox` when unset or blank.
+    pub fn from_env() -> Result<Self> {
+        from_vars(|name| env::var(name).ok())
+    }
+
+    /// Creates a gateway from a profile in the TOML config file at `path`,
+    /// the default profile if `profile` is `None`.
+    pub fn from_config<P: AsRef<Path>>(path: P, profile: Option<&str>) -> Result<Self> {
+        Config::load(path)?.gateway(profile)
+    }
+}
+
+fn from_vars<F>(var: F) -> Result<AfricasTalkingGateway>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let required = |name: &str| match var(name) {
+        Some(value) if !value.trim().is_empty() => Ok(value),
+        _ => Err(ErrorKind::MissingConfig(name.into())),
+    };
+    let username = required(USERNAME_VAR)?;
+    let api_key = SecretString::from(required(API_KEY_VAR)?);
+    let env = var(ENV_VAR);
+    let env = environment(env.as_deref());
+    check_environment(ENV_VAR, env)?;
+
+    Ok(AfricasTalkingGateway::new(&username, api_key.expose(), env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    const CONFIG: &str = r#"
+        default = "sandbox"
+
+        [profiles.sandbox]
+        username = "sandbox"
+        api_key = "sandbox-key"
+
+        [profiles.kenya]
+        username = "acme-ke"
+        environment = "production"
+        sender_id = "ACME"
+        senders = { UG = "ACME UG", NG = "20880" }
+        products = { payments = "Acme Store" }
+    "#;
+
+    fn vars(env: Option<&'static str>) -> impl Fn(&str) -> Option<String> {
+        move |name: &str| match name {
+            USERNAME_VAR => Some("sandbox".to_string()),
+            API_KEY_VAR => Some("key".to_string()),
+            ENV_VAR => env.map(String::from),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn loads_default_profile() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.profile(None).unwrap().name, "sandbox");
+        assert!(config.gateway(None).is_ok());
+        assert!(config.profile(Some("tanzania")).is_err());
+    }
+
+    #[test]
+    fn reports_missing_profile_values() {
+        let config = Config::parse(CONFIG).unwrap();
+        let kenya = config.profile(Some("kenya")).unwrap();
+        assert_eq!(kenya.product("payments").unwrap(), "Acme Store");
+        assert_eq!(
+            kenya.callback("delivery").unwrap_err().to_string(),
+            "missing configuration value profiles.kenya.callbacks.delivery"
+        );
+        assert_eq!(
+            kenya.gateway().unwrap_err().to_string(),
+            "missing configuration value profiles.kenya.api_key"
+        );
+    }
+
+    #[test]
+    fn builds_sender_registry() {
+        let config = Config::parse(CONFIG).unwrap();
+        let kenya = config.profile(Some("kenya")).unwrap();
+        let registry = kenya.sender_registry().unwrap().unwrap();
+        assert_eq!(
+            registry.sender_for("+234801000000"),
+            Some(&Sender::shortcode("20880").unwrap())
+        );
+        assert_eq!(
+            registry.sender_for("+254711000000"),
+            Some(&Sender::alphanumeric("ACME").unwrap())
+        );
+    }
+
+    #[test]
+    fn requires_api_key_variable() {
+        let no_key = |name: &str| match name {
+            API_KEY_VAR => None,
+            other => vars(None)(other),
+        };
+        assert_eq!(
+            from_vars(no_key).unwrap_err().to_string(),
+            "missing configuration value AFRICAS_TALKING_APIKEY"
+        );
+    }
+
+    #[test]
+    fn defaults_blank_environment_to_sandbox() {
+        assert!(from_vars(vars(None)).is_ok());
+        assert!(from_vars(vars(Some(""))).is_ok());
+        assert!(from_vars(vars(Some("  "))).is_ok());
+        assert_eq!(environment(Some(" ")), "sandbox");
+        assert_eq!(environment(Some("production")), "production");
+    }
+
+    #[test]
+    fn rejects_unknown_environment() {
+        match from_vars(vars(Some("staging"))) {
+            Err(Error(ErrorKind::InvalidConfig(key, _), _)) => assert_eq!(key, ENV_VAR),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,431 @@
+//! SMS delivery tracking: correlates sent message ids with the delivery
+//! reports posted to the application's callback URL.
+//!
+//! ```rust,ignore
+//! let mut tracker = DeliveryTracker::new();
+//! tracker.record_sent(&gway.send_message(msg)?)?;
+//!
+//! // in the delivery report callback handler:
+//! tracker.handle_report(&DeliveryReport::from_form(&body)?);
+//!
+//! for message in tracker.stale(Duration::from_secs(24 * 60 * 60)) {
+//!     println!("no final status for {}", message.message_id);
+//! }
+//! ```
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use json;
+use serde_urlencoded;
+
+use super::Result;
+
+/// Delivery status of an SMS message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// sent to the mobile network
+    Sent,
+    /// submitted to the mobile network
+    Submitted,
+    /// queued by the mobile network
+    Buffered,
+    /// rejected by the mobile network
+    Rejected,
+    /// delivered to the handset
+    Success,
+    /// could not be delivered
+    Failed,
+    /// a status this client does not know
+    Other(String),
+}
+
+impl DeliveryStatus {
+    /// true for statuses that will not change again
+    pub fn is_final(&self) -> bool {
+        matches!(
+            *self,
+            DeliveryStatus::Rejected | DeliveryStatus::Success | DeliveryStatus::Failed
+        )
+    }
+}
+
+impl From<&str> for DeliveryStatus {
+    fn from(status: &str) -> Self {
+        match status {
+            "Sent" => DeliveryStatus::Sent,
+            "Submitted" => DeliveryStatus::Submitted,
+            "Buffered" => DeliveryStatus::Buffered,
+            "Rejected" => DeliveryStatus::Rejected,
+            "Success" => DeliveryStatus::Success,
+            "Failed" => DeliveryStatus::Failed,
+            other => DeliveryStatus::Other(other.into()),
+        }
+    }
+}
+
+/// Why an SMS message was not delivered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureReason {
+    /// the account has too little credit to send the message
+    InsufficientCredit,
+    /// the premium message's link id was invalid or expired
+    InvalidLinkId,
+    /// the subscriber is inactive or the account deactivated
+    UserIsInactive,
+    /// the subscriber has blacklisted the sender
+    UserInBlacklist,
+    /// the subscriber's account is suspended
+    UserAccountSuspended,
+    /// the number is not on the sending network
+    NotNetworkSubscriber,
+    /// the subscriber is not subscribed to the premium product
+    UserNotSubscribedToProduct,
+    /// the number does not exist
+    UserDoesNotExist,
+    /// the network failed to deliver the message
+    DeliveryFailure,
+    /// rejected by the network's do-not-disturb service
+    DoNotDisturbRejection,
+    /// the handset was off or out of coverage
+    AbsentSubscriber,
+    /// a reason this client does not know
+    Other(String),
+}
+
+impl From<&str> for FailureReason {
+    fn from(reason: &str) -> Self {
+        match reason {
+            "InsufficientCredit" => FailureReason::InsufficientCredit,
+            "InvalidLinkId" => FailureReason::InvalidLinkId,
+            "UserIsInactive" => FailureReason::UserIsInactive,
+            "UserInBlacklist" => FailureReason::UserInBlacklist,
+            "UserAccountSuspended" => FailureReason::UserAccountSuspended,
+            "NotNetworkSubscriber" => FailureReason::NotNetworkSubscriber,
+            "UserNotSubscribedToProduct" => FailureReason::UserNotSubscribedToProduct,
+            "UserDoesNotExist" => FailureReason::UserDoesNotExist,
+            "DeliveryFailure" => FailureReason::DeliveryFailure,
+            "DoNotDisturbRejection" => FailureReason::DoNotDisturbRejection,
+            "AbsentSubscriber" => FailureReason::AbsentSubscriber,
+            other => FailureReason::Other(other.into()),
+        }
+    }
+}
+
+/// SMS Recipient Struct
+///
+/// One entry per recipient in the `send_message` response.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct SMSRecipient {
+    /// recipient's phone number
+    pub number: String,
+
+    /// send status e.g. `Success`, `InvalidPhoneNumber`
+    pub status: String,
+
+    /// send status code
+    #[serde(default)]
+    pub statusCode: i32,
+
+    /// message id, `None` if the message was not sent
+    #[serde(default)]
+    pub messageId: String,
+
+    /// cost of the message e.g. `"KES 0.8000"`
+    #[serde(default)]
+    pub cost: String,
+}
+
+impl SMSRecipient {
+    /// Parses the recipients of a `send_message` response.
+    pub fn from_response(response: &json::Value) -> Result<Vec<Self>> {
+        Ok(json::from_value(
+            response["SMSMessageData"]["Recipients"].clone(),
+        )?)
+    }
+
+    /// true if the message was accepted for delivery
+    pub fn is_sent(&self) -> bool {
+        !self.messageId.is_empty() && self.messageId != "None"
+    }
+}
+
+/// Delivery Report Struct
+///
+/// Posted as form data to the application's delivery report callback URL.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct DeliveryReport {
+    /// id of the message the report is for
+    pub id: String,
+
+    /// delivery status
+    pub status: String,
+
+    /// recipient's phone number
+    #[serde(default)]
+    pub phoneNumber: String,
+
+    /// mobile network code of the recipient
+    #[serde(default)]
+    pub networkCode: Option<String>,
+
+    /// why the message failed, for `Rejected` and `Failed` reports
+    #[serde(default)]
+    pub failureReason: Option<String>,
+
+    /// number of delivery attempts made
+    #[serde(default)]
+    pub retryCount: Option<String>,
+}
+
+impl DeliveryReport {
+    /// Parses the form encoded body of a delivery report callback.
+    pub fn from_form(body: &str) -> Result<Self> {
+        Ok(serde_urlencoded::from_str(body)?)
+    }
+
+    /// the reported status
+    pub fn delivery_status(&self) -> DeliveryStatus {
+        DeliveryStatus::from(self.status.as_str())
+    }
+
+    /// the reported failure reason, if any
+    pub fn failure_reason(&self) -> Option<FailureReason> {
+        match self.failureReason.as_deref() {
+            None | Some("") => None,
+            Some(reason) => Some(FailureReason::from(reason)),
+        }
+    }
+}
+
+/// A message being tracked
+#[derive(Debug, Clone)]
+pub struct TrackedMessage {
+    /// the message id
+    pub message_id: String,
+
+    /// recipient's phone number
+    pub phone_number: String,
+
+    /// latest known status
+    pub status: DeliveryStatus,
+
+    /// failure reason from the latest report, if any
+    pub failure_reason: Option<FailureReason>,
+
+    /// when the message was sent
+    pub sent_at: Instant,
+
+    /// when the latest report arrived
+    pub updated_at: Option<Instant>,
+}
+
+/// Tracks sent messages and their delivery reports
+#[derive(Debug, Default)]
+pub struct DeliveryTracker {
+    messages: HashMap<String, TrackedMessage>,
+}
+
+impl DeliveryTracker {
+    /// creates an empty tracker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking every sent recipient of a `send_message` response,
+    /// returning how many were recorded.
+    pub fn record_sent(&mut self, response: &json::Value) -> Result<usize> {
+        let now = Instant::now();
+        let mut recorded = 0;
+        for recipient in SMSRecipient::from_response(response)? {
+            if recipient.is_sent() {
+                self.track(&recipient.messageId, &recipient.number, now);
+                recorded += 1;
+            }
+        }
+
+        Ok(recorded)
+    }
+
+    /// starts tracking a message sent at `sent_at`
+    pub fn track(&mut self, message_id: &str, phone_number: &str, sent_at: Instant) {
+        self.messages.insert(
+            message_id.into(),
+            TrackedMessage {
+                message_id: message_id.into(),
+                phone_number: phone_number.into(),
+                status: DeliveryStatus::Sent,
+                failure_reason: None,
+                sent_at,
+                updated_at: None,
+            },
+        );
+    }
+
+    /// Applies a delivery report, returning false if the message is not
+    /// tracked. Reports arriving after a final status are ignored.
+    pub fn handle_report(&mut self, report: &DeliveryReport) -> bool {
+        let message = match self.messages.get_mut(&report.id) {
+            Some(message) => message,
+            None => return false,
+        };
+        if !message.status.is_final() {
+            message.status = report.delivery_status();
+            message.failure_reason = report.failure_reason();
+            message.updated_at = Some(Instant::now());
+        }
+        true
+    }
+
+    /// the tracked message with id `message_id`
+    pub fn get(&self, message_id: &str) -> Option<&TrackedMessage> {
+        self.messages.get(message_id)
+    }
+
+    /// latest known status of `message_id`
+    pub fn status(&self, message_id: &str) -> Option<&DeliveryStatus> {
+        self.get(message_id).map(|m| &m.status)
+    }
+
+    /// number of messages tracked
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// true if no messages are tracked
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Messages sent more than `max_age` ago that have no final status,
+    /// oldest first.
+    pub fn stale(&self, max_age: Duration) -> Vec<&TrackedMessage> {
+        self.stale_at(max_age, Instant::now())
+    }
+
+    fn stale_at(&self, max_age: Duration, now: Instant) -> Vec<&TrackedMessage> {
+        let mut stale: Vec<&TrackedMessage> = self
+            .messages
+            .values()
+            .filter(|m| !m.status.is_final() && now.duration_since(m.sent_at) > max_age)
+            .collect();
+        stale.sort_by_key(|m| m.sent_at);
+        stale
+    }
+
+    /// Stops tracking messages with a final status, returning them.
+    pub fn drain_final(&mut self) -> Vec<TrackedMessage> {
+        let done: Vec<String> = self
+            .messages
+            .values()
+            .filter(|m| m.status.is_final())
+            .map(|m| m.message_id.clone())
+            .collect();
+        done.iter()
+            .filter_map(|id| self.messages.remove(id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> json::Value {
+        json!({
+            "SMSMessageData": {
+                "Message": "Sent to 2/3 Total Cost: KES 1.6000",
+                "Recipients": [
+                    {"number": "+254711000001", "status": "Success", "statusCode": 101,
+                     "messageId": "ATXid_1", "cost": "KES 0.8000"},
+                    {"number": "+254711000002", "status": "Success", "statusCode": 101,
+                     "messageId": "ATXid_2", "cost": "KES 0.8000"},
+                    {"number": "+2547", "status": "InvalidPhoneNumber", "statusCode": 403,
+                     "messageId": "None", "cost": "0"}
+                ]
+            }
+        })
+    }
+
+    fn report(id: &str, status: &str) -> DeliveryReport {
+        DeliveryReport {
+            id: id.into(),
+            status: status.into(),
+            ..Default::default()
+        }
+    }
+
+    fn tracker() -> DeliveryTracker {
+        let mut tracker = DeliveryTracker::new();
+        tracker.record_sent(&response()).unwrap();
+        tracker
+    }
+
+    #[test]
+    fn records_sent_recipients_only() {
+        let mut tracker = DeliveryTracker::new();
+        assert_eq!(tracker.record_sent(&response()).unwrap(), 2);
+        assert_eq!(tracker.status("ATXid_1"), Some(&DeliveryStatus::Sent));
+        assert!(tracker.get("None").is_none());
+    }
+
+    #[test]
+    fn record_sent_rejects_malformed_response() {
+        let mut tracker = DeliveryTracker::new();
+        let response = json!({"SMSMessageData": {"Recipients": "none"}});
+        assert!(tracker.record_sent(&response).is_err());
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn applies_failed_report() {
+        let mut tracker = tracker();
+        let report = DeliveryReport::from_form(
+            "id=ATXid_1&status=Failed&phoneNumber=%2B254711000001\
+             &networkCode=63902&failureReason=AbsentSubscriber&retryCount=1",
+        )
+        .unwrap();
+        assert!(tracker.handle_report(&report));
+
+        let failed = tracker.get("ATXid_1").unwrap();
+        assert_eq!(failed.status, DeliveryStatus::Failed);
+        assert_eq!(failed.failure_reason, Some(FailureReason::AbsentSubscriber));
+    }
+
+    #[test]
+    fn ignores_reports_for_untracked_messages() {
+        let mut tracker = tracker();
+        assert!(!tracker.handle_report(&report("ATXid_9", "Success")));
+        assert_eq!(tracker.len(), 2);
+    }
+
+    #[test]
+    fn keeps_final_status() {
+        let mut tracker = tracker();
+        assert!(tracker.handle_report(&report("ATXid_1", "Success")));
+        assert!(tracker.handle_report(&report("ATXid_1", "Buffered")));
+        assert_eq!(tracker.status("ATXid_1"), Some(&DeliveryStatus::Success));
+    }
+
+    #[test]
+    fn lists_stale_messages_without_final_status() {
+        let mut tracker = tracker();
+        tracker.handle_report(&report("ATXid_1", "Failed"));
+        let later = Instant::now() + Duration::from_secs(120);
+        let stale = tracker.stale_at(Duration::from_secs(60), later);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].message_id, "ATXid_2");
+        assert!(tracker.stale_at(Duration::from_secs(300), later).is_empty());
+    }
+
+    #[test]
+    fn drains_final_messages() {
+        let mut tracker = tracker();
+        tracker.handle_report(&report("ATXid_1", "Rejected"));
+        let done = tracker.drain_final();
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].message_id, "ATXid_1");
+        assert_eq!(tracker.len(), 1);
+    }
+}
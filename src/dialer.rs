@@ -0,0 +1,472 @@
+//! Outbound voice campaign dialer.
+//!
+//! Calls a list of numbers through the gateway's call API while keeping at
+//! most `max_concurrent` calls active, retries unanswered and busy calls
+//! after the configured delays, and records each number's outcome from the
+//! call-ended voice callbacks.
+//!
+//! ```rust,ignore
+//! let (tx, rx) = mpsc::channel();
+//! // in the voice callback handler:
+//! //     tx.send(VoiceNotification::from_form(&body)?)
+//!
+//! let mut dialer = Dialer::new("+254711000000", &numbers, DialerConfig::default());
+//! for result in dialer.run(&gway, &rx) {
+//!     println!("{} {:?} {:?}", result.phone_number, result.outcome, result.duration);
+//! }
+//! ```
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use super::{AfricasTalkingGateway, Result};
+use money::Money;
+use voice::{CallEntry, VoiceNotification};
+
+/// Dialer configuration
+#[derive(Debug, Clone)]
+pub struct DialerConfig {
+    /// maximum number of calls active at once
+    pub max_concurrent: usize,
+
+    /// delay before each retry; a number is retried at most once per delay
+    pub retry_delays: Vec<Duration>,
+
+    /// calls with no call-ended callback after this long count as unanswered
+    pub call_timeout: Duration,
+
+    /// how often `run` checks for due calls while waiting for callbacks
+    pub poll_interval: Duration,
+}
+
+impl Default for DialerConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 10,
+            retry_delays: vec![Duration::from_secs(5 * 60), Duration::from_secs(30 * 60)],
+            call_timeout: Duration::from_secs(10 * 60),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Outcome of dialing a number
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialOutcome {
+    /// not dialed yet, or waiting for a call to end
+    Pending,
+    /// the call was answered
+    Answered,
+    /// the call rang out or was not picked up
+    NoAnswer,
+    /// the number was busy or rejected the call
+    Busy,
+    /// the call could not be placed
+    Failed,
+}
+
+impl DialOutcome {
+    fn is_retryable(self) -> bool {
+        matches!(self, DialOutcome::NoAnswer | DialOutcome::Busy)
+    }
+
+    /// Classifies a call-ended notification.
+    fn from_notification(notification: &VoiceNotification) -> Self {
+        if notification.duration().unwrap_or(0) > 0 {
+            return DialOutcome::Answered;
+        }
+        match notification.hangupCause.as_deref() {
+            Some("USER_BUSY") | Some("CALL_REJECTED") => DialOutcome::Busy,
+            Some("NO_ANSWER")
+            | Some("NO_USER_RESPONSE")
+            | Some("SUBSCRIBER_ABSENT")
+            | Some("ORIGINATOR_CANCEL") => DialOutcome::NoAnswer,
+            _ => DialOutcome::Failed,
+        }
+    }
+}
+
+/// Result of dialing a number
+#[derive(Debug, Clone, PartialEq)]
+pub struct DialResult {
+    /// the number dialed
+    pub phone_number: String,
+
+    /// number of calls placed
+    pub attempts: u32,
+
+    /// outcome of the last attempt
+    pub outcome: DialOutcome,
+
+    /// duration of the last call, in seconds
+    pub duration: Option<u32>,
+
+    /// cost of the last call
+    pub cost: Option<Money>,
+
+    /// hangup cause of the last call, or the error placing it
+    pub hangup_cause: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Waiting(Instant),
+    Active(Instant),
+    Done,
+}
+
+#[derive(Debug)]
+struct Target {
+    result: DialResult,
+    state: State,
+}
+
+/// Dials a list of numbers with limited concurrency and retries
+#[derive(Debug)]
+pub struct Dialer {
+    from: String,
+    config: DialerConfig,
+    targets: Vec<Target>,
+    sessions: HashMap<String, usize>,
+}
+
+impl Dialer {
+    /// Creates a dialer calling `numbers` from the `from` number.
+    ///
+    /// Numbers that are the same once formatting and country codes are
+    /// ignored (e.g. `0711 000 001` and `+254711000001`) are dialed once,
+    /// under the first spelling given.
+    pub fn new(from: &str, numbers: &[String], config: DialerConfig) -> Self {
+        let now = Instant::now();
+        let mut unique: Vec<&String> = Vec::new();
+        for number in numbers {
+            if !unique.iter().any(|n| same_number(n, number)) {
+                unique.push(number);
+            }
+        }
+        let targets = unique
+            .into_iter()
+            .map(|number| Target {
+                result: DialResult {
+                    phone_number: number.clone(),
+                    attempts: 0,
+                    outcome: DialOutcome::Pending,
+                    duration: None,
+                    cost: None,
+                    hangup_cause: None,
+                },
+                state: State::Waiting(now),
+            })
+            .collect();
+        Self {
+            from: from.into(),
+            config,
+            targets,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// number of calls currently active
+    pub fn active(&self) -> usize {
+        self.targets
+            .iter()
+            .filter(|t| matches!(t.state, State::Active(_)))
+            .count()
+    }
+
+    /// true once every number has a final outcome
+    pub fn is_finished(&self) -> bool {
+        self.targets.iter().all(|t| t.state == State::Done)
+    }
+
+    /// current result for every distinct number, in the order given
+    pub fn results(&self) -> Vec<DialResult> {
+        self.targets.iter().map(|t| t.result.clone()).collect()
+    }
+
+    /// Places calls to numbers that are due, up to the concurrency limit,
+    /// returning how many calls were placed.
+    ///
+    /// If the call request fails, the attempt counts as failed and the
+    /// numbers are rescheduled like unanswered calls.
+    pub fn dial_due(&mut self, gateway: &AfricasTalkingGateway, now: Instant) -> Result<usize> {
+        let batch = self.due(now);
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let numbers: Vec<&str> = batch
+            .iter()
+            .map(|&i| self.targets[i].result.phone_number.as_str())
+            .collect();
+        match gateway.call(&self.from, &numbers, None) {
+            Ok(entries) => {
+                self.start(&batch, &entries, now);
+                Ok(batch.len())
+            }
+            Err(e) => {
+                for &i in &batch {
+                    self.targets[i].result.attempts += 1;
+                    self.targets[i].result.hangup_cause = Some(e.to_string());
+                    self.end_attempt(i, DialOutcome::Failed, true, now);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Records a voice callback; only call-ended notifications for calls
+    /// placed by this dialer change its state.
+    pub fn handle_notification(&mut self, notification: &VoiceNotification, now: Instant) {
+        if notification.is_active() {
+            return;
+        }
+        let idx = match self.sessions.remove(&notification.sessionId) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let outcome = DialOutcome::from_notification(notification);
+        {
+            let result = &mut self.targets[idx].result;
+            result.duration = notification.duration();
+            result.cost = notification.cost();
+            result.hangup_cause = notification.hangupCause.clone();
+        }
+        self.end_attempt(idx, outcome, outcome.is_retryable(), now);
+    }
+
+    /// Treats calls active for longer than `call_timeout` as unanswered.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.config.call_timeout;
+        let expired: Vec<usize> = self
+            .targets
+            .iter()
+            .enumerate()
+            .filter(|&(_, t)| match t.state {
+                State::Active(since) => now.duration_since(since) >= timeout,
+                _ => false,
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        self.sessions.retain(|_, i| !expired.contains(i));
+        for i in expired {
+            self.end_attempt(i, DialOutcome::NoAnswer, true, now);
+        }
+    }
+
+    /// Dials every number to completion, consuming voice callbacks from
+    /// `notifications`, and returns the final results.
+    ///
+    /// Errors placing calls are recorded against the numbers concerned
+    /// rather than stopping the campaign.
+    pub fn run(
+        &mut self,
+        gateway: &AfricasTalkingGateway,
+        notifications: &Receiver<VoiceNotification>,
+    ) -> Vec<DialResult> {
+        while !self.is_finished() {
+            let now = Instant::now();
+            self.expire(now);
+            let _ = self.dial_due(gateway, now);
+            match notifications.recv_timeout(self.config.poll_interval) {
+                Ok(notification) => self.handle_notification(&notification, Instant::now()),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.results()
+    }
+
+    /// Indices of numbers due to be dialed, limited by free call slots.
+    fn due(&self, now: Instant) -> Vec<usize> {
+        let free = self.config.max_concurrent.saturating_sub(self.active());
+        self.targets
+            .iter()
+            .enumerate()
+            .filter(|&(_, t)| match t.state {
+                State::Waiting(due) => due <= now,
+                _ => false,
+            })
+            .map(|(i, _)| i)
+            .take(free)
+            .collect()
+    }
+
+    /// Marks a batch as dialing, matching call entries to numbers.
+    ///
+    /// Entries are matched on the number they report, ignoring formatting,
+    /// and otherwise by position, as the API returns them in request order.
+    fn start(&mut self, batch: &[usize], entries: &[CallEntry], now: Instant) {
+        for (pos, &i) in batch.iter().enumerate() {
+            self.targets[i].result.attempts += 1;
+            let entry = entries
+                .iter()
+                .find(|e| same_number(&e.phoneNumber, &self.targets[i].result.phone_number))
+                .or_else(|| entries.get(pos));
+            let session_id = entry
+                .filter(|e| e.is_queued())
+                .and_then(|e| e.sessionId.clone());
+            match session_id {
+                Some(session_id) => {
+                    self.targets[i].state = State::Active(now);
+                    self.sessions.insert(session_id, i);
+                }
+                _ => {
+                    let status = entry.map(|e| e.status.clone());
+                    self.targets[i].result.hangup_cause = status;
+                    self.end_attempt(i, DialOutcome::Failed, false, now);
+                }
+            }
+        }
+    }
+
+    fn end_attempt(&mut self, idx: usize, outcome: DialOutcome, retryable: bool, now: Instant) {
+        let delays = &self.config.retry_delays;
+        let target = &mut self.targets[idx];
+        target.result.outcome = outcome;
+        let attempts = target.result.attempts as usize;
+        target.state = if retryable && attempts >= 1 && attempts <= delays.len() {
+            State::Waiting(now + delays[attempts - 1])
+        } else {
+            State::Done
+        };
+    }
+}
+
+/// Whether two phone numbers are the same subscriber, ignoring formatting,
+/// a trunk `0` and a country code present on only one of them.
+fn same_number(a: &str, b: &str) -> bool {
+    let significant = |s: &str| -> String {
+        let digits: String = s.chars().filter(char::is_ascii_digit).collect();
+        digits.trim_start_matches('0').to_string()
+    };
+    let (a, b) = (significant(a), significant(b));
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    short == long || (short.len() >= 7 && long.ends_with(&short))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(phone: &str, session: &str) -> CallEntry {
+        CallEntry {
+            phoneNumber: phone.into(),
+            status: "Queued".into(),
+            sessionId: Some(session.into()),
+        }
+    }
+
+    fn ended(session: &str, duration: &str, cause: &str) -> VoiceNotification {
+        VoiceNotification {
+            isActive: "0".into(),
+            sessionId: session.into(),
+            durationInSeconds: Some(duration.into()),
+            hangupCause: Some(cause.into()),
+            ..Default::default()
+        }
+    }
+
+    fn dialer(numbers: &[&str], max_concurrent: usize) -> Dialer {
+        let numbers: Vec<String> = numbers.iter().map(|n| n.to_string()).collect();
+        let config = DialerConfig {
+            max_concurrent,
+            retry_delays: vec![Duration::from_secs(60)],
+            ..Default::default()
+        };
+        Dialer::new("+254711000000", &numbers, config)
+    }
+
+    #[test]
+    fn limits_concurrent_calls() {
+        let mut dialer = dialer(&["+254711000001", "+254711000002"], 1);
+        let now = Instant::now();
+
+        let batch = dialer.due(now);
+        assert_eq!(batch, vec![0]);
+        dialer.start(&batch, &[entry("+254711000001", "s1")], now);
+        assert!(dialer.due(now).is_empty());
+
+        dialer.handle_notification(&ended("s1", "30", "NORMAL_CLEARING"), now);
+        assert_eq!(dialer.due(now), vec![1]);
+    }
+
+    #[test]
+    fn retries_busy_numbers_after_delay() {
+        let mut dialer = dialer(&["+254711000001"], 1);
+        let now = Instant::now();
+        dialer.start(&[0], &[entry("+254711000001", "s1")], now);
+        dialer.handle_notification(&ended("s1", "0", "USER_BUSY"), now);
+        assert_eq!(dialer.results()[0].outcome, DialOutcome::Busy);
+        assert!(dialer.due(now).is_empty());
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(dialer.due(later), vec![0]);
+        dialer.start(&[0], &[entry("+254711000001", "s2")], later);
+        dialer.handle_notification(&ended("s2", "0", "NO_ANSWER"), later);
+
+        assert!(dialer.is_finished());
+        let result = &dialer.results()[0];
+        assert_eq!(result.attempts, 2);
+        assert_eq!(result.outcome, DialOutcome::NoAnswer);
+    }
+
+    #[test]
+    fn records_answered_calls() {
+        let mut dialer = dialer(&["+254711000001"], 1);
+        let now = Instant::now();
+        dialer.start(&[0], &[entry("+254711000001", "s1")], now);
+        dialer.handle_notification(&ended("s1", "30", "NORMAL_CLEARING"), now);
+
+        assert!(dialer.is_finished());
+        assert_eq!(dialer.results()[0].outcome, DialOutcome::Answered);
+        assert_eq!(dialer.results()[0].duration, Some(30));
+    }
+
+    #[test]
+    fn matches_entries_on_normalized_number() {
+        let mut dialer = dialer(&["0711 000 001", "0711 000 002"], 2);
+        let now = Instant::now();
+        let entries = [entry("+254711000002", "s2"), entry("+254711000001", "s1")];
+        dialer.start(&[0, 1], &entries, now);
+        dialer.handle_notification(&ended("s1", "0", "USER_BUSY"), now);
+
+        let results = dialer.results();
+        assert_eq!(results[0].outcome, DialOutcome::Busy);
+        assert_eq!(results[1].outcome, DialOutcome::Pending);
+    }
+
+    #[test]
+    fn matches_entries_by_position_otherwise() {
+        let mut dialer = dialer(&["711000001"], 1);
+        let now = Instant::now();
+        dialer.start(&[0], &[entry("+44 20 7946 0000", "s1")], now);
+        assert_eq!(dialer.active(), 1);
+    }
+
+    #[test]
+    fn deduplicates_numbers() {
+        let dialer = dialer(&["+254711000001", "0711 000 001", "254711000002"], 2);
+        let numbers: Vec<String> = dialer
+            .results()
+            .into_iter()
+            .map(|r| r.phone_number)
+            .collect();
+        assert_eq!(numbers, vec!["+254711000001", "254711000002"]);
+    }
+
+    #[test]
+    fn dial_due_reschedules_on_error_response() {
+        let (url, _) = ::tests::serve(&[(401, "The supplied authentication is invalid")]);
+        let mut dialer = dialer(&["+254711000001"], 1);
+        let now = Instant::now();
+
+        assert!(dialer.dial_due(&::tests::gateway(&url), now).is_err());
+        let result = &dialer.results()[0];
+        assert_eq!(result.attempts, 1);
+        assert_eq!(result.outcome, DialOutcome::Failed);
+        assert_eq!(dialer.due(now + Duration::from_secs(61)), vec![0]);
+    }
+}
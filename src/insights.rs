@@ -0,0 +1,364 @@
+//! Insights API: SIM swap checks and the payout policy built on them.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use json;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use secret::redact;
+
+/// SIM Swap Number Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct SimSwapNumber {
+    /// phone number, in international format
+    pub number: String,
+
+    /// number type e.g. `Mobile`
+    #[serde(default)]
+    pub numberType: String,
+}
+
+/// SIM Swap Cost Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SimSwapCost {
+    /// amount charged
+    pub amount: f64,
+
+    /// 3-digit ISO currency code
+    pub currency: String,
+}
+
+/// SIM Swap Result Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct SimSwapResult {
+    /// the phone number checked
+    pub phoneNumber: SimSwapNumber,
+
+    /// mobile network provider
+    #[serde(default)]
+    pub provider: String,
+
+    /// check status e.g. `Swapped`, `NotSwapped`
+    pub status: String,
+
+    /// date of the last SIM swap, in the format `DD-MM-YYYY`
+    #[serde(default)]
+    pub lastSimSwapDate: Option<String>,
+
+    /// unique request id
+    #[serde(default)]
+    pub requestId: String,
+
+    /// cost of the check
+    #[serde(default)]
+    pub cost: Option<SimSwapCost>,
+}
+
+impl SimSwapResult {
+    /// Days since the last SIM swap, `None` if the date is missing or
+    /// cannot be parsed.
+    pub fn days_since_swap(&self) -> Option<i64> {
+        let date = self.lastSimSwapDate.as_ref()?;
+        let mut parts = date.trim().split('-').map(|p| p.parse::<i64>());
+        let day = parts.next()?.ok()?;
+        let month = parts.next()?.ok()?;
+        let year = parts.next()?.ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(now.as_secs() as i64 / 86_400 - days_from_civil(year, month, day))
+    }
+
+    /// true if the SIM was swapped within the last `days` days; a swap with
+    /// no usable date counts as recent
+    pub fn swapped_within(&self, days: u32) -> bool {
+        if self.status != "Swapped" {
+            return false;
+        }
+        match self.days_since_swap() {
+            Some(since) => since <= i64::from(days),
+            None => true,
+        }
+    }
+}
+
+/// Refuses payouts to recipients whose SIM was recently swapped.
+///
+/// Enable it on a gateway with `with_sim_swap_policy`; every
+/// `mobile_payment_b2c_request` then checks its recipients first and fails
+/// with `ErrorKind::PayoutRefused` without sending anything if any are refused.
+#[derive(Debug, Clone, Copy)]
+pub struct SimSwapPolicy {
+    /// recipients swapped within this many days are refused
+    pub window_days: u32,
+
+    /// refuse recipients whose SIM swap status could not be determined;
+    /// on by default
+    pub refuse_unknown: bool,
+}
+
+impl SimSwapPolicy {
+    /// creates a policy refusing SIMs swapped within `window_days`, and
+    /// SIMs whose swap status could not be determined
+    pub fn new(window_days: u32) -> Self {
+        Self {
+            window_days,
+            refuse_unknown: true,
+        }
+    }
+
+    /// Phone numbers this policy refuses, given the SIM swap results for
+    /// `phone_numbers`. Numbers missing from the results count as unknown.
+    pub fn refused(&self, phone_numbers: &[&str], results: &[SimSwapResult]) -> Vec<String> {
+        let mut refused: Vec<String> = results
+            .iter()
+            .filter(|r| {
+                let unknown = r.status != "Swapped" && r.status != "NotSwapped";
+                r.swapped_within(self.window_days) || (self.refuse_unknown && unknown)
+            })
+            .map(|r| r.phoneNumber.number.clone())
+            .collect();
+        if self.refuse_unknown {
+            let returned: Vec<String> = results
+                .iter()
+                .map(|r| digits(&r.phoneNumber.number))
+                .collect();
+            refused.extend(
+                phone_numbers
+                    .iter()
+                    .filter(|p| !returned.contains(&digits(p)))
+                    .map(|p| p.to_string()),
+            );
+        }
+        refused
+    }
+
+    pub(crate) fn check(
+        &self,
+        gateway: &AfricasTalkingGateway,
+        phone_numbers: &[&str],
+    ) -> Result<()> {
+        if phone_numbers.is_empty() {
+            return Ok(());
+        }
+        let results = gateway.check_sim_swap(phone_numbers)?;
+        let refused = self.refused(phone_numbers, &results);
+        if refused.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorKind::PayoutRefused(refused).into())
+        }
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Checks whether the SIM cards for the given phone numbers were swapped.
+    /// [read more..](http://docs.africastalking.com/insights/sim-swap)
+    pub fn check_sim_swap(&self, phone_numbers: &[&str]) -> Result<Vec<SimSwapResult>> {
+        let params = json!({
+            "username": self.username,
+            "phoneNumbers": phone_numbers
+        });
+        let mut resp = self.send_json_request(&self.sim_swap_url, params)?;
+        let code = resp.status().as_u16();
+        if code == 200 || code == 201 {
+            let jsn: json::Value = resp.json()?;
+            if jsn["status"] == "Processed" {
+                Ok(json::from_value(jsn["responses"].clone())?)
+            } else {
+                // raise error
+                let message = jsn["errorMessage"]
+                    .as_str()
+                    .or_else(|| jsn["description"].as_str())
+                    .or_else(|| jsn["status"].as_str())
+                    .unwrap_or_default();
+                Err(ErrorKind::GatewayError(redact(message)).into())
+            }
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+        }
+    }
+
+    /// Checks B2C payout recipients against a SIM swap policy first.
+    pub fn with_sim_swap_policy(mut self, policy: SimSwapPolicy) -> Self {
+        self.sim_swap_policy = Some(policy);
+        self
+    }
+}
+
+/// The digits of a phone number, to compare numbers however they are
+/// formatted.
+fn digits(phone_number: &str) -> String {
+    phone_number.chars().filter(char::is_ascii_digit).collect()
+}
+
+/// Days since 1970-01-01 for a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    fn result(number: &str, status: &str, date: &str) -> SimSwapResult {
+        SimSwapResult {
+            phoneNumber: SimSwapNumber {
+                number: number.into(),
+                numberType: "Mobile".into(),
+            },
+            status: status.into(),
+            lastSimSwapDate: Some(date.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn computes_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+    }
+
+    #[test]
+    fn policy_refuses_recent_swaps() {
+        let results = vec![
+            result("+254711000001", "Swapped", "01-01-2000"),
+            result("+254711000002", "NotSwapped", "01-01-1900"),
+            result("+254711000003", "Swapped", "not-a-date"),
+        ];
+        let numbers = ["+254711000001", "+254711000002", "+254711000003"];
+        assert_eq!(
+            SimSwapPolicy::new(30).refused(&numbers, &results),
+            vec!["+254711000003".to_string()]
+        );
+    }
+
+    #[test]
+    fn strict_policy_refuses_unknown_status() {
+        let results = vec![result("+254711000004", "Unknown", "")];
+        let strict = SimSwapPolicy::new(30);
+        assert_eq!(
+            strict.refused(&["+254711000004"], &results),
+            vec!["+254711000004".to_string()]
+        );
+
+        let lenient = SimSwapPolicy {
+            refuse_unknown: false,
+            ..strict
+        };
+        assert!(lenient.refused(&["+254711000004"], &results).is_empty());
+    }
+
+    #[test]
+    fn strict_policy_refuses_numbers_missing_from_results() {
+        let results = vec![result("254711000001", "NotSwapped", "01-01-1900")];
+        let numbers = ["+254711000001", "+254711000005"];
+        let strict = SimSwapPolicy::new(30);
+        assert_eq!(
+            strict.refused(&numbers, &results),
+            vec!["+254711000005".to_string()]
+        );
+
+        let lenient = SimSwapPolicy {
+            refuse_unknown: false,
+            ..strict
+        };
+        assert!(lenient.refused(&numbers, &results).is_empty());
+    }
+
+    #[test]
+    fn check_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(401, r#"{"errorMessage":"Invalid apiKey"}"#)]);
+        let err = ::tests::gateway(&url)
+            .check_sim_swap(&["+254711000001"])
+            .unwrap_err();
+        assert_eq!(err.to_string(), r#"{"errorMessage":"Invalid apiKey"}"#);
+    }
+
+    #[test]
+    fn check_fails_with_error_message() {
+        let (url, _) = ::tests::serve(&[(
+            200,
+            r#"{"status":"Failed","errorMessage":"Invalid phone number +254711000001"}"#,
+        )]);
+        let err = ::tests::gateway(&url)
+            .check_sim_swap(&["+254711000001"])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid phone number +*********001");
+
+        let (url, _) = ::tests::serve(&[(200, r#"{"status":"Failed"}"#)]);
+        let err = ::tests::gateway(&url)
+            .check_sim_swap(&["+254711000001"])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Failed");
+    }
+
+    #[test]
+    fn payout_refused_when_number_is_not_checked() {
+        let (url, requests) = ::tests::serve(&[(200, r#"{"status":"Processed","responses":[]}"#)]);
+        let gway = ::tests::gateway(&url).with_sim_swap_policy(SimSwapPolicy::new(30));
+        let recipients = json!([{"phoneNumber": "+254711000001", "amount": "KES 10"}]);
+        match gway.mobile_payment_b2c_request("Store", &recipients) {
+            Err(Error(ErrorKind::PayoutRefused(numbers), _)) => {
+                assert_eq!(numbers, vec!["+254711000001".to_string()])
+            }
+            other => panic!("expected PayoutRefused, got {:?}", other),
+        }
+        assert!(requests.recv().unwrap().contains("/v1/sim-swap"));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn payout_sent_when_numbers_pass_check() {
+        let (url, requests) = ::tests::serve(&[
+            (
+                200,
+                r#"{"status":"Processed","responses":[{"phoneNumber":
+                    {"number":"+254711000001"},"status":"NotSwapped"}]}"#,
+            ),
+            (
+                201,
+                r#"{"entries":[{"phoneNumber":"+254711000001","status":"Queued"}]}"#,
+            ),
+        ]);
+        let gway = ::tests::gateway(&url).with_sim_swap_policy(SimSwapPolicy::new(30));
+        let recipients = json!([{"phoneNumber": "+254711000001", "amount": "KES 10"}]);
+        let entries = gway
+            .mobile_payment_b2c_request("Store", &recipients)
+            .unwrap();
+        assert_eq!(entries[0]["status"], "Queued");
+        assert!(requests.recv().unwrap().contains("/v1/sim-swap"));
+        assert!(requests.recv().unwrap().contains("/mobile/b2c/request"));
+    }
+
+    #[test]
+    fn payout_not_sent_when_check_fails() {
+        let (url, requests) = ::tests::serve(&[(500, "Internal Server Error")]);
+        let gway = ::tests::gateway(&url).with_sim_swap_policy(SimSwapPolicy::new(30));
+        let recipients = json!([{"phoneNumber": "+254711000001", "amount": "KES 10"}]);
+        let err = gway
+            .mobile_payment_b2c_request("Store", &recipients)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Internal Server Error");
+        assert_eq!(requests.iter().count(), 1);
+    }
+
+    #[test]
+    fn payout_rejects_recipient_without_phone_number() {
+        let gway = AfricasTalkingGateway::new("sandbox", "key", "sandbox")
+            .with_sim_swap_policy(SimSwapPolicy::new(30));
+        let recipients = json!([{"phoneNumber": 254_711_000_001u64, "amount": "KES 10"}]);
+        match gway.mobile_payment_b2c_request("Store", &recipients) {
+            Err(Error(ErrorKind::InvalidPhoneNumber(number), _)) => {
+                assert_eq!(number, "254711000001")
+            }
+            other => panic!("expected InvalidPhoneNumber, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,711 @@
+//! Spend accounting and budget limits for outgoing traffic.
+//!
+//! With a `SpendLedger` set, the costs reported in `send_message` and
+//! `send_airtime` responses are recorded with the API, sender, campaign
+//! tag and UTC day, and can be totalled by any of them. Calls are only
+//! charged once they end, so their costs are recorded by passing the
+//! call-ended voice callback to `record_call_cost`.
+//!
+//! Daily and monthly limits refuse a request with
+//! `ErrorKind::BudgetExceeded`, before it is sent, if it would take
+//! spending past the limit. The cost of an SMS or call is only known
+//! afterwards, so it is estimated from the unit costs set with `estimate`;
+//! airtime is checked against the amounts requested. The estimate is
+//! reserved in the same step as the check and counts against the limits
+//! until the response replaces it with the actual cost, so concurrent
+//! requests can't overspend between them. If the response's costs can't be
+//! read, the estimate is recorded instead.
+//!
+//! Costs are kept as running totals per API, sender, campaign, day and
+//! currency, so a ledger's memory grows with the number of groups rather
+//! than the number of requests.
+//!
+//! ```rust,ignore
+//! let ledger = SpendLedger::new()
+//!     .daily_limit(Money::whole("KES", 5000))
+//!     .monthly_limit(Money::whole("KES", 100_000))
+//!     .estimate(Api::Sms, "KES 0.80".parse()?);
+//! let gway = AfricasTalkingGateway::new(&username, &api_key, "sandbox")
+//!     .with_ledger(ledger.clone());
+//!
+//! gway.clone().with_campaign("easter-promo").send_bulk_message(&to, text, None)?;
+//! for total in ledger.totals(GroupBy::Campaign) {
+//!     println!("{:?}: {}", total.key, total.cost);
+//! }
+//!
+//! // in the voice callback handler
+//! let notification = VoiceNotification::from_form(&body)?;
+//! gway.record_call_cost(&notification);
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use json;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use airtime::{AirtimeRecipient, AirtimeResponse};
+use delivery::SMSRecipient;
+use metrics::Api;
+use money::Money;
+use voice::VoiceNotification;
+
+/// A recorded cost
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpendEntry {
+    /// the API charged
+    pub api: Api,
+
+    /// sender id, shortcode or caller number, if any
+    pub sender: Option<String>,
+
+    /// campaign tag of the gateway that made the request
+    pub campaign: Option<String>,
+
+    /// UTC day of the request, `YYYY-MM-DD`
+    pub day: String,
+
+    /// amount charged
+    pub cost: Money,
+}
+
+/// What to total spending by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    /// API, e.g. `sms`
+    Api,
+    /// sender
+    Sender,
+    /// campaign tag
+    Campaign,
+    /// UTC day
+    Day,
+}
+
+/// Spending for one group in one currency
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpendTotal {
+    /// the group, `None` for requests without a sender or campaign
+    pub key: Option<String>,
+
+    /// total spent
+    pub cost: Money,
+}
+
+/// The group a running total is kept for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SpendKey {
+    api: Api,
+    sender: Option<String>,
+    campaign: Option<String>,
+    day: String,
+    currency: String,
+}
+
+#[derive(Debug, Default)]
+struct Records {
+    /// units spent per group
+    totals: HashMap<SpendKey, i64>,
+    reserved: HashMap<u64, Reserved>,
+    next_id: u64,
+}
+
+/// Estimated costs of a request in flight
+#[derive(Debug)]
+struct Reserved {
+    day: String,
+    costs: Vec<Money>,
+}
+
+impl Records {
+    /// adds `entry` to its group's total
+    fn add(&mut self, entry: SpendEntry) {
+        let key = SpendKey {
+            api: entry.api,
+            sender: entry.sender,
+            campaign: entry.campaign,
+            day: entry.day,
+            currency: entry.cost.currency,
+        };
+        *self.totals.entry(key).or_insert(0) += entry.cost.units;
+    }
+
+    /// units spent in `currency` on days starting with `period`
+    fn spent(&self, currency: &str, period: &str) -> i64 {
+        self.totals
+            .iter()
+            .filter(|&(k, _)| k.currency == currency && k.day.starts_with(period))
+            .map(|(_, units)| units)
+            .sum()
+    }
+
+    /// units spent and reserved in `currency` on days starting with `period`
+    fn committed(&self, currency: &str, period: &str) -> i64 {
+        let reserved: i64 = self
+            .reserved
+            .values()
+            .filter(|r| r.day.starts_with(period))
+            .flat_map(|r| r.costs.iter())
+            .filter(|c| c.currency == currency)
+            .map(|c| c.units)
+            .sum();
+        self.spent(currency, period) + reserved
+    }
+}
+
+/// Estimated costs held against a ledger's limits while a request is in
+/// flight. Dropping it releases them; `settle` replaces them with the
+/// actual costs.
+#[derive(Debug)]
+pub struct Reservation {
+    records: Arc<Mutex<Records>>,
+    id: u64,
+}
+
+impl Reservation {
+    /// the estimated costs reserved
+    pub fn estimate(&self) -> Vec<Money> {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records
+            .reserved
+            .get(&self.id)
+            .map(|r| r.costs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Releases the reservation and records `entries` in its place.
+    pub fn settle(self, entries: Vec<SpendEntry>) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.reserved.remove(&self.id);
+        for entry in entries {
+            records.add(entry);
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.reserved.remove(&self.id);
+    }
+}
+
+/// Records spending and enforces budget limits; clones share records
+#[derive(Debug, Clone, Default)]
+pub struct SpendLedger {
+    records: Arc<Mutex<Records>>,
+    daily_limits: Vec<Money>,
+    monthly_limits: Vec<Money>,
+    estimates: HashMap<Api, Money>,
+}
+
+impl SpendLedger {
+    /// creates an empty ledger with no limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// limits spending per UTC day in the limit's currency
+    pub fn daily_limit(mut self, limit: Money) -> Self {
+        self.daily_limits.push(limit);
+        self
+    }
+
+    /// limits spending per UTC calendar month in the limit's currency
+    pub fn monthly_limit(mut self, limit: Money) -> Self {
+        self.monthly_limits.push(limit);
+        self
+    }
+
+    /// Estimates each SMS segment or call to `api` to cost `unit` when
+    /// checking limits.
+    pub fn estimate(mut self, api: Api, unit: Money) -> Self {
+        self.estimates.insert(api, unit);
+        self
+    }
+
+    /// Records a cost.
+    pub fn record(&self, entry: SpendEntry) {
+        self.records().add(entry);
+    }
+
+    /// Recorded costs, summed per API, sender, campaign, day and currency,
+    /// ordered by day.
+    pub fn entries(&self) -> Vec<SpendEntry> {
+        let mut entries: Vec<SpendEntry> = self
+            .records()
+            .totals
+            .iter()
+            .map(|(key, &units)| SpendEntry {
+                api: key.api,
+                sender: key.sender.clone(),
+                campaign: key.campaign.clone(),
+                day: key.day.clone(),
+                cost: Money::from_units(&key.currency, units),
+            })
+            .collect();
+        entries.sort_by_key(|e| {
+            let group = (e.api.as_str(), e.sender.clone(), e.campaign.clone());
+            (e.day.clone(), group, e.cost.currency.clone())
+        });
+        entries
+    }
+
+    /// Totals spending by `group` and currency, ordered by group.
+    pub fn totals(&self, group: GroupBy) -> Vec<SpendTotal> {
+        let mut totals: BTreeMap<(Option<String>, String), i64> = BTreeMap::new();
+        for (key, &units) in &self.records().totals {
+            let group_key = match group {
+                GroupBy::Api => Some(key.api.as_str().to_string()),
+                GroupBy::Sender => key.sender.clone(),
+                GroupBy::Campaign => key.campaign.clone(),
+                GroupBy::Day => Some(key.day.clone()),
+            };
+            *totals.entry((group_key, key.currency.clone())).or_insert(0) += units;
+        }
+        totals
+            .into_iter()
+            .map(|((key, currency), units)| SpendTotal {
+                key,
+                cost: Money::from_units(&currency, units),
+            })
+            .collect()
+    }
+
+    /// Total spent in `currency` on days starting with `period`, a
+    /// `YYYY-MM-DD` day or `YYYY-MM` month. Reservations are not included.
+    pub fn spent(&self, currency: &str, period: &str) -> Money {
+        Money::from_units(currency, self.records().spent(currency, period))
+    }
+
+    /// Fails with `ErrorKind::BudgetExceeded` if spending `costs` now, on
+    /// top of what is spent and reserved, would go over a limit, or a limit
+    /// has already been reached.
+    pub fn check(&self, costs: &[Money]) -> Result<()> {
+        self.check_on(costs, &today())
+    }
+
+    /// Checks `costs` like `check` and, if they are within the limits,
+    /// reserves them until the returned reservation is settled or dropped.
+    pub fn reserve(&self, costs: Vec<Money>) -> Result<Reservation> {
+        self.reserve_on(costs, &today())
+    }
+
+    fn check_on(&self, costs: &[Money], day: &str) -> Result<()> {
+        self.check_records(&self.records(), costs, day)
+    }
+
+    fn reserve_on(&self, costs: Vec<Money>, day: &str) -> Result<Reservation> {
+        let mut records = self.records();
+        self.check_records(&records, &costs, day)?;
+        let id = records.next_id;
+        records.next_id += 1;
+        records.reserved.insert(
+            id,
+            Reserved {
+                day: day.into(),
+                costs,
+            },
+        );
+
+        Ok(Reservation {
+            records: self.records.clone(),
+            id,
+        })
+    }
+
+    fn check_records(&self, records: &Records, costs: &[Money], day: &str) -> Result<()> {
+        let periods = [
+            ("daily", day, &self.daily_limits),
+            ("monthly", &day[..7], &self.monthly_limits),
+        ];
+        for &(name, period, limits) in &periods {
+            for limit in limits {
+                let committed = records.committed(&limit.currency, period);
+                let cost: i64 = costs
+                    .iter()
+                    .filter(|c| c.currency == limit.currency)
+                    .map(|c| c.units)
+                    .sum();
+                if committed >= limit.units || committed + cost > limit.units {
+                    // raise error
+                    return Err(ErrorKind::BudgetExceeded(name.into(), limit.to_string()).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn records<'a>(&'a self) -> MutexGuard<'a, Records> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// `units` times the estimated unit cost of `api`, if one is set
+    fn estimated(&self, api: Api, units: usize) -> Vec<Money> {
+        match self.estimates.get(&api) {
+            Some(unit) => vec![Money::from_units(&unit.currency, unit.units * units as i64)],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Records costs in `ledger` and enforces its limits.
+    pub fn with_ledger(mut self, ledger: SpendLedger) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// Tags costs of requests made by this gateway with `campaign`.
+    pub fn with_campaign(mut self, campaign: &str) -> Self {
+        self.campaign = Some(campaign.into());
+        self
+    }
+
+    /// Checks an SMS to `recipients` numbers of `segments` each against the
+    /// ledger's limits, reserving its estimated cost.
+    pub(crate) fn reserve_sms_budget(
+        &self,
+        recipients: usize,
+        segments: usize,
+    ) -> Result<Option<Reservation>> {
+        match self.ledger {
+            Some(ref ledger) => {
+                let costs = ledger.estimated(Api::Sms, recipients * segments);
+                ledger.reserve(costs).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks calls to `recipients` numbers against the ledger's limits,
+    /// reserving their estimated cost.
+    pub(crate) fn reserve_call_budget(&self, recipients: usize) -> Result<Option<Reservation>> {
+        match self.ledger {
+            Some(ref ledger) => ledger
+                .reserve(ledger.estimated(Api::Voice, recipients))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks airtime for `recipients` against the ledger's limits,
+    /// reserving the amounts requested.
+    pub(crate) fn reserve_airtime_budget(
+        &self,
+        recipients: &[AirtimeRecipient],
+    ) -> Result<Option<Reservation>> {
+        match self.ledger {
+            Some(ref ledger) => {
+                let costs = recipients.iter().map(|r| r.amount.clone()).collect();
+                ledger.reserve(costs).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Records the costs in a `send_message` response in place of
+    /// `reservation`, or the reserved estimate if the response or the cost
+    /// of a sent message can't be parsed.
+    pub(crate) fn record_sms_spend(
+        &self,
+        reservation: Option<Reservation>,
+        sender: Option<&str>,
+        response: &json::Value,
+    ) {
+        let reservation = match reservation {
+            Some(reservation) => reservation,
+            None => return,
+        };
+        let sent_costs = |recipients: Vec<SMSRecipient>| -> Option<Vec<Money>> {
+            recipients
+                .iter()
+                .filter(|r| r.is_sent())
+                .map(|r| r.cost.parse().ok())
+                .collect()
+        };
+        let recipients = SMSRecipient::from_response(response).ok();
+        let costs = match recipients.and_then(sent_costs) {
+            Some(costs) => costs,
+            None => {
+                warn!("could not read SMS costs, recording the estimate");
+                reservation.estimate()
+            }
+        };
+        reservation.settle(self.spend_entries(Api::Sms, sender, costs));
+    }
+
+    /// Records airtime sent, less discounts, in place of `reservation`.
+    pub(crate) fn record_airtime_spend(
+        &self,
+        reservation: Option<Reservation>,
+        response: &AirtimeResponse,
+    ) {
+        let reservation = match reservation {
+            Some(reservation) => reservation,
+            None => return,
+        };
+        let costs = response
+            .responses
+            .iter()
+            .filter(|r| r.status == "Sent")
+            .filter_map(|r| {
+                let amount: Money = r.amount.parse().ok()?;
+                let discount = r.discount.parse::<Money>().map(|d| d.units).unwrap_or(0);
+                Some(Money::from_units(&amount.currency, amount.units - discount))
+            })
+            .collect();
+        reservation.settle(self.spend_entries(Api::Airtime, None, costs));
+    }
+
+    /// Records the cost of a call from its call-ended voice callback; other
+    /// notifications are ignored. The sender is the account's number on the
+    /// call, i.e. the caller of an outbound call or the number called on
+    /// an inbound one.
+    pub fn record_call_cost(&self, notification: &VoiceNotification) {
+        let ledger = match self.ledger {
+            Some(ref ledger) if !notification.is_active() => ledger,
+            _ => return,
+        };
+        let sender = if notification.direction == "Inbound" {
+            &notification.destinationNumber
+        } else {
+            &notification.callerNumber
+        };
+        let costs = notification.cost().into_iter().collect();
+        for entry in self.spend_entries(Api::Voice, Some(sender), costs) {
+            ledger.record(entry);
+        }
+    }
+
+    fn spend_entries(&self, api: Api, sender: Option<&str>, costs: Vec<Money>) -> Vec<SpendEntry> {
+        let day = today();
+        costs
+            .into_iter()
+            .filter(|c| c.units != 0)
+            .map(|cost| SpendEntry {
+                api,
+                sender: sender.map(String::from),
+                campaign: self.campaign.clone(),
+                day: day.clone(),
+                cost,
+            })
+            .collect()
+    }
+}
+
+/// Today's UTC date, `YYYY-MM-DD`.
+fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs as i64 / 86_400);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Date in the proleptic Gregorian calendar of a count of days since
+/// 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = (if z >= 0 { z } else { z - 146_096 }) / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    const DAY: &str = "2026-10-19";
+
+    const SMS_SENT: &str = r#"{"SMSMessageData":{"Message":"Sent to 2/2","Recipients":[
+        {"number":"+254711000001","status":"Success","messageId":"ATXid_1","cost":"KES 0.8000"},
+        {"number":"+254711000002","status":"Success","messageId":"ATXid_2","cost":"KES 0.8000"}
+    ]}}"#;
+
+    fn entry(api: Api, campaign: Option<&str>, day: &str, cost: &str) -> SpendEntry {
+        SpendEntry {
+            api,
+            sender: Some("ACME".into()),
+            campaign: campaign.map(String::from),
+            day: day.into(),
+            cost: kes(cost),
+        }
+    }
+
+    fn kes(amount: &str) -> Money {
+        Money::parse_amount("KES", amount).unwrap()
+    }
+
+    fn limited() -> SpendLedger {
+        SpendLedger::new()
+            .daily_limit(Money::whole("KES", 10))
+            .monthly_limit(Money::whole("KES", 15))
+    }
+
+    fn assert_over_budget<T: ::std::fmt::Debug>(result: Result<T>, period: &str) {
+        match result {
+            Err(Error(ErrorKind::BudgetExceeded(p, _), _)) => assert_eq!(p, period),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn totals_spending_by_group() {
+        let ledger = SpendLedger::new();
+        ledger.record(entry(Api::Sms, Some("promo"), "2026-10-18", "4"));
+        ledger.record(entry(Api::Sms, None, DAY, "2.4"));
+        ledger.record(entry(Api::Airtime, Some("promo"), DAY, "5"));
+
+        let totals = ledger.totals(GroupBy::Campaign);
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[0].key, None);
+        assert_eq!(totals[1].cost, Money::whole("KES", 9));
+        assert_eq!(ledger.totals(GroupBy::Api)[1].cost.amount(), "6.4");
+        assert_eq!(ledger.spent("KES", "2026-10").amount(), "11.4");
+    }
+
+    #[test]
+    fn enforces_daily_limit() {
+        let ledger = limited();
+        ledger.record(entry(Api::Sms, None, DAY, "7.4"));
+        assert!(ledger.check_on(&[kes("2.6")], DAY).is_ok());
+        assert_over_budget(ledger.check_on(&[kes("2.7")], DAY), "daily");
+        assert!(ledger.check_on(&[Money::whole("UGX", 500)], DAY).is_ok());
+    }
+
+    #[test]
+    fn enforces_monthly_limit() {
+        let ledger = limited();
+        ledger.record(entry(Api::Sms, None, "2026-10-05", "15"));
+        assert_eq!(
+            ledger.check_on(&[], DAY).unwrap_err().to_string(),
+            "monthly budget of KES 15.0000 would be exceeded"
+        );
+    }
+
+    #[test]
+    fn counts_reservations_against_limits() {
+        let ledger = limited();
+        let reservation = ledger.reserve_on(vec![kes("6")], DAY).unwrap();
+        assert_over_budget(ledger.reserve_on(vec![kes("6")], DAY), "daily");
+        assert_eq!(ledger.spent("KES", DAY), Money::whole("KES", 0));
+
+        drop(reservation);
+        assert!(ledger.reserve_on(vec![kes("6")], DAY).is_ok());
+    }
+
+    #[test]
+    fn settles_reservation_with_actual_cost() {
+        let ledger = limited();
+        let reservation = ledger.reserve_on(vec![kes("8")], DAY).unwrap();
+        reservation.settle(vec![entry(Api::Sms, None, DAY, "1.6")]);
+        assert_eq!(ledger.spent("KES", DAY).amount(), "1.6");
+        assert!(ledger.check_on(&[kes("8.4")], DAY).is_ok());
+    }
+
+    #[test]
+    fn records_call_cost_when_call_ends() {
+        let ledger = SpendLedger::new();
+        let gway = AfricasTalkingGateway::new("sandbox", "key", "sandbox")
+            .with_ledger(ledger.clone())
+            .with_campaign("reminders");
+        let mut notification = VoiceNotification {
+            isActive: "1".into(),
+            sessionId: "ATVId_1".into(),
+            direction: "Outbound".into(),
+            callerNumber: "+254711000000".into(),
+            currencyCode: Some("KES".into()),
+            amount: Some("1.5".into()),
+            ..Default::default()
+        };
+        gway.record_call_cost(&notification);
+        assert!(ledger.entries().is_empty());
+
+        notification.isActive = "0".into();
+        gway.record_call_cost(&notification);
+        let entries = ledger.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].api, Api::Voice);
+        assert_eq!(entries[0].sender.as_deref(), Some("+254711000000"));
+        assert_eq!(entries[0].campaign.as_deref(), Some("reminders"));
+        assert_eq!(entries[0].cost, kes("1.5"));
+    }
+
+    #[test]
+    fn records_sms_cost() {
+        let (url, _) = ::tests::serve(&[(201, SMS_SENT)]);
+        let ledger = SpendLedger::new().estimate(Api::Sms, kes("1"));
+        let gway = ::tests::gateway(&url).with_ledger(ledger.clone());
+        gway.send_bulk_message(&["+254711000001", "+254711000002"], "Hi", Some("ACME"))
+            .unwrap();
+        assert_eq!(ledger.spent("KES", &today()).amount(), "1.6");
+        assert_eq!(ledger.entries()[0].sender.as_deref(), Some("ACME"));
+    }
+
+    #[test]
+    fn records_estimate_when_costs_are_unreadable() {
+        let unparsed = r#"{"SMSMessageData":{"Message":"Sent to 1/1","Recipients":[
+            {"number":"+254711000001","status":"Success","messageId":"ATXid_1","cost":"0.8"}
+        ]}}"#;
+        let (url, _) = ::tests::serve(&[(201, unparsed), (201, r#"{"unexpected":true}"#)]);
+        let ledger = SpendLedger::new().estimate(Api::Sms, kes("1"));
+        let gway = ::tests::gateway(&url).with_ledger(ledger.clone());
+        gway.send_bulk_message(&["+254711000001"], "Hi", None)
+            .unwrap();
+        assert_eq!(ledger.spent("KES", &today()).amount(), "1");
+        let _ = gway.send_bulk_message(&["+254711000001"], "Hi", None);
+        assert_eq!(ledger.spent("KES", &today()).amount(), "2");
+    }
+
+    #[test]
+    fn sums_costs_per_group() {
+        let ledger = SpendLedger::new();
+        ledger.record(entry(Api::Sms, None, DAY, "0.8"));
+        ledger.record(entry(Api::Sms, None, DAY, "0.8"));
+        ledger.record(entry(Api::Sms, None, "2026-10-18", "0.8"));
+        let entries = ledger.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].day, DAY);
+        assert_eq!(entries[1].cost, kes("1.6"));
+    }
+
+    #[test]
+    fn refuses_sms_over_budget_before_sending() {
+        let (url, requests) = ::tests::serve(&[(201, SMS_SENT)]);
+        let ledger = SpendLedger::new()
+            .daily_limit(Money::whole("KES", 1))
+            .estimate(Api::Sms, kes("0.8"));
+        let gway = ::tests::gateway(&url).with_ledger(ledger);
+        let to = ["+254711000001", "+254711000002"];
+        assert_over_budget(gway.send_bulk_message(&to, "Hi", None), "daily");
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn releases_reservation_on_error_response() {
+        let (url, _) = ::tests::serve(&[(500, "Internal Server Error")]);
+        let ledger = SpendLedger::new()
+            .daily_limit(Money::whole("KES", 1))
+            .estimate(Api::Sms, kes("0.8"));
+        let gway = ::tests::gateway(&url).with_ledger(ledger.clone());
+        let sent = gway.send_bulk_message(&["+254711000001"], "Hi", None);
+        assert!(sent.is_err());
+        assert!(ledger.check(&[kes("1")]).is_ok());
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(20_745), (2026, 10, 19));
+    }
+}
@@ -7,20 +7,96 @@
 extern crate error_chain;
 #[macro_use]
 extern crate hyper;
+extern crate rand;
+extern crate regex;
 extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json as json;
+extern crate serde_urlencoded;
+extern crate sha2;
+extern crate toml;
+#[macro_use]
+extern crate tracing;
+extern crate zeroize;
 
 use std::collections::HashMap;
 use std::io::Read;
+use std::sync::Arc;
 
 use hyper::header::{Accept, Headers};
 use serde::ser::Serialize;
 
-header! { (Apikey, "apikey") => [String] }
+use airtime::{AirtimeRecipient, AirtimeResponse};
+use insights::SimSwapPolicy;
+use ledger::SpendLedger;
+use media::MediaFile;
+use metrics::MetricsHook;
+use optout::OptOutStore;
+use ratelimit::RateLimiter;
+use secret::{redact, SecretString};
+use sender::SenderRegistry;
+use subscription::{subscription_response, Subscription, SubscriptionResponse};
+use token::TokenCache;
+use voice::{check_voice_error, CallEntry, QueueStatus};
+
+pub mod airtime;
+pub mod application;
+pub mod config;
+pub mod delivery;
+pub mod dialer;
+pub mod insights;
+pub mod ledger;
+pub mod media;
+pub mod metrics;
+pub mod mobile_data;
+pub mod money;
+pub mod optout;
+pub mod otp;
+pub mod payments;
+pub mod queue;
+pub mod ratelimit;
+pub mod reconciliation;
+pub mod router;
+pub mod secret;
+pub mod sender;
+pub mod subscription;
+pub mod template;
+pub mod token;
+pub mod voice;
+
+/// Declares a header holding a `SecretString`, so the copy kept in each
+/// request's headers is zeroed when they are dropped.
+macro_rules! secret_header {
+    ($(#[$attr:meta])* ($id:ident, $name:expr)) => {
+        $(#[$attr])*
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct $id(pub SecretString);
+
+        impl hyper::header::Header for $id {
+            fn header_name() -> &'static str {
+                $name
+            }
+
+            fn parse_header(raw: &hyper::header::Raw) -> hyper::Result<Self> {
+                raw.one()
+                    .and_then(|line| ::std::str::from_utf8(line).ok())
+                    .map(|value| $id(SecretString::new(value)))
+                    .ok_or(hyper::Error::Header)
+            }
+
+            fn fmt_header(&self, f: &mut hyper::header::Formatter) -> ::std::fmt::Result {
+                f.fmt_line(&self.0.expose())
+            }
+        }
+    };
+}
+
+secret_header! { (Apikey, "apikey") }
+secret_header! { (Authtoken, "authToken") }
+header! { (IdempotencyKey, "Idempotency-Key") => [String] }
 
 #[allow(unused_variables)]
 trait HttpAccessMethods {
@@ -32,17 +108,85 @@ error_chain! {
         Network(reqwest::Error);
         Io(::std::io::Error);
         Json(json::Error);
+        Url(reqwest::UrlError);
+        Regex(regex::Error);
+        UrlEncoded(serde_urlencoded::de::Error);
+        Toml(toml::de::Error);
     }
     errors {
         GatewayError(e: String){
             description("Gateway error"),
             display("{}", e),
+        }
+        PayoutRefused(phone_numbers: Vec<String>){
+            description("Payout refused"),
+            display("payout refused by SIM swap policy: {}", phone_numbers.join(", ")),
+        }
+        InvalidMedia(url: String, reason: String){
+            description("Invalid media file"),
+            display("invalid media file {}: {}", url, reason),
+        }
+        UnregisteredMedia(urls: Vec<String>){
+            description("Unregistered media file"),
+            display("media files not uploaded: {}", urls.join(", ")),
+        }
+        InvalidSender(sender: String, reason: String){
+            description("Invalid sender"),
+            display("invalid sender {:?}: {}", sender, reason),
+        }
+        InvalidPhoneNumber(phone_number: String){
+            description("Invalid phone number"),
+            display("invalid phone number {:?}", phone_number),
+        }
+        PartiallySent(responses: Vec<json::Value>){
+            description("Partially sent"),
+            display("failed after {} requests were sent", responses.len()),
+        }
+        QueueNotSaved(outcomes: Vec<queue::QueueOutcome>){
+            description("Queue not saved"),
+            display("queue not saved after {} messages were processed", outcomes.len()),
+        }
+        NoRecipients{
+            description("No recipients"),
+            display("no recipients to send to"),
+        }
+        UnroutableNumber(phone_number: String){
+            description("No sender for number"),
+            display("no sender registered for {}", phone_number),
+        }
+        InvalidTemplate(template: String){
+            description("Invalid template"),
+            display("invalid template: {:?}", template),
+        }
+        MissingTemplateVariable(phone_number: String, name: String){
+            description("Missing template variable"),
+            display("no value for template variable {:?} for {}", name, phone_number),
+        }
+        OtpCooldown(seconds: u64){
+            description("Verification code resent too soon"),
+            display("a verification code can be resent in {} seconds", seconds),
+        }
+        MissingConfig(key: String){
+            description("Missing configuration value"),
+            display("missing configuration value {}", key),
+        }
+        InvalidConfig(key: String, reason: String){
+            description("Invalid configuration value"),
+            display("invalid configuration value {}: {}", key, reason),
+        }
+        RateLimited(api: String, retry_after: ::std::time::Duration){
+            description("Rate limited"),
+            display("{} requests rate limited, retry in {:.1}s", api, retry_after.as_secs_f64()),
+        }
+        BudgetExceeded(period: String, limit: String){
+            description("Budget exceeded"),
+            display("{} budget of {} would be exceeded", period, limit),
         } }
 
 }
 
 /// SMS Message Struct
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[allow(non_snake_case)]
 pub struct SMSMessage {
     /// Africa's Talking account username
@@ -109,10 +253,10 @@ impl SMSMessage {
 }
 
 /// Gateway struct
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AfricasTalkingGateway {
     username: String,
-    api_key: String,
+    api_key: SecretString,
     env: String,
     user_data_url: String,
     sms_url: String,
@@ -122,6 +266,24 @@ pub struct AfricasTalkingGateway {
     mobi_payment_checkout_url: String,
     mobi_payment_b2c_url: String,
     mobi_payment_b2b_url: String,
+    wallet_balance_url: String,
+    wallet_transfer_url: String,
+    topup_stash_url: String,
+    find_transaction_url: String,
+    product_transactions_url: String,
+    wallet_transactions_url: String,
+    mobile_data_url: String,
+    checkout_token_url: String,
+    auth_token_url: String,
+    auth_tokens: Option<Arc<TokenCache>>,
+    sim_swap_url: String,
+    sim_swap_policy: Option<SimSwapPolicy>,
+    opt_outs: Option<Arc<dyn OptOutStore>>,
+    senders: Option<SenderRegistry>,
+    metrics: Option<Arc<dyn MetricsHook>>,
+    rate_limiter: Option<RateLimiter>,
+    ledger: Option<SpendLedger>,
+    campaign: Option<String>,
 }
 
 impl AfricasTalkingGateway {
@@ -142,10 +304,20 @@ impl AfricasTalkingGateway {
         } else {
             "https://payments.africastalking.com"
         };
+        let bundles_host = if env == "sandbox" {
+            "https://bundles.sandbox.africastalking.com"
+        } else {
+            "https://bundles.africastalking.com"
+        };
+        let insights_host = if env == "sandbox" {
+            "https://insights.sandbox.africastalking.com"
+        } else {
+            "https://insights.africastalking.com"
+        };
 
         Self {
             username: username.into(),
-            api_key: api_key.into(),
+            api_key: SecretString::new(api_key),
             env: env.into(),
             user_data_url: format!("{}/version1/user", api_host),
             sms_url: format!("{}/version1/messaging", api_host),
@@ -155,9 +327,40 @@ impl AfricasTalkingGateway {
             mobi_payment_checkout_url: format!("{}/mobile/checkout/request", payments_host),
             mobi_payment_b2c_url: format!("{}/mobile/b2c/request", payments_host),
             mobi_payment_b2b_url: format!("{}/mobile/b2b/request", payments_host),
+            wallet_balance_url: format!("{}/query/wallet/balance", payments_host),
+            wallet_transfer_url: format!("{}/transfer/wallet", payments_host),
+            topup_stash_url: format!("{}/topup/stash", payments_host),
+            find_transaction_url: format!("{}/query/transaction/find", payments_host),
+            product_transactions_url: format!("{}/query/transaction/fetch", payments_host),
+            wallet_transactions_url: format!("{}/query/wallet/fetch", payments_host),
+            mobile_data_url: format!("{}/mobile/data/request", bundles_host),
+            checkout_token_url: format!("{}/checkout/token/create", api_host),
+            auth_token_url: format!("{}/auth-token/generate", api_host),
+            auth_tokens: None,
+            sim_swap_url: format!("{}/v1/sim-swap", insights_host),
+            sim_swap_policy: None,
+            opt_outs: None,
+            senders: None,
+            metrics: None,
+            rate_limiter: None,
+            ledger: None,
+            campaign: None,
         }
     }
 
+    /// the account username
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Authenticates requests with short-lived auth tokens instead of the
+    /// API key. Tokens are generated on first use and refreshed shortly
+    /// before they expire.
+    pub fn with_auth_token(mut self) -> Self {
+        self.auth_tokens = Some(Arc::new(TokenCache::default()));
+        self
+    }
+
     /// Gets user data
     pub fn get_user_data(&self) -> Result<json::Value> {
         let url = format!("{}?username={}", self.user_data_url, self.username);
@@ -168,14 +371,106 @@ impl AfricasTalkingGateway {
 
     /// Sends an SMS message
     /// [read more..](http://docs.africastalking.com/sms/sending)
-    pub fn send_message(&self, msg: SMSMessage) -> Result<json::Value> {
+    ///
+    /// With an opt-out store set, numbers opted out of the message's sender
+    /// are dropped from `to` and reported as `UserInBlacklist` recipients in
+    /// the response; if every number is dropped, no request is sent. A `to`
+    /// with no numbers fails with `ErrorKind::NoRecipients`. With a
+    /// sender registry set and no `from`, the message is sent from each
+    /// recipient's country sender, one request per sender; if a later request
+    /// fails, the error is chained onto `ErrorKind::PartiallySent` with the
+    /// responses of the requests already sent. With a spend
+    /// ledger set, the message is checked against its limits and its cost
+    /// recorded.
+    pub fn send_message(&self, mut msg: SMSMessage) -> Result<json::Value> {
+        if let (&None, Some(ref registry)) = (&msg.from, &self.senders) {
+            let to: Vec<&str> = msg
+                .to
+                .split(',')
+                .map(str::trim)
+                .filter(|to| !to.is_empty())
+                .collect();
+            if to.is_empty() {
+                // raise error
+                return Err(ErrorKind::NoRecipients.into());
+            }
+            let mut responses = Vec::new();
+            for batch in registry.batches(&to)? {
+                let batch_msg = SMSMessage {
+                    to: batch.recipients.join(","),
+                    from: Some(batch.sender.as_str().into()),
+                    ..msg.clone()
+                };
+                match self.send_message(batch_msg) {
+                    Ok(response) => responses.push(response),
+                    Err(e) if responses.is_empty() => return Err(e),
+                    // raise error
+                    Err(e) => return Err(e).chain_err(|| ErrorKind::PartiallySent(responses)),
+                }
+            }
+            return Ok(if responses.len() == 1 {
+                responses.remove(0)
+            } else {
+                sender::merge_responses(responses)
+            });
+        }
+        let dropped = match self.opt_outs {
+            Some(ref store) => {
+                let sender_id = msg.from.as_deref().unwrap_or(optout::DEFAULT_SENDER);
+                let (allowed, dropped) = optout::partition(store.as_ref(), sender_id, &msg.to)?;
+                if allowed.is_empty() && !dropped.is_empty() {
+                    return Ok(optout::report_dropped(None, &dropped));
+                }
+                msg.to = allowed.join(",");
+                dropped
+            }
+            None => Vec::new(),
+        };
+        let recipients = msg.to.split(',').filter(|to| !to.trim().is_empty()).count();
+        if recipients == 0 {
+            // raise error
+            return Err(ErrorKind::NoRecipients.into());
+        }
+        let reservation = self.reserve_sms_budget(recipients, template::segments(&msg.message))?;
+        let sender = msg.from.clone();
         let mut resp = self.send_form_data(&self.sms_url, msg)?;
         let mut buf = String::new();
         resp.read_to_string(&mut buf)?;
 
         let val: json::Value = json::from_str(&buf)?;
+        self.record_sms_spend(reservation, sender.as_deref(), &val);
+        if let Some(ref store) = self.opt_outs {
+            let sender_id = sender.as_deref().unwrap_or(optout::DEFAULT_SENDER);
+            if let Err(e) = store.import_blacklisted(sender_id, &val) {
+                warn!(error = %e, "could not record blacklisted recipients");
+            }
+        }
+        if dropped.is_empty() {
+            Ok(val)
+        } else {
+            Ok(optout::report_dropped(Some(val), &dropped))
+        }
+    }
 
-        Ok(val)
+    /// Sends the same SMS message to several numbers in one request.
+    pub fn send_bulk_message(
+        &self,
+        to: &[&str],
+        message: &str,
+        from: Option<&str>,
+    ) -> Result<json::Value> {
+        let msg = SMSMessage::new(
+            &self.username,
+            &to.join(","),
+            message,
+            Some(1),
+            from.map(String::from),
+            None,
+            None,
+            None,
+            None,
+        );
+        self.send_message(msg)
     }
 
     /// Fetches messages from Africa's Talking API
@@ -197,42 +492,47 @@ impl AfricasTalkingGateway {
             Ok(messages)
         } else {
             // raise error
-            Err(ErrorKind::GatewayError(format!("{}", resp.text()?)).into())
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
         }
     }
 
     /// Creates a subscription
     /// [read more..](http://docs.africastalking.com/subscriptions/create)
+    ///
+    /// The API requires a checkout token for the subscriber's phone number;
+    /// if `checkout_token` is `None` one is created with `create_checkout_token`.
     pub fn create_subscription(
         &self,
         phone_number: &str,
         short_code: &str,
         keyword: &str,
-    ) -> Result<json::Value> {
+        checkout_token: Option<&str>,
+    ) -> Result<SubscriptionResponse> {
+        let token = match checkout_token {
+            Some(token) => token.to_string(),
+            None => self.create_checkout_token(phone_number)?.token,
+        };
         let url = format!("{}/create", self.sms_subscription_url);
         let params = json!({
                 "username": self.username,
                 "phoneNumber": phone_number,
                 "shortCode": short_code,
-                "keyword": keyword
+                "keyword": keyword,
+                "checkoutToken": token
             });
 
-        let mut resp = self.send_json_request(&url, params)?;
-        if resp.status().as_u16() == 201 {
-            let jsn: json::Value = resp.json()?;
-            Ok(jsn)
-        } else {
-            Err(ErrorKind::GatewayError(format!("{}", resp.text()?)).into())
-        }
+        let mut resp = self.send_form_data(&url, params)?;
+        subscription_response(&mut resp)
     }
 
     /// Deletes a subscription
+    /// [read more..](http://docs.africastalking.com/subscriptions/delete)
     pub fn delete_subscription(
         &self,
         phone_number: &str,
         short_code: &str,
         keyword: &str,
-    ) -> Result<json::Value> {
+    ) -> Result<SubscriptionResponse> {
         let url = format!("{}/delete", self.sms_subscription_url);
         let params = json!({
                 "username": self.username,
@@ -241,23 +541,22 @@ impl AfricasTalkingGateway {
                 "keyword": keyword
             });
 
-        let mut resp = self.send_json_request(&url, params)?;
-        if resp.status().as_u16() == 201 {
-            let jsn: json::Value = resp.json()?;
-            Ok(jsn)
-        } else {
-            Err(ErrorKind::GatewayError(format!("{}", resp.text()?)).into())
-        }
+        let mut resp = self.send_form_data(&url, params)?;
+        subscription_response(&mut resp)
     }
 
     /// Fetches subscriptions
     /// [read more..](http://docs.africastalking.com/subscriptions/fetchsubscriptions)
+    ///
+    /// Returns up to 100 subscriptions received after `last_received_id`;
+    /// specify 0 to start from the beginning, or use `subscriptions` to
+    /// walk every page.
     pub fn fetch_subscriptions(
         &self,
         short_code: &str,
         keyword: &str,
-        last_received_id: i32,
-    ) -> Result<json::Value> {
+        last_received_id: i64,
+    ) -> Result<Vec<Subscription>> {
         let url = format!(
             "{}?username={}&shortCode={}&keyword={}&lastReceivedId={}",
             self.sms_subscription_url, self.username, short_code, keyword, last_received_id
@@ -266,10 +565,12 @@ impl AfricasTalkingGateway {
         let mut resp = self.send_request(&url, None)?;
         if resp.status().as_u16() == 200 {
             let jsn: json::Value = resp.json()?;
-            let responses: json::Value = jsn.get("responses").unwrap().clone();
-            Ok(responses)
+            match jsn.get("responses") {
+                Some(responses) => Ok(json::from_value(responses.clone())?),
+                None => Ok(Vec::new()),
+            }
         } else {
-            Err(ErrorKind::GatewayError(format!("{}", resp.text()?)).into())
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
         }
     }
 
@@ -278,138 +579,185 @@ impl AfricasTalkingGateway {
         url: &str,
         data: Option<HashMap<&str, &str>>,
     ) -> Result<reqwest::Response> {
-        let mut headers = Headers::new();
-        headers.set(Accept::json());
-        headers.set(Apikey(self.api_key.clone()));
+        let headers = self.auth_headers(Headers::new())?;
         let client = reqwest::Client::new();
-        let resp = match data {
-            Some(map) => client.post(url).json(&map).send()?,
-            None => client.get(url).headers(headers).send()?,
-        };
-
-        Ok(resp)
+        match data {
+            Some(map) => self.send_limited("POST", url, false, || {
+                Ok(client.post(url).json(&map).headers(headers.clone()).send()?)
+            }),
+            None => self.send_limited("GET", url, true, || {
+                Ok(client.get(url).headers(headers.clone()).send()?)
+            }),
+        }
     }
 
     fn send_form_data<T: Serialize>(&self, url: &str, data: T) -> Result<reqwest::Response> {
-        let mut headers = Headers::new();
-        headers.set(Accept::json());
-        headers.set(Apikey(self.api_key.clone()));
-        let client = reqwest::Client::new();
-        let resp = client.post(url).form(&data).headers(headers).send()?;
+        self.send_form_data_with_headers(url, data, Headers::new())
+    }
 
-        Ok(resp)
+    fn send_form_data_with_headers<T: Serialize>(
+        &self,
+        url: &str,
+        data: T,
+        headers: Headers,
+    ) -> Result<reqwest::Response> {
+        let idempotent = headers.has::<IdempotencyKey>();
+        let headers = self.auth_headers(headers)?;
+        let client = reqwest::Client::new();
+        self.send_limited("POST", url, idempotent, || {
+            Ok(client.post(url).form(&data).headers(headers.clone()).send()?)
+        })
     }
 
     fn send_json_request<T: Serialize>(&self, url: &str, data: T) -> Result<reqwest::Response> {
-        let mut headers = Headers::new();
-        headers.set(Accept::json());
-        headers.set(Apikey(self.api_key.clone()));
+        let headers = self.auth_headers(Headers::new())?;
         let client = reqwest::Client::new();
-        let resp = client.post(url).json(&data).headers(headers).send()?;
+        self.send_limited("POST", url, false, || {
+            Ok(client.post(url).json(&data).headers(headers.clone()).send()?)
+        })
+    }
 
-        Ok(resp)
+    /// Adds the `Accept` header and either the API key or, when enabled with
+    /// `with_auth_token`, a current auth token.
+    fn auth_headers(&self, mut headers: Headers) -> Result<Headers> {
+        headers.set(Accept::json());
+        match self.auth_tokens {
+            Some(ref tokens) => headers.set(Authtoken(tokens.token(self)?)),
+            None => headers.set(Apikey(self.api_key.clone())),
+        }
+
+        Ok(headers)
     }
 
     /// Makes voice call. [docs reference](http://docs.africastalking.com/voice/call)
-    pub fn call(&self, from: &str, to: &str) -> Result<json::Value> {
-        let params = json!({
+    ///
+    /// Calls every number in `to` from the same `from` number, returning one
+    /// entry (with its session id) per destination. `client_request_id` is
+    /// echoed back in the voice callbacks for the call.
+    pub fn call(
+        &self,
+        from: &str,
+        to: &[&str],
+        client_request_id: Option<&str>,
+    ) -> Result<Vec<CallEntry>> {
+        let mut params = json!({
             "username": self.username,
             "from": from,
-            "to": to
+            "to": to.join(",")
         });
+        if let Some(id) = client_request_id {
+            params["clientRequestId"] = json!(id);
+        }
+        // the estimate is released on return; calls are charged once they
+        // end, see `record_call_cost`
+        let _reservation = self.reserve_call_budget(to.len())?;
         let url = format!("{}/call", self.voice_url);
         let mut resp = self.send_form_data(&url, params)?;
         let jsn: json::Value = resp.json()?;
-        let entries: json::Value = jsn.get("entries").unwrap().clone();
-        if jsn["errorMessage"].as_str().unwrap() == "None" {
-            Ok(entries)
-        } else {
-            // raise error
-            Err(ErrorKind::GatewayError(format!("{}", jsn["errorMessage"])).into())
-        }
+        check_voice_error(&jsn)?;
+
+        Ok(json::from_value(jsn["entries"].clone())?)
     }
 
     /// Gets queued calls. [docs reference](http://docs.africastalking.com/voice/queuedcalls)
+    ///
+    /// `phone_numbers` is a comma separated list of the numbers whose queues
+    /// to check.
     pub fn get_queued_calls(
         &self,
-        phone_number: &str,
+        phone_numbers: &str,
         queue_name: Option<&str>,
-    ) -> Result<json::Value> {
+    ) -> Result<Vec<QueueStatus>> {
         let params = if queue_name.is_some() {
             json!({
                 "username": self.username,
-                "phoneNumbers": phone_number,
+                "phoneNumbers": phone_numbers,
                 "queueName": queue_name
             })
         } else {
             json!({
                 "username": self.username,
-                "phoneNumbers": phone_number
+                "phoneNumbers": phone_numbers
             })
         };
         let url = format!("{}/queueStatus", self.voice_url);
         let mut resp = self.send_form_data(&url, params)?;
         let jsn: json::Value = resp.json()?;
-        let entries: json::Value = jsn.get("entries").unwrap().clone();
-        if jsn["errorMessage"].as_str().unwrap() == "None" {
-            Ok(entries)
-        } else {
-            // raise error
-            Err(ErrorKind::GatewayError(format!("{}", jsn["errorMessage"])).into())
-        }
+        check_voice_error(&jsn)?;
+
+        Ok(json::from_value(jsn["entries"].clone())?)
     }
 
     /// Uploads Media File. [docs reference](http://docs.africastalking.com/voice/uploadmedia)
+    ///
+    /// The URL is validated locally first and must name an mp3 or wav
+    /// file; for URLs without an extension, give the type with
+    /// `MediaFile::with_content_type` and upload through
+    /// `media::MediaRegistry`, which also avoids uploading the same file
+    /// twice.
     pub fn upload_media_file(&self, media_url: &str) -> Result<json::Value> {
+        self.upload_media(&MediaFile::new(media_url)?)
+    }
+
+    /// Uploads a validated media file, failing if its type is unknown.
+    pub(crate) fn upload_media(&self, file: &MediaFile) -> Result<json::Value> {
+        if file.media_type.is_none() {
+            // raise error
+            return Err(ErrorKind::InvalidMedia(
+                file.url.clone(),
+                "unknown file type, give its Content-Type first".into(),
+            )
+            .into());
+        }
         let params = json!({
             "username": self.username,
-            "url": media_url,
+            "url": file.url,
         });
         let url = format!("{}/mediaUpload", self.voice_url);
         let mut resp = self.send_form_data(&url, params)?;
         let jsn: json::Value = resp.json()?;
-        if jsn["errorMessage"].as_str().unwrap() == "None" {
-            Ok(jsn)
-        } else {
-            // raise error
-            Err(ErrorKind::GatewayError(format!("{}", jsn["errorMessage"])).into())
-        }
+        check_voice_error(&jsn)?;
+
+        Ok(jsn)
     }
 
     /// Sends airtime. [docs reference](http://docs.africastalking.com/airtime/sending)
     ///
-    /// `recipients` is a json array of the format
-    ///
-    /// ```json,ignore
-    /// [
-    ///   {
-    ///     "phoneNumber":"+254711XXXYYY",
-    ///     "amount":"KES X"
-    ///   },
-    ///   {
-    ///     "phoneNumber":"+254733YYYZZZ",
-    ///     "amount":"KES Y"
-    ///   }
-    /// ]
-    /// ```
-    pub fn send_airtime(&self, recipients: &json::Value) -> Result<json::Value> {
-        let params = json!({
+    /// `max_num_retry` is the number of times the API retries a failed
+    /// request, and `idempotency_key` guards against sending the same
+    /// airtime twice when a request is retried.
+    pub fn send_airtime(
+        &self,
+        recipients: &[AirtimeRecipient],
+        max_num_retry: Option<i32>,
+        idempotency_key: Option<&str>,
+    ) -> Result<AirtimeResponse> {
+        let reservation = self.reserve_airtime_budget(recipients)?;
+        let recipients: Vec<json::Value> = recipients.iter().map(|r| r.to_value()).collect();
+        let mut params = json!({
             "username": self.username,
-            "recipients": recipients
+            "recipients": json::to_string(&recipients)?
         });
-        let mut resp = self.send_form_data(&self.send_airtime_url, params)?;
+        if let Some(retries) = max_num_retry {
+            params["maxNumRetry"] = json!(retries);
+        }
+        let mut headers = Headers::new();
+        if let Some(key) = idempotency_key {
+            headers.set(IdempotencyKey(key.into()));
+        }
+        let mut resp = self.send_form_data_with_headers(&self.send_airtime_url, params, headers)?;
         if resp.status().as_u16() == 201 {
-            let jsn: json::Value = resp.json()?;
-            let responses: json::Value = jsn.get("responses").unwrap().clone();
-            if !jsn["responses"].as_array().unwrap().is_empty() {
-                Ok(responses)
+            let airtime: AirtimeResponse = resp.json()?;
+            if !airtime.responses.is_empty() {
+                self.record_airtime_spend(reservation, &airtime);
+                Ok(airtime)
             } else {
                 // raise error
-                Err(ErrorKind::GatewayError(format!("{}", jsn["errorMessage"])).into())
+                Err(ErrorKind::GatewayError(airtime.errorMessage).into())
             }
         } else {
             // raise error
-            Err(ErrorKind::GatewayError(format!("{}", resp.text()?)).into())
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
         }
     }
 
@@ -445,7 +793,7 @@ impl AfricasTalkingGateway {
             }
         } else {
             // raise error
-            Err(ErrorKind::GatewayError(format!("{}", resp.text()?)).into())
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
         }
     }
 
@@ -489,12 +837,16 @@ impl AfricasTalkingGateway {
             Ok(jsn)
         } else {
             // raise error
-            Err(ErrorKind::GatewayError(format!("{:?}", resp)).into())
+            Err(ErrorKind::GatewayError(redact(&format!("{:?}", resp))).into())
         }
     }
 
     /// Requests a Business-to-Consumer payment to  mobile subscribers phone numbers.
     /// [read more..](http://docs.africastalking.com/mobile/b2c)
+    ///
+    /// If the gateway has a `SimSwapPolicy`, recipients are checked before
+    /// any payment is requested, and a recipient without a phone number
+    /// fails with `ErrorKind::InvalidPhoneNumber`.
     pub fn mobile_payment_b2c_request(
         &self,
         product_name: &str,
@@ -504,6 +856,19 @@ impl AfricasTalkingGateway {
             recipients.as_array().unwrap().len() <= 10,
             "Recipients should not be greater than 10"
         );
+        if let Some(ref policy) = self.sim_swap_policy {
+            let phone_numbers = recipients
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| match r["phoneNumber"].as_str() {
+                    Some(phone_number) => Ok(phone_number),
+                    // raise error
+                    None => Err(ErrorKind::InvalidPhoneNumber(r["phoneNumber"].to_string()).into()),
+                })
+                .collect::<Result<Vec<&str>>>()?;
+            policy.check(self, &phone_numbers)?;
+        }
         let params = json!({
             "username": self.username,
             "productName": product_name,
@@ -520,7 +885,7 @@ impl AfricasTalkingGateway {
                 Err(ErrorKind::GatewayError(format!("{}", jsn["errorMessage"])).into())
             }
         } else {
-            Err(ErrorKind::GatewayError(format!("{:?}", resp.text()?)).into())
+            Err(ErrorKind::GatewayError(redact(&format!("{:?}", resp.text()?))).into())
         }
     }
 }
@@ -529,6 +894,106 @@ impl AfricasTalkingGateway {
 mod tests {
     use super::*;
     use std::env;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    /// Answers requests on a local port with `responses`, in order, and
+    /// passes each request's text to the returned receiver.
+    pub fn serve(responses: &[(u16, &str)]) -> (String, Receiver<String>) {
+        let responses: Vec<(u16, &str, &str)> =
+            responses.iter().map(|&(s, b)| (s, "", b)).collect();
+        serve_with_headers(&responses)
+    }
+
+    /// Like `serve`, with extra header lines, each ending in `\r\n`, sent
+    /// before each response's body.
+    pub fn serve_with_headers(responses: &[(u16, &str, &str)]) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let responses: Vec<(u16, String, String)> = responses
+            .iter()
+            .map(|&(s, h, b)| (s, h.to_string(), b.to_string()))
+            .collect();
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || {
+            for (status, headers, body) in responses {
+                let mut stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(_) => return,
+                };
+                let _ = requests.send(read_request(&mut stream));
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} Test\r\nContent-Type: application/json\r\n{}\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        (url, received)
+    }
+
+    fn read_request<R: Read>(stream: &mut R) -> String {
+        let mut request = Vec::new();
+        let mut byte = [0; 1];
+        while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+            request.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&request).into_owned();
+        let length = head
+            .lines()
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                if name.eq_ignore_ascii_case("content-length") {
+                    value.trim().parse().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        let _ = stream.read_exact(&mut body);
+        head + &String::from_utf8_lossy(&body)
+    }
+
+    /// A gateway sending every request to `url`.
+    pub fn gateway(url: &str) -> AfricasTalkingGateway {
+        let mut gway = AfricasTalkingGateway::new("sandbox", "key", "sandbox");
+        for field in &mut [
+            &mut gway.user_data_url,
+            &mut gway.sms_url,
+            &mut gway.voice_url,
+            &mut gway.sms_subscription_url,
+            &mut gway.send_airtime_url,
+            &mut gway.mobi_payment_checkout_url,
+            &mut gway.mobi_payment_b2c_url,
+            &mut gway.mobi_payment_b2b_url,
+            &mut gway.wallet_balance_url,
+            &mut gway.wallet_transfer_url,
+            &mut gway.topup_stash_url,
+            &mut gway.find_transaction_url,
+            &mut gway.product_transactions_url,
+            &mut gway.wallet_transactions_url,
+            &mut gway.mobile_data_url,
+            &mut gway.checkout_token_url,
+            &mut gway.auth_token_url,
+            &mut gway.sim_swap_url,
+        ] {
+            let path = match field.find(".com") {
+                Some(i) => field[i + 4..].to_string(),
+                None => String::new(),
+            };
+            **field = format!("{}{}", url, path);
+        }
+        gway
+    }
 
     #[test]
     fn it_works() {}
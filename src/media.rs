@@ -0,0 +1,279 @@
+//! Voice media files: local validation and a registry of uploaded files.
+//!
+//! ```rust,ignore
+//! let mut registry = MediaRegistry::load("media.json")?;
+//! let file = MediaFile::new("https://cdn.example.com/a1b2c3?sig=...")?
+//!     .with_content_type("audio/mpeg")?
+//!     .with_size(120_000)?;
+//! registry.upload(&gway, &file)?;
+//! registry.save("media.json")?;
+//!
+//! let xml = VoiceResponse::new().play(&file.url);
+//! xml.check_media(&registry)?;
+//! ```
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::Path;
+
+use json;
+use reqwest::Url;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+
+/// Largest media file the voice API accepts, in bytes.
+pub const MAX_MEDIA_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Media file type
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    /// MPEG audio, `.mp3`
+    Mp3,
+    /// WAVE audio, `.wav`
+    Wav,
+}
+
+impl MediaType {
+    /// Media type for a file name or URL path, from its extension.
+    pub fn from_path(path: &str) -> Option<Self> {
+        match extension(path)?.to_lowercase().as_str() {
+            "mp3" => Some(MediaType::Mp3),
+            "wav" => Some(MediaType::Wav),
+            _ => None,
+        }
+    }
+
+    /// Media type for a `Content-Type` header value, ignoring parameters.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next()?.trim().to_lowercase();
+        match essence.as_str() {
+            "audio/mpeg" | "audio/mp3" => Some(MediaType::Mp3),
+            "audio/wav" | "audio/wave" | "audio/x-wav" | "audio/vnd.wave" => Some(MediaType::Wav),
+            _ => None,
+        }
+    }
+}
+
+/// The extension of the last segment of `path`, if it has one.
+fn extension(path: &str) -> Option<&str> {
+    let name = path.rsplit('/').next()?;
+    match name.rfind('.') {
+        Some(i) if i + 1 < name.len() => Some(&name[i + 1..]),
+        _ => None,
+    }
+}
+
+/// A media file to upload or play, validated locally.
+///
+/// Only what is known locally is checked: the URL always, the file type
+/// from the URL's extension or, for URLs without one, once a
+/// `Content-Type` is given with `with_content_type`, and the size only once
+/// it is given with `with_size`. Files of unknown type can't be uploaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MediaFile {
+    /// publicly reachable http(s) URL of the file
+    pub url: String,
+
+    /// file type, from the URL's extension or the `Content-Type`, `None`
+    /// until known
+    pub media_type: Option<MediaType>,
+
+    /// file size in bytes, if known
+    pub size: Option<u64>,
+}
+
+impl MediaFile {
+    /// Validates a media URL, which must be http(s) and, if its path has an
+    /// extension, name an mp3 or wav file.
+    ///
+    /// URLs without an extension, such as signed CDN links, are accepted
+    /// with an unknown type, to be given with `with_content_type`.
+    pub fn new(url: &str) -> Result<Self> {
+        let parsed = Url::parse(url).map_err(|_| invalid(url, "not a valid URL"))?;
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(invalid(url, "must be an http or https URL"));
+        }
+        let media_type = MediaType::from_path(parsed.path());
+        if media_type.is_none() && extension(parsed.path()).is_some() {
+            return Err(invalid(url, "must be an mp3 or wav file"));
+        }
+
+        Ok(Self {
+            url: url.into(),
+            media_type,
+            size: None,
+        })
+    }
+
+    /// Records the file type from its `Content-Type`, e.g. from a `HEAD`
+    /// request, failing unless it is mp3 or wav audio.
+    pub fn with_content_type(mut self, content_type: &str) -> Result<Self> {
+        match MediaType::from_content_type(content_type) {
+            Some(media_type) => {
+                self.media_type = Some(media_type);
+                Ok(self)
+            }
+            None => Err(invalid(
+                &self.url,
+                &format!("content type {} is not mp3 or wav audio", content_type),
+            )),
+        }
+    }
+
+    /// Records the file size, failing if it is empty or over `MAX_MEDIA_SIZE`.
+    pub fn with_size(mut self, size: u64) -> Result<Self> {
+        if size == 0 {
+            return Err(invalid(&self.url, "file is empty"));
+        }
+        if size > MAX_MEDIA_SIZE {
+            return Err(invalid(
+                &self.url,
+                &format!("file is {} bytes, the limit is {}", size, MAX_MEDIA_SIZE),
+            ));
+        }
+        self.size = Some(size);
+        Ok(self)
+    }
+}
+
+fn invalid(url: &str, reason: &str) -> super::Error {
+    ErrorKind::InvalidMedia(url.into(), reason.into()).into()
+}
+
+/// Media URLs uploaded for the account, kept locally so files are not
+/// uploaded twice.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MediaRegistry {
+    urls: BTreeSet<String>,
+}
+
+impl MediaRegistry {
+    /// creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a registry saved with `save`; a missing file gives an empty
+    /// registry.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if !path.as_ref().exists() {
+            return Ok(Self::new());
+        }
+        Ok(json::from_reader(File::open(path)?)?)
+    }
+
+    /// saves the registry as JSON
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(json::to_writer_pretty(File::create(path)?, self)?)
+    }
+
+    /// true if `url` has been uploaded
+    pub fn contains(&self, url: &str) -> bool {
+        self.urls.contains(url)
+    }
+
+    /// records `url` as uploaded, returning false if it already was
+    pub fn insert(&mut self, url: &str) -> bool {
+        self.urls.insert(url.into())
+    }
+
+    /// forgets `url`, e.g. after the file was removed from the account
+    pub fn remove(&mut self, url: &str) -> bool {
+        self.urls.remove(url)
+    }
+
+    /// uploaded URLs, in order
+    pub fn urls(&self) -> Vec<&str> {
+        self.urls.iter().map(|u| u.as_str()).collect()
+    }
+
+    /// Uploads `file` unless it is already registered, returning whether
+    /// an upload was made.
+    pub fn upload(&mut self, gateway: &AfricasTalkingGateway, file: &MediaFile) -> Result<bool> {
+        if self.contains(&file.url) {
+            return Ok(false);
+        }
+        gateway.upload_media(file)?;
+        self.insert(&file.url);
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    #[test]
+    fn guesses_type_from_extension() {
+        let file = MediaFile::new("https://example.com/audio/Welcome.MP3?v=2").unwrap();
+        assert_eq!(file.media_type, Some(MediaType::Mp3));
+        let wav = MediaFile::new("http://example.com/hold.wav").unwrap();
+        assert_eq!(wav.media_type, Some(MediaType::Wav));
+    }
+
+    #[test]
+    fn accepts_urls_without_extension() {
+        let file = MediaFile::new("https://cdn.example.com/v1.2/a1b2c3?sig=x.y").unwrap();
+        assert_eq!(file.media_type, None);
+    }
+
+    #[test]
+    fn rejects_other_extensions() {
+        match MediaFile::new("https://example.com/welcome.ogg") {
+            Err(Error(ErrorKind::InvalidMedia(_, reason), _)) => {
+                assert_eq!(reason, "must be an mp3 or wav file")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(MediaFile::new("https://example.com/notes.txt").is_err());
+    }
+
+    #[test]
+    fn rejects_non_http_urls() {
+        assert!(MediaFile::new("ftp://example.com/welcome.mp3").is_err());
+        assert!(MediaFile::new("not a url").is_err());
+    }
+
+    #[test]
+    fn checks_content_type() {
+        let file = MediaFile::new("https://cdn.example.com/a1b2c3").unwrap();
+        let wav = file.clone().with_content_type("audio/x-wav").unwrap();
+        assert_eq!(wav.media_type, Some(MediaType::Wav));
+        let mp3 = file.clone().with_content_type("Audio/MPEG; q=1").unwrap();
+        assert_eq!(mp3.media_type, Some(MediaType::Mp3));
+        assert!(file.with_content_type("text/html").is_err());
+    }
+
+    #[test]
+    fn checks_size_when_given() {
+        let file = MediaFile::new("http://example.com/hold.wav").unwrap();
+        assert_eq!(file.size, None);
+        assert_eq!(file.clone().with_size(1024).unwrap().size, Some(1024));
+        assert!(file.clone().with_size(0).is_err());
+        assert!(file.with_size(MAX_MEDIA_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn upload_requires_known_type() {
+        let (url, requests) = ::tests::serve(&[(200, r#"{"errorMessage":"None"}"#)]);
+        let gway = ::tests::gateway(&url);
+        let file = MediaFile::new("https://cdn.example.com/a1b2c3").unwrap();
+        let mut registry = MediaRegistry::new();
+        assert!(registry.upload(&gway, &file).is_err());
+        assert!(gway.upload_media_file(&file.url).is_err());
+
+        let file = file.with_content_type("audio/mpeg").unwrap();
+        assert!(registry.upload(&gway, &file).unwrap());
+        assert!(requests.recv().unwrap().contains("a1b2c3"));
+    }
+
+    #[test]
+    fn upload_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(401, "The supplied authentication is invalid")]);
+        let file = MediaFile::new("https://example.com/welcome.mp3").unwrap();
+        let mut registry = MediaRegistry::new();
+        assert!(registry.upload(&::tests::gateway(&url), &file).is_err());
+        assert!(!registry.contains(&file.url));
+    }
+}
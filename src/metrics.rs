@@ -0,0 +1,366 @@
+//! Request tracing and metrics.
+//!
+//! Every API request runs in an `africastalking.request` tracing span with
+//! the endpoint, method, attempt, status, duration and a masked username.
+//! A `MetricsHook` set with `with_metrics` additionally gets, per API:
+//!
+//! * `africastalking_requests_total`, counter labelled `endpoint`, `method`
+//!   and `status` (`error` if no response was received)
+//! * `africastalking_request_errors_total`, the same for failed requests and
+//!   4xx/5xx responses
+//! * `africastalking_request_duration_seconds`, histogram labelled
+//!   `endpoint` and `method`
+//!
+//! ```rust,ignore
+//! #[derive(Debug)]
+//! struct Prometheus { .. }
+//!
+//! impl MetricsHook for Prometheus {
+//!     fn counter(&self, name: &str, api: Api, labels: &[(&str, &str)], value: u64) {
+//!         // look up the counter vec for `name` and add `value`
+//!     }
+//!     fn histogram(&self, name: &str, api: Api, labels: &[(&str, &str)], value: f64) {
+//!         // look up the histogram vec for `name` and observe `value`
+//!     }
+//! }
+//!
+//! let gway = AfricasTalkingGateway::new(&username, &api_key, "sandbox")
+//!     .with_metrics(Arc::new(Prometheus::new()));
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use reqwest;
+use tracing::field;
+
+use super::{AfricasTalkingGateway, Result};
+
+/// Counter of requests made
+pub const REQUESTS_TOTAL: &str = "africastalking_requests_total";
+
+/// Counter of failed requests and error responses
+pub const REQUEST_ERRORS_TOTAL: &str = "africastalking_request_errors_total";
+
+/// Histogram of request durations in seconds
+pub const REQUEST_DURATION_SECONDS: &str = "africastalking_request_duration_seconds";
+
+/// The API a request was made to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Api {
+    /// SMS, premium subscriptions and blacklists
+    Sms,
+    /// voice calls
+    Voice,
+    /// airtime
+    Airtime,
+    /// payments, wallets and mobile data
+    Payments,
+    /// user data, tokens and insights
+    Other,
+}
+
+impl Api {
+    /// The API serving `url`.
+    pub fn from_url(url: &str) -> Self {
+        let base = url.split('?').next().unwrap_or_default();
+        if base.contains("://voice.") {
+            Api::Voice
+        } else if base.contains("/airtime") {
+            Api::Airtime
+        } else if base.contains("://payments.") || base.contains("://bundles.") {
+            Api::Payments
+        } else if base.contains("/messaging") || base.contains("/subscription") {
+            Api::Sms
+        } else {
+            Api::Other
+        }
+    }
+
+    /// lower case name, for metric labels
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Api::Sms => "sms",
+            Api::Voice => "voice",
+            Api::Airtime => "airtime",
+            Api::Payments => "payments",
+            Api::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for Api {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Receives request metrics.
+///
+/// Implement this to export to Prometheus or another metrics system;
+/// `MemoryMetrics` keeps totals in memory.
+pub trait MetricsHook: fmt::Debug + Send + Sync {
+    /// adds `value` to the counter `name`
+    fn counter(&self, name: &str, api: Api, labels: &[(&str, &str)], value: u64);
+
+    /// records `value` in the histogram `name`
+    fn histogram(&self, name: &str, api: Api, labels: &[(&str, &str)], value: f64);
+}
+
+/// Keeps metrics in memory, summed over labels
+#[derive(Debug, Default)]
+pub struct MemoryMetrics {
+    counters: Mutex<HashMap<(String, Api), u64>>,
+    histograms: Mutex<HashMap<(String, Api), Vec<f64>>>,
+}
+
+impl MemoryMetrics {
+    /// the total of counter `name` for `api`
+    pub fn counter_value(&self, name: &str, api: Api) -> u64 {
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.get(&(name.into(), api)).cloned().unwrap_or(0)
+    }
+
+    /// the values recorded in histogram `name` for `api`
+    pub fn histogram_values(&self, name: &str, api: Api) -> Vec<f64> {
+        let histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        histograms
+            .get(&(name.into(), api))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl MetricsHook for MemoryMetrics {
+    fn counter(&self, name: &str, api: Api, _labels: &[(&str, &str)], value: u64) {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        *counters.entry((name.into(), api)).or_insert(0) += value;
+    }
+
+    fn histogram(&self, name: &str, api: Api, _labels: &[(&str, &str)], value: f64) {
+        let mut histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        histograms
+            .entry((name.into(), api))
+            .or_default()
+            .push(value);
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Reports request counts, errors and durations to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsHook>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Runs `send` in a request span and reports its outcome.
+    pub(crate) fn instrumented<F>(
+        &self,
+        method: &str,
+        url: &str,
+        attempt: u32,
+        send: F,
+    ) -> Result<reqwest::Response>
+    where
+        F: FnOnce() -> Result<reqwest::Response>,
+    {
+        let api = Api::from_url(url);
+        let endpoint = endpoint(url);
+        let span = info_span!(
+            "africastalking.request",
+            api = api.as_str(),
+            endpoint,
+            method,
+            attempt,
+            username = mask_username(&self.username).as_str(),
+            status = field::Empty,
+            duration_ms = field::Empty,
+        );
+        let _entered = span.enter();
+
+        let started = Instant::now();
+        let result = send();
+        let elapsed = started.elapsed();
+        span.record("duration_ms", elapsed.as_millis() as u64);
+
+        let status = match result {
+            Ok(ref resp) => {
+                let status = resp.status().as_u16();
+                span.record("status", status);
+                debug!(status, "response received");
+                status.to_string()
+            }
+            Err(ref e) => {
+                warn!(error = %e, "request failed");
+                "error".to_string()
+            }
+        };
+
+        if let Some(ref metrics) = self.metrics {
+            let labels = [
+                ("endpoint", endpoint),
+                ("method", method),
+                ("status", status.as_str()),
+            ];
+            metrics.counter(REQUESTS_TOTAL, api, &labels, 1);
+            if !status.starts_with('2') && !status.starts_with('3') {
+                metrics.counter(REQUEST_ERRORS_TOTAL, api, &labels, 1);
+            }
+            metrics.histogram(
+                REQUEST_DURATION_SECONDS,
+                api,
+                &labels[..2],
+                elapsed.as_secs_f64(),
+            );
+        }
+
+        result
+    }
+}
+
+/// The path of `url`, without host or query.
+fn endpoint(url: &str) -> &str {
+    let base = url.split('?').next().unwrap_or_default();
+    let path = match base.find("://") {
+        Some(i) => &base[i + 3..],
+        None => base,
+    };
+    match path.find('/') {
+        Some(i) => &path[i..],
+        None => "/",
+    }
+}
+
+/// The first two characters of `username`, for logs.
+fn mask_username(username: &str) -> String {
+    let shown: String = username.chars().take(2).collect();
+    format!("{}***", shown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ErrorKind;
+
+    type Labels = Vec<(String, String)>;
+
+    /// Keeps every counter increment with its labels
+    #[derive(Debug, Default)]
+    struct Recorded(Mutex<Vec<(String, Api, Labels)>>);
+
+    impl Recorded {
+        fn counters(&self, name: &str) -> Vec<(Api, Labels)> {
+            let recorded = self.0.lock().unwrap();
+            recorded
+                .iter()
+                .filter(|r| r.0 == name)
+                .map(|r| (r.1, r.2.clone()))
+                .collect()
+        }
+    }
+
+    impl MetricsHook for Recorded {
+        fn counter(&self, name: &str, api: Api, labels: &[(&str, &str)], _value: u64) {
+            let labels = labels
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            self.0.lock().unwrap().push((name.into(), api, labels));
+        }
+
+        fn histogram(&self, _name: &str, _api: Api, _labels: &[(&str, &str)], _value: f64) {}
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn send_sms(url: &str, metrics: Arc<dyn MetricsHook>) -> Result<json::Value> {
+        ::tests::gateway(url)
+            .with_metrics(metrics)
+            .send_bulk_message(&["+254711000001"], "Hi", None)
+    }
+
+    #[test]
+    fn classifies_urls_by_api() {
+        assert_eq!(
+            Api::from_url("https://voice.sandbox.africastalking.com/call"),
+            Api::Voice
+        );
+        assert_eq!(
+            Api::from_url("https://api.africastalking.com/version1/airtime/send"),
+            Api::Airtime
+        );
+        assert_eq!(
+            Api::from_url("https://api.africastalking.com/version1/user?username=airtime"),
+            Api::Other
+        );
+    }
+
+    #[test]
+    fn strips_host_and_query_from_endpoint() {
+        assert_eq!(
+            endpoint("https://api.africastalking.com/version1/messaging?username=sandbox"),
+            "/version1/messaging"
+        );
+        assert_eq!(endpoint("https://api.africastalking.com"), "/");
+    }
+
+    #[test]
+    fn masks_username() {
+        assert_eq!(mask_username("sandbox"), "sa***");
+        assert_eq!(mask_username("a"), "a***");
+    }
+
+    #[test]
+    fn counts_failed_requests() {
+        let metrics = Arc::new(MemoryMetrics::default());
+        let gway =
+            AfricasTalkingGateway::new("sandbox", "key", "sandbox").with_metrics(metrics.clone());
+        let url = "https://api.sandbox.africastalking.com/version1/messaging";
+        let result = gway.instrumented("POST", url, 1, || {
+            Err(ErrorKind::GatewayError("timeout".into()).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(metrics.counter_value(REQUESTS_TOTAL, Api::Sms), 1);
+        assert_eq!(metrics.counter_value(REQUEST_ERRORS_TOTAL, Api::Sms), 1);
+        let durations = metrics.histogram_values(REQUEST_DURATION_SECONDS, Api::Sms);
+        assert_eq!(durations.len(), 1);
+        assert_eq!(metrics.counter_value(REQUESTS_TOTAL, Api::Voice), 0);
+    }
+
+    #[test]
+    fn labels_error_responses_with_status() {
+        let (url, _) = ::tests::serve(&[(500, "Internal Server Error")]);
+        let metrics = Arc::new(Recorded::default());
+        assert!(send_sms(&url, metrics.clone()).is_err());
+
+        let expected = labels(&[
+            ("endpoint", "/version1/messaging"),
+            ("method", "POST"),
+            ("status", "500"),
+        ]);
+        assert_eq!(
+            metrics.counters(REQUESTS_TOTAL),
+            vec![(Api::Sms, expected.clone())]
+        );
+        assert_eq!(
+            metrics.counters(REQUEST_ERRORS_TOTAL),
+            vec![(Api::Sms, expected)]
+        );
+    }
+
+    #[test]
+    fn does_not_count_successful_responses_as_errors() {
+        let body = r#"{"SMSMessageData":{"Message":"Sent to 1/1","Recipients":[]}}"#;
+        let (url, _) = ::tests::serve(&[(201, body)]);
+        let metrics = Arc::new(MemoryMetrics::default());
+        send_sms(&url, metrics.clone()).unwrap();
+        assert_eq!(metrics.counter_value(REQUESTS_TOTAL, Api::Sms), 1);
+        assert_eq!(metrics.counter_value(REQUEST_ERRORS_TOTAL, Api::Sms), 0);
+    }
+}
@@ -0,0 +1,297 @@
+//! Mobile Data API: data bundles and their notifications.
+use std::collections::HashMap;
+
+use json;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use airtime::AirtimeValidationResponse;
+use secret::redact;
+
+/// Data bundle unit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataUnit {
+    /// megabytes
+    MB,
+    /// gigabytes
+    GB,
+}
+
+/// Data bundle validity period
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataValidity {
+    /// valid for a day
+    Day,
+    /// valid for a week
+    Week,
+    /// valid for a month
+    Month,
+}
+
+/// Mobile Data Recipient Struct
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct MobileDataRecipient {
+    /// phone number to send the bundle to, in international format
+    pub phoneNumber: String,
+
+    /// bundle size, in `unit`s
+    pub quantity: i32,
+
+    /// bundle unit
+    pub unit: DataUnit,
+
+    /// bundle validity
+    pub validity: DataValidity,
+
+    /// metadata to associate with the transaction
+    pub metadata: HashMap<String, String>,
+
+    /// send the bundle as a promotion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isPromoMode: Option<bool>,
+}
+
+impl MobileDataRecipient {
+    /// creates a new mobile data recipient
+    pub fn new(phone_number: &str, quantity: i32, unit: DataUnit, validity: DataValidity) -> Self {
+        Self {
+            phoneNumber: phone_number.into(),
+            quantity,
+            unit,
+            validity,
+            metadata: HashMap::new(),
+            isPromoMode: None,
+        }
+    }
+}
+
+/// Mobile Data Entry Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct MobileDataEntry {
+    /// recipient's phone number
+    pub phoneNumber: String,
+
+    /// mobile network provider
+    #[serde(default)]
+    pub provider: String,
+
+    /// request status e.g. `Queued`, `InvalidRequest`
+    pub status: String,
+
+    /// unique transaction id, present if the request was accepted
+    #[serde(default)]
+    pub transactionId: Option<String>,
+
+    /// bundle value e.g. `"KES 100.0000"`
+    #[serde(default)]
+    pub value: Option<String>,
+
+    /// reason the request was rejected
+    #[serde(default)]
+    pub errorMessage: Option<String>,
+}
+
+/// Mobile Data Validation Request Struct
+///
+/// Sent as JSON to the application's validation callback URL before the
+/// bundle is dispatched; answer with a `MobileDataValidationResponse`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct MobileDataValidationRequest {
+    /// transaction id
+    pub transactionId: String,
+
+    /// recipient's phone number
+    pub phoneNumber: String,
+
+    /// IP address of the client that made the request
+    #[serde(default)]
+    pub sourceIpAddress: String,
+
+    /// 3-digit ISO currency code
+    #[serde(default)]
+    pub currencyCode: String,
+
+    /// bundle value
+    #[serde(default)]
+    pub amount: f64,
+}
+
+impl MobileDataValidationRequest {
+    /// Parses the JSON body of a validation callback.
+    pub fn from_json(body: &str) -> Result<Self> {
+        Ok(json::from_str(body)?)
+    }
+}
+
+/// Validation callback response, identical to the airtime one.
+pub type MobileDataValidationResponse = AirtimeValidationResponse;
+
+/// Mobile Data Status Notification Struct
+///
+/// Posted as JSON to the application's status callback URL once the
+/// provider has processed the request.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct MobileDataStatusNotification {
+    /// transaction id returned when the bundle was sent
+    pub transactionId: String,
+
+    /// recipient's phone number
+    #[serde(default)]
+    pub destination: String,
+
+    /// mobile network provider
+    #[serde(default)]
+    pub provider: String,
+
+    /// transaction id assigned by the provider
+    #[serde(default)]
+    pub providerRefId: Option<String>,
+
+    /// final status, either `Success` or `Failed`
+    pub status: String,
+
+    /// description of the final status
+    #[serde(default)]
+    pub description: String,
+
+    /// bundle value e.g. `"KES 100.0000"`
+    #[serde(default)]
+    pub value: String,
+
+    /// metadata sent with the request
+    #[serde(default)]
+    pub requestMetadata: HashMap<String, String>,
+
+    /// time the transaction was completed
+    #[serde(default)]
+    pub transactionDate: Option<String>,
+}
+
+impl MobileDataStatusNotification {
+    /// Parses the JSON body of a status callback.
+    pub fn from_json(body: &str) -> Result<Self> {
+        Ok(json::from_str(body)?)
+    }
+
+    /// true if the bundle was delivered
+    pub fn is_success(&self) -> bool {
+        self.status == "Success"
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Sends mobile data bundles.
+    /// [read more..](http://docs.africastalking.com/mobiledata/sending)
+    pub fn send_mobile_data(
+        &self,
+        product_name: &str,
+        recipients: &[MobileDataRecipient],
+    ) -> Result<Vec<MobileDataEntry>> {
+        let params = json!({
+            "username": self.username,
+            "productName": product_name,
+            "recipients": recipients
+        });
+        let mut resp = self.send_json_request(&self.mobile_data_url, params)?;
+        if resp.status().as_u16() == 201 {
+            let jsn: json::Value = resp.json()?;
+            let entries: Vec<MobileDataEntry> = json::from_value(jsn["entries"].clone())?;
+            if !entries.is_empty() {
+                Ok(entries)
+            } else {
+                // raise error
+                let message = jsn["errorMessage"].as_str().unwrap_or_default();
+                Err(ErrorKind::GatewayError(message.into()).into())
+            }
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_recipient() {
+        let recipient =
+            MobileDataRecipient::new("+254711XXXYYY", 50, DataUnit::MB, DataValidity::Day);
+        assert_eq!(
+            json::to_value(&recipient).unwrap(),
+            json!({
+                "phoneNumber": "+254711XXXYYY",
+                "quantity": 50,
+                "unit": "MB",
+                "validity": "Day",
+                "metadata": {}
+            })
+        );
+    }
+
+    fn recipients() -> Vec<MobileDataRecipient> {
+        vec![MobileDataRecipient::new(
+            "+254711XXXYYY",
+            50,
+            DataUnit::MB,
+            DataValidity::Day,
+        )]
+    }
+
+    #[test]
+    fn sends_bundles() {
+        let (url, requests) = ::tests::serve(&[(
+            201,
+            r#"{"entries":[{"phoneNumber":"+254711XXXYYY","provider":"Safaricom",
+                "status":"Queued","transactionId":"ATPid_1","value":"KES 100.0000"}]}"#,
+        )]);
+        let entries = ::tests::gateway(&url)
+            .send_mobile_data("Mobile Data", &recipients())
+            .unwrap();
+        assert_eq!(entries[0].transactionId.as_deref(), Some("ATPid_1"));
+        let request = requests.recv().unwrap();
+        assert!(request.contains(r#""productName":"Mobile Data""#));
+    }
+
+    #[test]
+    fn send_fails_when_no_entries_returned() {
+        let (url, _) = ::tests::serve(&[(
+            201,
+            r#"{"entries":[],"errorMessage":"Invalid product name"}"#,
+        )]);
+        let err = ::tests::gateway(&url)
+            .send_mobile_data("Unknown", &recipients())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid product name");
+    }
+
+    #[test]
+    fn send_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(401, "The supplied authentication is invalid")]);
+        let err = ::tests::gateway(&url)
+            .send_mobile_data("Mobile Data", &recipients())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "The supplied authentication is invalid");
+    }
+
+    #[test]
+    fn parses_status_notification() {
+        let body = r#"{
+            "transactionId": "ATPid_SampleTxnId123",
+            "category": "MobileData",
+            "provider": "Safaricom",
+            "destination": "+254711XXXYYY",
+            "value": "KES 100.0000",
+            "status": "Success",
+            "description": "Delivered",
+            "requestMetadata": {"campaign": "promo"}
+        }"#;
+        let notification = MobileDataStatusNotification::from_json(body).unwrap();
+        assert!(notification.is_success());
+        assert_eq!(notification.requestMetadata["campaign"], "promo");
+    }
+}
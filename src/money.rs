@@ -0,0 +1,170 @@
+//! Money values as returned by the API e.g. `"KES 1,234.5000"`.
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use super::{Error, ErrorKind, Result};
+
+/// Decimal places kept, the precision of the API's amounts
+pub const DECIMALS: usize = 4;
+
+/// `units` in one whole currency unit
+const SCALE: i64 = 10_000;
+
+/// An amount of money in a given currency.
+///
+/// The amount is kept exactly, as a whole number of ten-thousandths of the
+/// currency unit. Parses the API's `"<currency> <amount>"` strings,
+/// tolerating thousands separators, and (de)serializes to and from the same
+/// format.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Money {
+    /// 3-letter ISO currency code
+    pub currency: String,
+
+    /// amount in ten-thousandths of the currency unit, e.g. `12_345_000`
+    /// for 1,234.50
+    pub units: i64,
+}
+
+impl Money {
+    /// `units` ten-thousandths of `currency`
+    pub fn from_units(currency: &str, units: i64) -> Self {
+        Self {
+            currency: currency.into(),
+            units,
+        }
+    }
+
+    /// a whole number of `currency`, e.g. `Money::whole("KES", 500)`
+    pub fn whole(currency: &str, amount: i64) -> Self {
+        Self::from_units(currency, amount * SCALE)
+    }
+
+    /// Parses a decimal amount of `currency` such as `"1,234.50"`. More
+    /// than 4 decimal places are refused rather than rounded.
+    pub fn parse_amount(currency: &str, amount: &str) -> Result<Self> {
+        match parse_units(amount) {
+            Some(units) if !currency.trim().is_empty() => Ok(Self::from_units(currency, units)),
+            // raise error
+            _ => Err(ErrorKind::GatewayError(format!(
+                "invalid money value: \"{} {}\"",
+                currency, amount
+            ))
+            .into()),
+        }
+    }
+
+    /// The amount without currency or trailing zeros, e.g. `"1234.5"`.
+    pub fn amount(&self) -> String {
+        let fixed = format_units(self.units);
+        fixed
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    }
+}
+
+/// Ten-thousandths in a decimal string, `None` if it isn't one.
+fn parse_units(amount: &str) -> Option<i64> {
+    let amount = amount.trim().replace(',', "");
+    let (negative, amount) = match amount.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, amount.as_str()),
+    };
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if whole.is_empty() || !digits(whole) || !digits(fraction) || fraction.len() > DECIMALS {
+        return None;
+    }
+    let fraction = format!("{:0<width$}", fraction, width = DECIMALS);
+    let units = whole
+        .parse::<i64>()
+        .ok()?
+        .checked_mul(SCALE)?
+        .checked_add(fraction.parse::<i64>().ok()?)?;
+    Some(if negative { -units } else { units })
+}
+
+/// `units` as a decimal string with 4 places.
+fn format_units(units: i64) -> String {
+    let sign = if units < 0 { "-" } else { "" };
+    let units = units.unsigned_abs();
+    let scale = SCALE as u64;
+    format!(
+        "{}{}.{:0width$}",
+        sign,
+        units / scale,
+        units % scale,
+        width = DECIMALS
+    )
+}
+
+impl FromStr for Money {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().splitn(2, ' ');
+        let currency = parts.next().unwrap_or_default();
+        match parts.next() {
+            Some(amount) => Self::parse_amount(currency, amount),
+            // raise error
+            None => Err(ErrorKind::GatewayError(format!("invalid money value: {:?}", s)).into()),
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.currency, format_units(self.units))
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_api_values() {
+        let money: Money = "KES 1,234.50".parse().unwrap();
+        assert_eq!(money, Money::from_units("KES", 12_345_000));
+        assert_eq!(money.to_string(), "KES 1234.5000");
+        assert_eq!(money.amount(), "1234.5");
+        assert_eq!(Money::whole("KES", 100).amount(), "100");
+    }
+
+    #[test]
+    fn keeps_large_amounts_exact() {
+        let money: Money = "KES 98,765,432.1001".parse().unwrap();
+        assert_eq!(money.units, 987_654_321_001);
+        assert_eq!(money.to_string(), "KES 98765432.1001");
+        assert_eq!(
+            "USD -0.0075".parse::<Money>().unwrap().to_string(),
+            "USD -0.0075"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!("1234.50".parse::<Money>().is_err());
+        assert!("KES lots".parse::<Money>().is_err());
+        assert!("KES 1.23456".parse::<Money>().is_err());
+        assert!("KES 1e3".parse::<Money>().is_err());
+        assert!(Money::parse_amount("", "10").is_err());
+    }
+}
@@ -0,0 +1,410 @@
+//! SMS opt-outs: numbers that asked a sender not to message them again.
+//!
+//! Once a store is set with `with_opt_out_store`, `send_message` and
+//! `send_bulk_message` drop numbers opted out of the message's sender before
+//! calling the API and report them in the response as `UserInBlacklist`
+//! recipients, the same way the API reports numbers on its own blacklist.
+//!
+//! Opt-outs are kept per sender id or shortcode, with phone numbers in E.164
+//! format so `0711 000 001` and `+254711000001` are the same subscriber.
+//!
+//! Africa's Talking has no documented endpoint for fetching an account's
+//! blacklist, so it can't be loaded up front. Instead, numbers the API
+//! reports as `UserInBlacklist` in a send response are added to the store,
+//! so later sends drop them before calling the API.
+//!
+//! ```rust,ignore
+//! let store = Arc::new(MemoryOptOutStore::new().with_country(Country::Kenya));
+//! let gway = AfricasTalkingGateway::new(&username, &apikey, "sandbox")
+//!     .with_opt_out_store(store.clone());
+//!
+//! // in the opt-out callback handler:
+//! store.handle_notification(&OptOutNotification::from_form(&body)?)?;
+//! // in the incoming message callback handler:
+//! store.handle_inbound(&to, &from, &text)?;
+//! ```
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use json;
+use serde_urlencoded;
+
+use super::{AfricasTalkingGateway, Result};
+use delivery::SMSRecipient;
+use sender::Country;
+
+/// Default inbound message keywords that opt the sender out.
+pub const STOP_KEYWORDS: &[&str] = &["STOP", "STOPALL", "UNSUBSCRIBE"];
+
+/// Sender key for messages sent without a sender id, i.e. from the account's
+/// default sender, and for opt-out notifications that name no sender.
+pub const DEFAULT_SENDER: &str = "";
+
+/// Status the API reports for blacklisted recipients.
+const BLACKLISTED_STATUS: &str = "UserInBlacklist";
+const BLACKLISTED_STATUS_CODE: i32 = 406;
+
+/// true if `text` is an opt-out request, i.e. starts with one of `keywords`
+pub fn is_stop_message<S: AsRef<str>>(text: &str, keywords: &[S]) -> bool {
+    match text.split_whitespace().next() {
+        Some(word) => keywords
+            .iter()
+            .any(|keyword| keyword.as_ref().eq_ignore_ascii_case(word)),
+        None => false,
+    }
+}
+
+/// Normalizes a phone number to E.164, e.g. `0711 000 001` to
+/// `+254711000001` in Kenya.
+///
+/// Numbers with a `+`, a `00` prefix or a supported country's dialing code
+/// are international already. Other numbers are taken as local to `country`;
+/// with no country they are only stripped of formatting.
+pub fn normalize(phone_number: &str, country: Option<Country>) -> String {
+    let trimmed = phone_number.trim();
+    let digits: String = trimmed.chars().filter(char::is_ascii_digit).collect();
+    if trimmed.starts_with('+') {
+        return format!("+{}", digits);
+    }
+    if let Some(rest) = digits.strip_prefix("00") {
+        return format!("+{}", rest);
+    }
+    let international = Country::ALL.iter().any(|c| {
+        digits.starts_with(c.dialing_code()) && digits.len() >= c.dialing_code().len() + 9
+    });
+    match country {
+        _ if international => format!("+{}", digits),
+        Some(country) => {
+            let national = digits.trim_start_matches('0');
+            format!("+{}{}", country.dialing_code(), national)
+        }
+        None => digits,
+    }
+}
+
+/// Opt-Out Notification Struct
+///
+/// Posted as form data to the application's opt-out callback URL when a
+/// subscriber opts out of a sender id.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct OptOutNotification {
+    /// sender id or shortcode the subscriber opted out of
+    #[serde(default)]
+    pub senderId: String,
+
+    /// subscriber's phone number
+    pub phoneNumber: String,
+}
+
+impl OptOutNotification {
+    /// Parses the form encoded body of an opt-out callback.
+    pub fn from_form(body: &str) -> Result<Self> {
+        Ok(serde_urlencoded::from_str(body)?)
+    }
+}
+
+/// Storage for opted-out phone numbers, per sender.
+///
+/// Implement this to keep opt-outs in a database; `MemoryOptOutStore` keeps
+/// them in memory. The provided methods and the gateway pass phone numbers
+/// in E.164 format, as returned by `normalize`, and senders as the sender id
+/// or shortcode (`DEFAULT_SENDER` for the account's default sender).
+pub trait OptOutStore: fmt::Debug + Send + Sync {
+    /// true if `phone_number` has opted out of `sender_id`
+    fn is_opted_out(&self, sender_id: &str, phone_number: &str) -> Result<bool>;
+
+    /// records that `phone_number` opted out of `sender_id`
+    fn opt_out(&self, sender_id: &str, phone_number: &str) -> Result<()>;
+
+    /// removes the opt-out of `phone_number` from `sender_id`
+    fn opt_in(&self, sender_id: &str, phone_number: &str) -> Result<()>;
+
+    /// inbound message keywords that opt the sender out
+    fn stop_keywords(&self) -> Vec<String> {
+        STOP_KEYWORDS.iter().map(|k| k.to_string()).collect()
+    }
+
+    /// country of phone numbers given in local format, if any
+    fn country(&self) -> Option<Country> {
+        None
+    }
+
+    /// Records an opt-out notification.
+    fn handle_notification(&self, notification: &OptOutNotification) -> Result<()> {
+        let phone_number = normalize(&notification.phoneNumber, self.country());
+        self.opt_out(&notification.senderId, &phone_number)
+    }
+
+    /// Opts out of `sender_id` every recipient a `send_message` response
+    /// reports as blacklisted, returning how many there were.
+    fn import_blacklisted(&self, sender_id: &str, response: &json::Value) -> Result<usize> {
+        let recipients = SMSRecipient::from_response(response)?;
+        let mut imported = 0;
+        for recipient in recipients.iter().filter(|r| r.status == BLACKLISTED_STATUS) {
+            self.opt_out(sender_id, &normalize(&recipient.number, self.country()))?;
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
+    /// Opts `from` out of the shortcode `to` if an inbound message is a stop
+    /// request, returning whether it was.
+    fn handle_inbound(&self, to: &str, from: &str, text: &str) -> Result<bool> {
+        if is_stop_message(text, &self.stop_keywords()) {
+            self.opt_out(to, &normalize(from, self.country()))?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+/// Keeps opt-outs in memory
+#[derive(Debug)]
+pub struct MemoryOptOutStore {
+    numbers: Mutex<HashSet<(String, String)>>,
+    keywords: Vec<String>,
+    country: Option<Country>,
+}
+
+impl Default for MemoryOptOutStore {
+    fn default() -> Self {
+        Self {
+            numbers: Mutex::new(HashSet::new()),
+            keywords: STOP_KEYWORDS.iter().map(|k| k.to_string()).collect(),
+            country: None,
+        }
+    }
+}
+
+impl MemoryOptOutStore {
+    /// creates an empty store using `STOP_KEYWORDS`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// opts out on inbound messages starting with one of `keywords` instead
+    pub fn with_keywords(mut self, keywords: &[&str]) -> Self {
+        self.keywords = keywords.iter().map(|k| k.to_string()).collect();
+        self
+    }
+
+    /// treats phone numbers in local format as numbers in `country`
+    pub fn with_country(mut self, country: Country) -> Self {
+        self.country = Some(country);
+        self
+    }
+
+    fn numbers(&self) -> MutexGuard<'_, HashSet<(String, String)>> {
+        self.numbers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl OptOutStore for MemoryOptOutStore {
+    fn is_opted_out(&self, sender_id: &str, phone_number: &str) -> Result<bool> {
+        let key = (sender_id.to_string(), phone_number.to_string());
+        Ok(self.numbers().contains(&key))
+    }
+
+    fn opt_out(&self, sender_id: &str, phone_number: &str) -> Result<()> {
+        let key = (sender_id.to_string(), phone_number.to_string());
+        self.numbers().insert(key);
+        Ok(())
+    }
+
+    fn opt_in(&self, sender_id: &str, phone_number: &str) -> Result<()> {
+        let key = (sender_id.to_string(), phone_number.to_string());
+        self.numbers().remove(&key);
+        Ok(())
+    }
+
+    fn stop_keywords(&self) -> Vec<String> {
+        self.keywords.clone()
+    }
+
+    fn country(&self) -> Option<Country> {
+        self.country
+    }
+}
+
+/// Splits comma separated recipients into those allowed and those opted out
+/// of `sender_id`.
+pub(crate) fn partition(
+    store: &dyn OptOutStore,
+    sender_id: &str,
+    to: &str,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let country = store.country();
+    let mut allowed = Vec::new();
+    let mut dropped = Vec::new();
+    for number in to.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        if store.is_opted_out(sender_id, &normalize(number, country))? {
+            dropped.push(number.to_string());
+        } else {
+            allowed.push(number.to_string());
+        }
+    }
+
+    Ok((allowed, dropped))
+}
+
+/// Adds opted-out numbers to a send response as blacklisted recipients; with
+/// no response, builds one as if nothing was sent.
+pub(crate) fn report_dropped(response: Option<json::Value>, dropped: &[String]) -> json::Value {
+    let entries: Vec<json::Value> = dropped
+        .iter()
+        .map(|number| {
+            json!({
+                "number": number,
+                "status": BLACKLISTED_STATUS,
+                "statusCode": BLACKLISTED_STATUS_CODE,
+                "messageId": "None",
+                "cost": "0"
+            })
+        })
+        .collect();
+    let mut response = response.unwrap_or_else(|| {
+        json!({
+            "SMSMessageData": {
+                "Message": format!("Sent to 0/{} Total Cost: 0", dropped.len()),
+                "Recipients": []
+            }
+        })
+    });
+    if let Some(recipients) = response["SMSMessageData"]["Recipients"].as_array_mut() {
+        recipients.extend(entries);
+    }
+    response
+}
+
+impl AfricasTalkingGateway {
+    /// Drops opted-out numbers from messages sent through this gateway.
+    pub fn with_opt_out_store(mut self, store: Arc<dyn OptOutStore>) -> Self {
+        self.opt_outs = Some(store);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Error, ErrorKind};
+
+    #[test]
+    fn matches_stop_keywords() {
+        assert!(is_stop_message(" stop please", STOP_KEYWORDS));
+        assert!(is_stop_message("Unsubscribe", STOP_KEYWORDS));
+        assert!(!is_stop_message("stopwatch", STOP_KEYWORDS));
+        assert!(!is_stop_message("cancel my order", STOP_KEYWORDS));
+        assert!(!is_stop_message("end", STOP_KEYWORDS));
+        assert!(!is_stop_message("", STOP_KEYWORDS));
+    }
+
+    #[test]
+    fn uses_configured_keywords() {
+        let store = MemoryOptOutStore::new().with_keywords(&["ACHA"]);
+        let number = "+254711000001";
+        assert!(!store.handle_inbound("20880", number, "stop").unwrap());
+        assert!(store.handle_inbound("20880", number, "acha").unwrap());
+        assert!(store.is_opted_out("20880", number).unwrap());
+    }
+
+    #[test]
+    fn normalizes_phone_numbers() {
+        let kenya = Some(Country::Kenya);
+        assert_eq!(normalize("0711 000 001", kenya), "+254711000001");
+        assert_eq!(normalize("711000001", kenya), "+254711000001");
+        assert_eq!(normalize("254711000001", kenya), "+254711000001");
+        assert_eq!(normalize("+254 (711) 000-001", None), "+254711000001");
+        assert_eq!(normalize("00256711000001", kenya), "+256711000001");
+        assert_eq!(normalize("254711000001", None), "+254711000001");
+        assert_eq!(normalize("0711000001", None), "0711000001");
+    }
+
+    #[test]
+    fn keys_opt_outs_by_sender() {
+        let store = MemoryOptOutStore::new().with_country(Country::Kenya);
+        store.handle_inbound("20880", "0711000001", "STOP").unwrap();
+        let notification =
+            OptOutNotification::from_form("senderId=ACME&phoneNumber=%2B254711000002").unwrap();
+        store.handle_notification(&notification).unwrap();
+
+        assert!(store.is_opted_out("20880", "+254711000001").unwrap());
+        assert!(!store.is_opted_out("ACME", "+254711000001").unwrap());
+        assert!(store.is_opted_out("ACME", "+254711000002").unwrap());
+        store.opt_in("ACME", "+254711000002").unwrap();
+        assert!(!store.is_opted_out("ACME", "+254711000002").unwrap());
+    }
+
+    #[test]
+    fn partitions_on_normalized_numbers() {
+        let store = MemoryOptOutStore::new().with_country(Country::Kenya);
+        store.opt_out("ACME", "+254711000001").unwrap();
+
+        let (allowed, dropped) = partition(&store, "ACME", "0711 000 001, +254711000002,").unwrap();
+        assert_eq!(allowed, vec!["+254711000002".to_string()]);
+        assert_eq!(dropped, vec!["0711 000 001".to_string()]);
+
+        let (allowed, dropped) = partition(&store, DEFAULT_SENDER, "0711000001").unwrap();
+        assert_eq!((allowed.len(), dropped.len()), (1, 0));
+    }
+
+    #[test]
+    fn reports_dropped_numbers_as_blacklisted() {
+        let dropped = vec!["+254711000001".to_string(), "+254711000003".to_string()];
+        let response = report_dropped(None, &dropped);
+        let recipients = SMSRecipient::from_response(&response).unwrap();
+        assert_eq!(recipients.len(), 2);
+        assert!(recipients
+            .iter()
+            .all(|r| !r.is_sent() && r.status == "UserInBlacklist"));
+    }
+
+    #[test]
+    fn send_skips_request_when_every_number_opted_out() {
+        let store = Arc::new(MemoryOptOutStore::new());
+        store.opt_out("ACME", "+254711000001").unwrap();
+        let (url, requests) = ::tests::serve(&[]);
+        let gway = ::tests::gateway(&url).with_opt_out_store(store);
+
+        let response = gway
+            .send_bulk_message(&["+254711000001"], "Hello", Some("ACME"))
+            .unwrap();
+        assert_eq!(SMSRecipient::from_response(&response).unwrap().len(), 1);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn send_records_numbers_reported_as_blacklisted() {
+        let response = r#"{"SMSMessageData":{"Message":"Sent to 1/2","Recipients":[
+            {"number":"+254711000001","status":"UserInBlacklist","statusCode":406,
+             "messageId":"None","cost":"0"},
+            {"number":"+254711000002","status":"Success","statusCode":101,
+             "messageId":"ATXid_2","cost":"KES 0.8000"}]}}"#;
+        let store = Arc::new(MemoryOptOutStore::new());
+        let (url, requests) = ::tests::serve(&[(201, response)]);
+        let gway = ::tests::gateway(&url).with_opt_out_store(store.clone());
+        let to = ["+254711000001", "+254711000002"];
+
+        gway.send_bulk_message(&to, "Hello", Some("ACME")).unwrap();
+        assert!(store.is_opted_out("ACME", "+254711000001").unwrap());
+        assert!(!store.is_opted_out("ACME", "+254711000002").unwrap());
+
+        let resent = gway.send_bulk_message(&to[..1], "Hello", Some("ACME"));
+        assert!(resent.is_ok());
+        assert_eq!(requests.iter().count(), 1);
+    }
+
+    #[test]
+    fn send_fails_without_recipients() {
+        let (url, requests) = ::tests::serve(&[]);
+        match ::tests::gateway(&url).send_bulk_message(&[" ", ""], "Hello", None) {
+            Err(Error(ErrorKind::NoRecipients, _)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(requests.try_recv().is_err());
+    }
+}
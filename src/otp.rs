@@ -0,0 +1,761 @@
+//! One-time passwords for phone number verification.
+//!
+//! Codes are sent by SMS, falling back to a voice call that reads the code
+//! out, and only an HMAC of each code, keyed by a server secret, is stored.
+//!
+//! ```rust,ignore
+//! let secret = SecretString::new(&env::var("OTP_SECRET")?);
+//! let store = Box::new(MemoryOtpStore::default());
+//! let otp = OtpService::new(store, OtpConfig::default(), secret)?;
+//!
+//! match otp.send(&gway, "+254711000001", Some("+254711000000"))? {
+//!     OtpDelivery::Sms => {}
+//!     // return `response` from the voice callback for this session
+//!     OtpDelivery::Voice { session_id, response } => calls.insert(session_id, response),
+//! }
+//!
+//! match otp.verify("+254711000001", &code)? {
+//!     Verification::Valid => println!("verified"),
+//!     other => println!("not verified: {:?}", other),
+//! }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::{self, Rng};
+use sha2::{Digest, Sha256};
+
+use super::{AfricasTalkingGateway, ErrorKind, Result, SMSMessage};
+use delivery::SMSRecipient;
+use secret::SecretString;
+use voice::VoiceResponse;
+
+/// Shortest server secret accepted, in bytes
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// SHA-256 block size, for HMAC
+const BLOCK_SIZE: usize = 64;
+
+/// OTP configuration
+#[derive(Debug, Clone)]
+pub struct OtpConfig {
+    /// number of characters in a code; at least 1
+    pub length: usize,
+
+    /// characters codes are made of; must not be empty
+    pub alphabet: String,
+
+    /// how long a code stays valid
+    pub expiry: Duration,
+
+    /// wrong guesses allowed before the code is invalidated; at least 1
+    pub max_attempts: u32,
+
+    /// minimum time between sends to the same number
+    pub resend_cooldown: Duration,
+
+    /// SMS text; `{code}` is replaced with the code
+    pub message: String,
+}
+
+impl OtpConfig {
+    /// Checks the configuration can produce and deliver codes.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, reason: &str| -> Result<()> {
+            // raise error
+            Err(ErrorKind::InvalidConfig(key.into(), reason.into()).into())
+        };
+        if self.length == 0 {
+            return invalid("length", "must be at least 1");
+        }
+        if self.alphabet.is_empty() {
+            return invalid("alphabet", "must not be empty");
+        }
+        if self.max_attempts == 0 {
+            return invalid("max_attempts", "must be at least 1");
+        }
+        if !self.message.contains("{code}") {
+            return invalid("message", "must contain {code}");
+        }
+        Ok(())
+    }
+}
+
+impl Default for OtpConfig {
+    fn default() -> Self {
+        Self {
+            length: 6,
+            alphabet: "0123456789".into(),
+            expiry: Duration::from_secs(5 * 60),
+            max_attempts: 5,
+            resend_cooldown: Duration::from_secs(60),
+            message: "Your verification code is {code}".into(),
+        }
+    }
+}
+
+/// A stored code
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OtpRecord {
+    /// hex HMAC-SHA-256 of the salt, phone number and code
+    pub hash: String,
+
+    /// random hex salt
+    pub salt: String,
+
+    /// when the code was sent, in seconds since the unix epoch
+    pub sent_at: u64,
+
+    /// when the code expires, in seconds since the unix epoch
+    pub expires_at: u64,
+
+    /// wrong guesses so far
+    pub attempts: u32,
+
+    /// the code is still being sent; it holds the resend cooldown but can't
+    /// be verified yet
+    #[serde(default)]
+    pub pending: bool,
+}
+
+/// Storage for codes, keyed by phone number.
+///
+/// Implement this to keep codes in a database or cache; `MemoryOtpStore`
+/// keeps them in memory.
+pub trait OtpStore: fmt::Debug + Send + Sync {
+    /// the code stored for `phone_number`, if any
+    fn get(&self, phone_number: &str) -> Result<Option<OtpRecord>>;
+
+    /// stores the code for `phone_number`, replacing any previous one
+    fn set(&self, phone_number: &str, record: OtpRecord) -> Result<()>;
+
+    /// removes the code for `phone_number`
+    fn remove(&self, phone_number: &str) -> Result<()>;
+
+    /// Atomically replaces the code for `phone_number` with `new`, removing
+    /// it if `new` is `None`, but only if the stored code is still
+    /// `current`, or there is none if `current` is `None`. Returns whether
+    /// it was replaced.
+    fn compare_and_set(
+        &self,
+        phone_number: &str,
+        current: Option<&OtpRecord>,
+        new: Option<OtpRecord>,
+    ) -> Result<bool>;
+}
+
+/// Keeps codes in memory
+#[derive(Debug, Default)]
+pub struct MemoryOtpStore {
+    records: Mutex<HashMap<String, OtpRecord>>,
+}
+
+impl OtpStore for MemoryOtpStore {
+    fn get(&self, phone_number: &str) -> Result<Option<OtpRecord>> {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(records.get(phone_number).cloned())
+    }
+
+    fn set(&self, phone_number: &str, record: OtpRecord) -> Result<()> {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.insert(phone_number.into(), record);
+        Ok(())
+    }
+
+    fn remove(&self, phone_number: &str) -> Result<()> {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.remove(phone_number);
+        Ok(())
+    }
+
+    fn compare_and_set(
+        &self,
+        phone_number: &str,
+        current: Option<&OtpRecord>,
+        new: Option<OtpRecord>,
+    ) -> Result<bool> {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        if records.get(phone_number) != current {
+            return Ok(false);
+        }
+        match new {
+            Some(record) => records.insert(phone_number.into(), record),
+            None => records.remove(phone_number),
+        };
+        Ok(true)
+    }
+}
+
+/// How a code was delivered
+#[derive(Debug, Clone, PartialEq)]
+pub enum OtpDelivery {
+    /// by SMS
+    Sms,
+    /// by voice call; the application's voice callback must answer the
+    /// call's session with `response`
+    Voice {
+        /// session id of the call, `None` if the API did not return one
+        session_id: Option<String>,
+        /// voice XML reading the code out
+        response: VoiceResponse,
+    },
+}
+
+/// Result of checking a code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// the code is correct; it cannot be used again
+    Valid,
+    /// the code is wrong
+    Invalid,
+    /// the code has expired
+    Expired,
+    /// too many wrong guesses; a new code must be sent
+    TooManyAttempts,
+    /// no code was sent to the number
+    NotFound,
+}
+
+/// Sends and verifies one-time passwords
+#[derive(Debug)]
+pub struct OtpService {
+    store: Box<dyn OtpStore>,
+    config: OtpConfig,
+    secret: SecretString,
+}
+
+impl OtpService {
+    /// Creates a service keeping codes in `store`, hashed with `secret`.
+    ///
+    /// Fails with `ErrorKind::InvalidConfig` if the configuration is invalid
+    /// or the secret is shorter than `MIN_SECRET_LEN` bytes.
+    pub fn new(store: Box<dyn OtpStore>, config: OtpConfig, secret: SecretString) -> Result<Self> {
+        config.validate()?;
+        if secret.expose().len() < MIN_SECRET_LEN {
+            // raise error
+            return Err(ErrorKind::InvalidConfig(
+                "secret".into(),
+                format!("must be at least {} bytes", MIN_SECRET_LEN),
+            )
+            .into());
+        }
+
+        Ok(Self {
+            store,
+            config,
+            secret,
+        })
+    }
+
+    /// Generates a random code from the configured alphabet.
+    pub fn generate(&self) -> String {
+        let alphabet: Vec<char> = self.config.alphabet.chars().collect();
+        let mut rng = rand::thread_rng();
+        (0..self.config.length)
+            .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+            .collect()
+    }
+
+    /// Sends a new code to `phone_number` by SMS, or by a voice call from
+    /// `voice_from` if the SMS cannot be sent.
+    ///
+    /// The resend cooldown is claimed before sending, so concurrent sends to
+    /// the same number can't both go out. The new code can only be verified
+    /// once it was delivered. If sending fails the previous code, if any, is
+    /// restored, but the cooldown still runs from this attempt.
+    ///
+    /// Fails with `ErrorKind::OtpCooldown` if a code was sent to the number
+    /// less than `resend_cooldown` ago.
+    pub fn send(
+        &self,
+        gateway: &AfricasTalkingGateway,
+        phone_number: &str,
+        voice_from: Option<&str>,
+    ) -> Result<OtpDelivery> {
+        let (code, pending, previous) = self.claim(phone_number, unix_now())?;
+        match self.deliver(gateway, phone_number, &code, voice_from) {
+            Ok(delivery) => {
+                self.delivered(phone_number, &pending)?;
+                Ok(delivery)
+            }
+            Err(e) => {
+                if let Err(rollback) = self.undelivered(phone_number, &pending, previous) {
+                    warn!(error = %rollback, "could not restore the previous code");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Checks a code sent to `phone_number`. A valid code is consumed.
+    pub fn verify(&self, phone_number: &str, code: &str) -> Result<Verification> {
+        self.verify_at(phone_number, code, unix_now())
+    }
+
+    /// Sends `code` by SMS, falling back to a voice call from `voice_from`.
+    fn deliver(
+        &self,
+        gateway: &AfricasTalkingGateway,
+        phone_number: &str,
+        code: &str,
+        voice_from: Option<&str>,
+    ) -> Result<OtpDelivery> {
+        let text = self.config.message.replace("{code}", code);
+        let msg = SMSMessage::new(
+            &gateway.username,
+            phone_number,
+            &text,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let sms = gateway
+            .send_message(msg)
+            .and_then(|response| SMSRecipient::from_response(&response));
+        let sent = match sms {
+            Ok(ref recipients) => recipients.iter().any(|r| r.is_sent()),
+            Err(_) => false,
+        };
+        match (sent, voice_from) {
+            (true, _) => Ok(OtpDelivery::Sms),
+            (false, Some(from)) => {
+                let entries = gateway.call(from, &[phone_number], None)?;
+                Ok(OtpDelivery::Voice {
+                    session_id: entries.into_iter().next().and_then(|e| e.sessionId),
+                    response: voice_response(code),
+                })
+            }
+            (false, None) => match sms {
+                Err(e) => Err(e),
+                // raise error
+                Ok(_) => Err(ErrorKind::GatewayError(format!(
+                    "verification code not sent to {}",
+                    phone_number
+                ))
+                .into()),
+            },
+        }
+    }
+
+    /// Generates a new code and stores it as pending with compare-and-set,
+    /// enforcing the resend cooldown. Returns the code, the pending record
+    /// and the record it replaced.
+    fn claim(
+        &self,
+        phone_number: &str,
+        now: u64,
+    ) -> Result<(String, OtpRecord, Option<OtpRecord>)> {
+        loop {
+            let previous = self.store.get(phone_number)?;
+            if let Some(ref record) = previous {
+                let ready_at = record.sent_at + self.config.resend_cooldown.as_secs();
+                if now < ready_at {
+                    // raise error
+                    return Err(ErrorKind::OtpCooldown(ready_at - now).into());
+                }
+            }
+            let code = self.generate();
+            let salt = to_hex(&rand::thread_rng().gen::<[u8; 16]>());
+            let pending = OtpRecord {
+                hash: self.hash_code(&salt, phone_number, &code),
+                salt,
+                sent_at: now,
+                expires_at: now + self.config.expiry.as_secs(),
+                attempts: 0,
+                pending: true,
+            };
+            if self
+                .store
+                .compare_and_set(phone_number, previous.as_ref(), Some(pending.clone()))?
+            {
+                return Ok((code, pending, previous));
+            }
+        }
+    }
+
+    /// Makes a pending code verifiable once it was delivered.
+    fn delivered(&self, phone_number: &str, pending: &OtpRecord) -> Result<()> {
+        let record = OtpRecord {
+            pending: false,
+            ..pending.clone()
+        };
+        self.store
+            .compare_and_set(phone_number, Some(pending), Some(record))?;
+        Ok(())
+    }
+
+    /// Restores the code a pending one replaced after sending it failed,
+    /// keeping the pending code's cooldown. With no previous code the
+    /// pending one stays, unverifiable, until the cooldown is over.
+    fn undelivered(
+        &self,
+        phone_number: &str,
+        pending: &OtpRecord,
+        previous: Option<OtpRecord>,
+    ) -> Result<()> {
+        if let Some(previous) = previous {
+            let restored = OtpRecord {
+                sent_at: pending.sent_at,
+                ..previous
+            };
+            self.store
+                .compare_and_set(phone_number, Some(pending), Some(restored))?;
+        }
+        Ok(())
+    }
+
+    /// Checks a code, counting wrong guesses with compare-and-set so
+    /// concurrent guesses cannot exceed `max_attempts`.
+    fn verify_at(&self, phone_number: &str, code: &str, now: u64) -> Result<Verification> {
+        loop {
+            let record = match self.store.get(phone_number)? {
+                Some(ref record) if record.pending => return Ok(Verification::NotFound),
+                Some(record) => record,
+                None => return Ok(Verification::NotFound),
+            };
+            if now >= record.expires_at {
+                self.store
+                    .compare_and_set(phone_number, Some(&record), None)?;
+                return Ok(Verification::Expired);
+            }
+            if record.attempts >= self.config.max_attempts {
+                return Ok(Verification::TooManyAttempts);
+            }
+            let hash = self.hash_code(&record.salt, phone_number, code.trim());
+            if constant_time_eq(hash.as_bytes(), record.hash.as_bytes()) {
+                if self
+                    .store
+                    .compare_and_set(phone_number, Some(&record), None)?
+                {
+                    return Ok(Verification::Valid);
+                }
+                continue;
+            }
+            let guessed = OtpRecord {
+                attempts: record.attempts + 1,
+                ..record.clone()
+            };
+            let exhausted = guessed.attempts >= self.config.max_attempts;
+            if self
+                .store
+                .compare_and_set(phone_number, Some(&record), Some(guessed))?
+            {
+                return Ok(if exhausted {
+                    Verification::TooManyAttempts
+                } else {
+                    Verification::Invalid
+                });
+            }
+        }
+    }
+
+    /// Hex HMAC-SHA-256 of the salt, phone number and code, keyed by the
+    /// server secret.
+    fn hash_code(&self, salt: &str, phone_number: &str, code: &str) -> String {
+        let message = format!("{}:{}:{}", salt, phone_number, code);
+        to_hex(&hmac_sha256(
+            self.secret.expose().as_bytes(),
+            message.as_bytes(),
+        ))
+    }
+}
+
+/// Voice XML reading `code` out twice, one character at a time.
+fn voice_response(code: &str) -> VoiceResponse {
+    let spelled: Vec<String> = code.chars().map(String::from).collect();
+    let text = format!("Your verification code is {}.", spelled.join(" "));
+    VoiceResponse::new().say(&text).say(&text)
+}
+
+/// HMAC-SHA-256 (RFC 2104) of `message` keyed by `key`.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| -> Vec<u8> { block.iter().map(|b| b ^ byte).collect() };
+    let mut inner = Sha256::new();
+    inner.update(pad(0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(pad(0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+    const PHONE: &str = "+254711000001";
+
+    fn service() -> OtpService {
+        let config = OtpConfig {
+            max_attempts: 2,
+            ..Default::default()
+        };
+        let store = Box::new(MemoryOtpStore::default());
+        OtpService::new(store, config, SecretString::new(SECRET)).unwrap()
+    }
+
+    /// Claims a code and marks it delivered.
+    fn issue(otp: &OtpService, now: u64) -> String {
+        let (code, pending, _) = otp.claim(PHONE, now).unwrap();
+        otp.delivered(PHONE, &pending).unwrap();
+        code
+    }
+
+    fn invalid_config(result: Result<OtpService>) -> String {
+        match result {
+            Err(Error(ErrorKind::InvalidConfig(key, _), _)) => key,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn generates_codes_from_alphabet() {
+        let code = service().generate();
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let new = |config: OtpConfig, secret: &str| {
+            let store = Box::new(MemoryOtpStore::default());
+            OtpService::new(store, config, SecretString::new(secret))
+        };
+        let config = |length, alphabet: &str| OtpConfig {
+            length,
+            alphabet: alphabet.into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            invalid_config(new(config(0, "0123456789"), SECRET)),
+            "length"
+        );
+        assert_eq!(invalid_config(new(config(6, ""), SECRET)), "alphabet");
+        assert_eq!(invalid_config(new(config(6, "0123"), "short")), "secret");
+        let no_code = OtpConfig {
+            message: "Your code".into(),
+            ..Default::default()
+        };
+        assert_eq!(invalid_config(new(no_code, SECRET)), "message");
+    }
+
+    #[test]
+    fn consumes_valid_codes() {
+        let otp = service();
+        let code = issue(&otp, 1000);
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1010).unwrap(),
+            Verification::Valid
+        );
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1011).unwrap(),
+            Verification::NotFound
+        );
+    }
+
+    #[test]
+    fn enforces_resend_cooldown() {
+        let otp = service();
+        issue(&otp, 1000);
+        match otp.claim(PHONE, 1030) {
+            Err(Error(ErrorKind::OtpCooldown(seconds), _)) => assert_eq!(seconds, 30),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(otp.claim(PHONE, 1060).is_ok());
+    }
+
+    #[test]
+    fn pending_codes_hold_cooldown_but_do_not_verify() {
+        let otp = service();
+        let (code, pending, previous) = otp.claim(PHONE, 1000).unwrap();
+        assert!(pending.pending);
+        assert_eq!(previous, None);
+        assert!(otp.claim(PHONE, 1001).is_err());
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1002).unwrap(),
+            Verification::NotFound
+        );
+        otp.delivered(PHONE, &pending).unwrap();
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1003).unwrap(),
+            Verification::Valid
+        );
+    }
+
+    #[test]
+    fn undelivered_codes_restore_previous_code() {
+        let otp = service();
+        let code = issue(&otp, 1000);
+        let (_, pending, previous) = otp.claim(PHONE, 1100).unwrap();
+        otp.undelivered(PHONE, &pending, previous).unwrap();
+        match otp.claim(PHONE, 1130) {
+            Err(Error(ErrorKind::OtpCooldown(seconds), _)) => assert_eq!(seconds, 30),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1140).unwrap(),
+            Verification::Valid
+        );
+    }
+
+    #[test]
+    fn limits_wrong_guesses() {
+        let otp = service();
+        let code = issue(&otp, 1000);
+        assert_eq!(
+            otp.verify_at(PHONE, "x", 1010).unwrap(),
+            Verification::Invalid
+        );
+        assert_eq!(
+            otp.verify_at(PHONE, "x", 1011).unwrap(),
+            Verification::TooManyAttempts
+        );
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1012).unwrap(),
+            Verification::TooManyAttempts
+        );
+    }
+
+    #[test]
+    fn expires_codes() {
+        let otp = service();
+        let code = issue(&otp, 1000);
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1300).unwrap(),
+            Verification::Expired
+        );
+        assert_eq!(
+            otp.verify_at(PHONE, &code, 1301).unwrap(),
+            Verification::NotFound
+        );
+    }
+
+    #[test]
+    fn compare_and_set_refuses_stale_records() {
+        let otp = service();
+        issue(&otp, 1000);
+        let record = otp.store.get(PHONE).unwrap().unwrap();
+        let guessed = OtpRecord {
+            attempts: 1,
+            ..record.clone()
+        };
+        assert!(otp
+            .store
+            .compare_and_set(PHONE, Some(&record), Some(guessed.clone()))
+            .unwrap());
+        assert!(!otp
+            .store
+            .compare_and_set(PHONE, Some(&record), None)
+            .unwrap());
+        assert!(!otp.store.compare_and_set(PHONE, None, None).unwrap());
+        assert_eq!(otp.store.get(PHONE).unwrap(), Some(guessed));
+    }
+
+    #[test]
+    fn hashes_codes_with_secret() {
+        let otp = service();
+        let other = OtpService::new(
+            Box::new(MemoryOtpStore::default()),
+            OtpConfig::default(),
+            SecretString::new("fedcba9876543210fedcba9876543210"),
+        )
+        .unwrap();
+        let hash = otp.hash_code("salt", PHONE, "123456");
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, other.hash_code("salt", PHONE, "123456"));
+    }
+
+    #[test]
+    fn computes_hmac_sha256() {
+        // RFC 4231 test case 2
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            to_hex(&mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn undelivered_codes_keep_cooldown() {
+        let (url, _) = ::tests::serve(&[(500, "Internal Server Error")]);
+        let otp = service();
+        assert!(otp.send(&::tests::gateway(&url), PHONE, None).is_err());
+        let record = otp.store.get(PHONE).unwrap().unwrap();
+        assert!(record.pending);
+        match otp.send(&::tests::gateway(&url), PHONE, None) {
+            Err(Error(ErrorKind::OtpCooldown(_), _)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stores_code_once_sms_is_sent() {
+        let response = json!({
+            "SMSMessageData": {
+                "Message": "Sent to 1/1 Total Cost: KES 0.8000",
+                "Recipients": [{
+                    "number": PHONE,
+                    "status": "Success",
+                    "statusCode": 101,
+                    "messageId": "ATXid_1",
+                    "cost": "KES 0.8000"
+                }]
+            }
+        })
+        .to_string();
+        let (url, _) = ::tests::serve(&[(201, &response)]);
+        let otp = service();
+        let delivery = otp.send(&::tests::gateway(&url), PHONE, None).unwrap();
+        assert_eq!(delivery, OtpDelivery::Sms);
+        assert!(!otp.store.get(PHONE).unwrap().unwrap().pending);
+    }
+
+    #[test]
+    fn reads_codes_out_by_voice() {
+        assert_eq!(
+            voice_response("4821").to_xml(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response>\
+             <Say>Your verification code is 4 8 2 1.</Say>\
+             <Say>Your verification code is 4 8 2 1.</Say></Response>"
+        );
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
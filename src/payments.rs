@@ -0,0 +1,683 @@
+//! Payments API: wallet balance, wallet transfers, stash top ups and
+//! transaction queries.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use json;
+use reqwest;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use money::Money;
+use secret::redact;
+
+/// Wallet Balance Struct
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[allow(non_snake_case)]
+pub struct WalletBalance {
+    /// request status
+    pub status: String,
+
+    /// wallet balance e.g. `"KES 1000.00"`
+    #[serde(default)]
+    pub balance: String,
+
+    /// error message, if any
+    #[serde(default)]
+    pub errorMessage: Option<String>,
+}
+
+/// Wallet Transfer Request Struct
+///
+/// Moves money from one payment product's wallet to another product
+/// within the same account.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[allow(non_snake_case)]
+pub struct WalletTransferRequest {
+    /// payment product to transfer from
+    pub productName: String,
+
+    /// code of the payment product to transfer to
+    pub targetProductCode: i32,
+
+    /// amount to transfer
+    pub amount: Money,
+
+    /// metadata to associate with the transaction
+    pub metadata: HashMap<String, String>,
+}
+
+/// Topup Stash Request Struct
+///
+/// Moves money from the application's stash to a payment product's wallet.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[allow(non_snake_case)]
+pub struct TopupStashRequest {
+    /// payment product to top up
+    pub productName: String,
+
+    /// amount to top up
+    pub amount: Money,
+
+    /// metadata to associate with the transaction
+    pub metadata: HashMap<String, String>,
+}
+
+/// Transfer Response Struct
+///
+/// Returned by both wallet transfer and topup stash requests.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[allow(non_snake_case)]
+pub struct TransferResponse {
+    /// request status
+    pub status: String,
+
+    /// detailed description of the request status
+    #[serde(default)]
+    pub description: String,
+
+    /// unique transaction id, present on success
+    #[serde(default)]
+    pub transactionId: Option<String>,
+}
+
+/// Transaction Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct Transaction {
+    /// unique transaction id
+    #[serde(default)]
+    pub transactionId: String,
+
+    /// payment product the transaction belongs to
+    pub productName: Option<String>,
+
+    /// transaction category e.g. `MobileCheckout`, `MobileB2C`, `MobileB2B`
+    pub category: Option<String>,
+
+    /// payment provider e.g. `Mpesa`, `Athena`
+    pub provider: Option<String>,
+
+    /// provider channel e.g. a paybill number
+    pub providerChannel: Option<String>,
+
+    /// transaction id assigned by the provider
+    pub providerRefId: Option<String>,
+
+    /// extra data returned by the provider
+    #[serde(default)]
+    pub providerMetadata: json::Value,
+
+    /// transaction source
+    pub source: Option<String>,
+
+    /// source type e.g. `PhoneNumber`, `Wallet`
+    pub sourceType: Option<String>,
+
+    /// transaction destination
+    pub destination: Option<String>,
+
+    /// destination type e.g. `PhoneNumber`, `Wallet`
+    pub destinationType: Option<String>,
+
+    /// transaction value e.g. `"KES 100.0000"`
+    pub value: Option<String>,
+
+    /// fee charged for the transaction
+    pub transactionFee: Option<String>,
+
+    /// transaction status e.g. `Success`, `Failed`
+    pub status: Option<String>,
+
+    /// description of the transaction status
+    pub description: Option<String>,
+
+    /// metadata sent with the initiating request
+    #[serde(default)]
+    pub requestMetadata: HashMap<String, String>,
+
+    /// time the transaction was created
+    pub creationTime: Option<String>,
+
+    /// time the transaction was completed
+    pub transactionDate: Option<String>,
+}
+
+/// Wallet Transaction Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct WalletTransaction {
+    /// unique transaction id
+    #[serde(default)]
+    pub transactionId: String,
+
+    /// transaction category e.g. `Debit`, `Credit`, `Refund`, `Topup`
+    pub category: Option<String>,
+
+    /// transaction description
+    pub description: Option<String>,
+
+    /// transaction value e.g. `"KES 100.0000"`
+    pub value: Option<String>,
+
+    /// wallet balance after the transaction
+    pub balance: Option<String>,
+
+    /// transaction date
+    pub date: Option<String>,
+
+    /// details of the underlying payment transaction
+    pub transactionData: Option<Transaction>,
+}
+
+/// Product Transactions Filter Struct
+///
+/// Fields left as `None` are not sent to the API.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct ProductTransactionsFilter {
+    /// page to fetch, starting from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pageNumber: Option<i32>,
+
+    /// number of transactions per page (max 1000)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<i32>,
+
+    /// start date, in the format `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startDate: Option<String>,
+
+    /// end date, in the format `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endDate: Option<String>,
+
+    /// transaction category e.g. `MobileCheckout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+
+    /// payment provider e.g. `Mpesa`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+
+    /// transaction status e.g. `Success`, `Failed`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+
+    /// transaction source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// transaction destination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+
+    /// provider channel e.g. a paybill number
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub providerChannel: Option<String>,
+}
+
+/// Wallet Transactions Filter Struct
+///
+/// Fields left as `None` are not sent to the API.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct WalletTransactionsFilter {
+    /// page to fetch, starting from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pageNumber: Option<i32>,
+
+    /// number of transactions per page (max 1000)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<i32>,
+
+    /// start date, in the format `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startDate: Option<String>,
+
+    /// end date, in the format `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endDate: Option<String>,
+
+    /// comma separated categories e.g. `Debit,Credit`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub categories: Option<String>,
+}
+
+/// Iterator that walks every page of a transaction query.
+///
+/// Pages are fetched lazily; iteration stops after an empty or short page,
+/// or after the first error.
+pub struct Pages<'a, T> {
+    fetch: Box<dyn FnMut(i32) -> Result<Vec<T>> + 'a>,
+    page_number: i32,
+    count: Option<i32>,
+    buffer: VecDeque<T>,
+    done: bool,
+}
+
+impl<'a, T> Pages<'a, T> {
+    fn new<F>(page_number: Option<i32>, count: Option<i32>, fetch: F) -> Self
+    where
+        F: FnMut(i32) -> Result<Vec<T>> + 'a,
+    {
+        Pages {
+            fetch: Box::new(fetch),
+            page_number: page_number.unwrap_or(1),
+            count,
+            buffer: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<'a, T> fmt::Debug for Pages<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pages")
+            .field("page_number", &self.page_number)
+            .field("count", &self.count)
+            .field("buffered", &self.buffer.len())
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<'a, T> Iterator for Pages<'a, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.buffer.pop_front() {
+                return Some(Ok(item));
+            }
+            if self.done {
+                return None;
+            }
+            match (self.fetch)(self.page_number) {
+                Ok(page) => {
+                    self.page_number += 1;
+                    let short = self.count.filter(|&c| (page.len() as i32) < c).is_some();
+                    if page.is_empty() || short {
+                        self.done = true;
+                    }
+                    self.buffer.extend(page);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct QueryResponse<T> {
+    status: String,
+    #[serde(default)]
+    errorMessage: Option<String>,
+    #[serde(default)]
+    data: Option<T>,
+    #[serde(default)]
+    responses: Vec<T>,
+}
+
+impl AfricasTalkingGateway {
+    /// Fetches the account's payment wallet balance.
+    /// [read more..](http://docs.africastalking.com/payments/wallet-balance)
+    pub fn get_wallet_balance(&self) -> Result<WalletBalance> {
+        let url = format!("{}?username={}", self.wallet_balance_url, self.username);
+        let mut resp = self.send_request(&url, None)?;
+        if resp.status().as_u16() == 200 {
+            let balance: WalletBalance = resp.json()?;
+            if balance.status == "Success" {
+                Ok(balance)
+            } else {
+                // raise error
+                Err(ErrorKind::GatewayError(balance.errorMessage.unwrap_or_default()).into())
+            }
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+        }
+    }
+
+    /// Transfers money from one payment product to another.
+    /// [read more..](http://docs.africastalking.com/payments/wallet-transfer)
+    pub fn wallet_transfer(&self, req: &WalletTransferRequest) -> Result<TransferResponse> {
+        let params = json!({
+            "username": self.username,
+            "productName": req.productName,
+            "targetProductCode": req.targetProductCode,
+            "currencyCode": req.amount.currency,
+            "amount": amount_value(&req.amount)?,
+            "metadata": req.metadata
+        });
+        let mut resp = self.send_json_request(&self.wallet_transfer_url, params)?;
+        transfer_response(&mut resp)
+    }
+
+    /// Moves money from the application stash to a payment product.
+    /// [read more..](http://docs.africastalking.com/payments/topup-stash)
+    pub fn topup_stash(&self, req: &TopupStashRequest) -> Result<TransferResponse> {
+        let params = json!({
+            "username": self.username,
+            "productName": req.productName,
+            "currencyCode": req.amount.currency,
+            "amount": amount_value(&req.amount)?,
+            "metadata": req.metadata
+        });
+        let mut resp = self.send_json_request(&self.topup_stash_url, params)?;
+        transfer_response(&mut resp)
+    }
+
+    /// Finds a payment transaction by its id.
+    /// [read more..](http://docs.africastalking.com/payments/find-transaction)
+    pub fn find_transaction(&self, transaction_id: &str) -> Result<Transaction> {
+        let params = vec![("transactionId".to_string(), transaction_id.to_string())];
+        let resp: QueryResponse<Transaction> =
+            self.query_payments(&self.find_transaction_url, params)?;
+        match resp.data {
+            Some(transaction) => Ok(transaction),
+            None => Err(ErrorKind::GatewayError(resp.errorMessage.unwrap_or_default()).into()),
+        }
+    }
+
+    /// Fetches a single page of a payment product's transactions.
+    /// [read more..](http://docs.africastalking.com/payments/fetch-product-transactions)
+    pub fn fetch_product_transactions(
+        &self,
+        product_name: &str,
+        filter: &ProductTransactionsFilter,
+    ) -> Result<Vec<Transaction>> {
+        let mut params = query_params(filter)?;
+        params.push(("productName".to_string(), product_name.to_string()));
+        if filter.pageNumber.is_none() {
+            params.push(("pageNumber".to_string(), "1".to_string()));
+        }
+        let resp: QueryResponse<Transaction> =
+            self.query_payments(&self.product_transactions_url, params)?;
+
+        Ok(resp.responses)
+    }
+
+    /// Fetches a single page of the payment wallet's transactions.
+    /// [read more..](http://docs.africastalking.com/payments/fetch-wallet-transactions)
+    pub fn fetch_wallet_transactions(
+        &self,
+        filter: &WalletTransactionsFilter,
+    ) -> Result<Vec<WalletTransaction>> {
+        let mut params = query_params(filter)?;
+        if filter.pageNumber.is_none() {
+            params.push(("pageNumber".to_string(), "1".to_string()));
+        }
+        let resp: QueryResponse<WalletTransaction> =
+            self.query_payments(&self.wallet_transactions_url, params)?;
+
+        Ok(resp.responses)
+    }
+
+    /// Returns an iterator over all of a payment product's transactions,
+    /// starting from the filter's page number.
+    pub fn product_transactions<'a>(
+        &'a self,
+        product_name: &str,
+        filter: ProductTransactionsFilter,
+    ) -> Pages<'a, Transaction> {
+        let product_name = product_name.to_string();
+        Pages::new(filter.pageNumber, filter.count, move |page| {
+            let mut filter = filter.clone();
+            filter.pageNumber = Some(page);
+            self.fetch_product_transactions(&product_name, &filter)
+        })
+    }
+
+    /// Returns an iterator over all of the payment wallet's transactions,
+    /// starting from the filter's page number.
+    pub fn wallet_transactions<'a>(
+        &'a self,
+        filter: WalletTransactionsFilter,
+    ) -> Pages<'a, WalletTransaction> {
+        Pages::new(filter.pageNumber, filter.count, move |page| {
+            let mut filter = filter.clone();
+            filter.pageNumber = Some(page);
+            self.fetch_wallet_transactions(&filter)
+        })
+    }
+
+    fn query_payments<T: DeserializeOwned + Default>(
+        &self,
+        url: &str,
+        mut params: Vec<(String, String)>,
+    ) -> Result<QueryResponse<T>> {
+        params.insert(0, ("username".to_string(), self.username.clone()));
+        let url = reqwest::Url::parse_with_params(url, &params)?;
+        let mut resp = self.send_request(url.as_str(), None)?;
+        if resp.status().as_u16() == 200 {
+            let jsn: QueryResponse<T> = resp.json()?;
+            if jsn.status == "Success" {
+                Ok(jsn)
+            } else {
+                // raise error
+                Err(ErrorKind::GatewayError(jsn.errorMessage.unwrap_or_default()).into())
+            }
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+        }
+    }
+}
+
+/// Flattens a filter struct into query parameters, skipping unset fields.
+fn query_params<T: Serialize>(filter: &T) -> Result<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    if let json::Value::Object(map) = json::to_value(filter)? {
+        for (key, val) in map {
+            match val {
+                json::Value::Null => {}
+                json::Value::String(s) => params.push((key, s)),
+                val => params.push((key, val.to_string())),
+            }
+        }
+    }
+
+    Ok(params)
+}
+
+/// The amount of `money` as a JSON number, e.g. `100.5`.
+fn amount_value(money: &Money) -> Result<json::Value> {
+    Ok(json::from_str(&money.amount())?)
+}
+
+fn transfer_response(resp: &mut reqwest::Response) -> Result<TransferResponse> {
+    if resp.status().as_u16() == 201 {
+        let transfer: TransferResponse = resp.json()?;
+        if transfer.status == "Success" {
+            Ok(transfer)
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(transfer.description).into())
+        }
+    } else {
+        // raise error
+        Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_transfer_response() {
+        let jsn = json!({
+            "status": "Success",
+            "description": "Transfered funds to sandbox [TestProduct]",
+            "transactionId": "ATPid_SampleTxnId123"
+        });
+        let resp: TransferResponse = json::from_value(jsn).unwrap();
+        assert_eq!(resp.status, "Success");
+        assert_eq!(resp.transactionId, Some("ATPid_SampleTxnId123".to_string()));
+    }
+
+    #[test]
+    fn gets_wallet_balance() {
+        let (url, requests) =
+            ::tests::serve(&[(200, r#"{"status":"Success","balance":"KES 1000.00"}"#)]);
+        let balance = ::tests::gateway(&url).get_wallet_balance().unwrap();
+        assert_eq!(balance.balance, "KES 1000.00");
+        assert!(requests.recv().unwrap().contains("username=sandbox"));
+    }
+
+    #[test]
+    fn wallet_balance_fails_on_failed_status() {
+        let (url, _) = ::tests::serve(&[(
+            200,
+            r#"{"status":"Failed","errorMessage":"Invalid username"}"#,
+        )]);
+        let err = ::tests::gateway(&url).get_wallet_balance().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid username");
+    }
+
+    #[test]
+    fn wallet_balance_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(401, "The supplied authentication is invalid")]);
+        let err = ::tests::gateway(&url).get_wallet_balance().unwrap_err();
+        assert_eq!(err.to_string(), "The supplied authentication is invalid");
+    }
+
+    #[test]
+    fn wallet_transfer_fails_on_failed_status() {
+        let (url, _) = ::tests::serve(&[(
+            201,
+            r#"{"status":"Failed","description":"Insufficient funds"}"#,
+        )]);
+        let req = WalletTransferRequest {
+            productName: "TestProduct".into(),
+            targetProductCode: 2,
+            amount: Money::whole("KES", 100),
+            ..Default::default()
+        };
+        let err = ::tests::gateway(&url).wallet_transfer(&req).unwrap_err();
+        assert_eq!(err.to_string(), "Insufficient funds");
+    }
+
+    #[test]
+    fn wallet_transfer_sends_exact_amount() {
+        let (url, requests) = ::tests::serve(&[(
+            201,
+            r#"{"status":"Success","description":"Transfered funds","transactionId":"ATPid_1"}"#,
+        )]);
+        let req = WalletTransferRequest {
+            productName: "TestProduct".into(),
+            targetProductCode: 2,
+            amount: "KES 1,234.56".parse().unwrap(),
+            ..Default::default()
+        };
+        ::tests::gateway(&url).wallet_transfer(&req).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.contains(r#""amount":1234.56"#));
+        assert!(request.contains(r#""currencyCode":"KES""#));
+    }
+
+    #[test]
+    fn topup_stash_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(400, "Invalid product name")]);
+        let req = TopupStashRequest {
+            productName: "Unknown".into(),
+            amount: Money::whole("KES", 100),
+            ..Default::default()
+        };
+        let err = ::tests::gateway(&url).topup_stash(&req).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid product name");
+    }
+
+    #[test]
+    fn filter_skips_unset_fields() {
+        let filter = ProductTransactionsFilter {
+            count: Some(50),
+            status: Some("Success".to_string()),
+            ..Default::default()
+        };
+        let params = query_params(&filter).unwrap();
+        assert_eq!(params.len(), 2);
+        assert!(params.contains(&("count".to_string(), "50".to_string())));
+        assert!(params.contains(&("status".to_string(), "Success".to_string())));
+    }
+
+    #[test]
+    fn find_transaction_fails_on_failed_status() {
+        let (url, _) = ::tests::serve(&[(
+            200,
+            r#"{"status":"Failed","errorMessage":"Invalid transactionId"}"#,
+        )]);
+        let err = ::tests::gateway(&url)
+            .find_transaction("ATPid_1")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid transactionId");
+    }
+
+    #[test]
+    fn fetch_sends_filter_and_first_page() {
+        let (url, requests) = ::tests::serve(&[(
+            200,
+            r#"{"status":"Success","responses":[{"transactionId":"ATPid_1"}]}"#,
+        )]);
+        let filter = ProductTransactionsFilter {
+            count: Some(50),
+            ..Default::default()
+        };
+        let transactions = ::tests::gateway(&url)
+            .fetch_product_transactions("TestProduct", &filter)
+            .unwrap();
+        assert_eq!(transactions[0].transactionId, "ATPid_1");
+        let request = requests.recv().unwrap();
+        assert!(request.contains("productName=TestProduct"));
+        assert!(request.contains("pageNumber=1"));
+        assert!(request.contains("count=50"));
+    }
+
+    #[test]
+    fn pages_stop_after_error_response() {
+        let (url, _) = ::tests::serve(&[
+            (
+                200,
+                r#"{"status":"Success","responses":[{"transactionId":"1"},{"transactionId":"2"}]}"#,
+            ),
+            (500, "Internal Server Error"),
+        ]);
+        let gway = ::tests::gateway(&url);
+        let filter = WalletTransactionsFilter {
+            count: Some(2),
+            ..Default::default()
+        };
+        let mut pages = gway.wallet_transactions(filter);
+        assert_eq!(pages.next().unwrap().unwrap().transactionId, "1");
+        assert_eq!(pages.next().unwrap().unwrap().transactionId, "2");
+        assert_eq!(
+            pages.next().unwrap().unwrap_err().to_string(),
+            "Internal Server Error"
+        );
+        assert!(pages.next().is_none());
+    }
+
+    #[test]
+    fn pages_stop_after_short_page() {
+        let mut calls = Vec::new();
+        let items: Vec<i32> = {
+            let pages = Pages::new(None, Some(2), |page| {
+                calls.push(page);
+                Ok(match page {
+                    1 => vec![1, 2],
+                    2 => vec![3],
+                    _ => vec![4, 5],
+                })
+            });
+            pages.map(|item| item.unwrap()).collect()
+        };
+        assert_eq!(items, vec![1, 2, 3]);
+        assert_eq!(calls, vec![1, 2]);
+    }
+}
@@ -0,0 +1,486 @@
+//! Local outbound SMS queue with scheduling, throttling and persistence.
+//!
+//! Unlike the API's `enqueue` option, messages stay on this side until they
+//! are due; pending messages are saved through a `QueueStore` after every
+//! change so they survive a restart.
+//!
+//! ```rust,ignore
+//! let store = FileQueueStore::new("sms-queue.json");
+//! let mut queue = SendQueue::open(Box::new(store), QueueConfig::default())?;
+//! queue.enqueue(msg, Some(SystemTime::now() + Duration::from_secs(3600)))?;
+//!
+//! loop {
+//!     for outcome in queue.drain(&gway)? {
+//!         println!("{:?}", outcome);
+//!     }
+//!     thread::sleep(Duration::from_secs(10));
+//! }
+//! ```
+use std::fmt;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use json;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result, ResultExt, SMSMessage};
+
+/// A message waiting in the queue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedMessage {
+    /// queue-assigned id
+    pub id: u64,
+
+    /// the message to send
+    pub message: SMSMessage,
+
+    /// when to send, in seconds since the unix epoch
+    pub send_at: u64,
+
+    /// failed send attempts so far
+    pub attempts: u32,
+
+    /// error from the last failed attempt
+    pub last_error: Option<String>,
+}
+
+/// Storage for pending queue messages.
+pub trait QueueStore: fmt::Debug + Send {
+    /// loads the pending messages
+    fn load(&self) -> Result<Vec<QueuedMessage>>;
+
+    /// replaces the stored messages with `pending`
+    fn save(&self, pending: &[QueuedMessage]) -> Result<()>;
+}
+
+/// Keeps pending messages in a JSON file
+#[derive(Debug, Clone)]
+pub struct FileQueueStore {
+    path: PathBuf,
+}
+
+impl FileQueueStore {
+    /// creates a store saving to `path`
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl QueueStore for FileQueueStore {
+    fn load(&self) -> Result<Vec<QueuedMessage>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(json::from_reader(File::open(&self.path)?)?)
+    }
+
+    fn save(&self, pending: &[QueuedMessage]) -> Result<()> {
+        // write then rename, so a crash mid-write keeps the previous file
+        let tmp = self.path.with_extension("tmp");
+        json::to_writer(File::create(&tmp)?, pending)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Keeps pending messages in memory, for tests and short-lived queues
+#[derive(Debug, Default)]
+pub struct MemoryQueueStore {
+    pending: Mutex<Vec<QueuedMessage>>,
+}
+
+impl QueueStore for MemoryQueueStore {
+    fn load(&self) -> Result<Vec<QueuedMessage>> {
+        Ok(self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone())
+    }
+
+    fn save(&self, pending: &[QueuedMessage]) -> Result<()> {
+        *self.pending.lock().unwrap_or_else(|e| e.into_inner()) = pending.to_vec();
+        Ok(())
+    }
+}
+
+/// Queue configuration
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// most messages sent per second; at least 1
+    pub messages_per_second: u32,
+
+    /// attempts before a message is dropped as failed
+    pub max_attempts: u32,
+
+    /// delay before retrying a failed message
+    pub retry_delay: Duration,
+}
+
+impl QueueConfig {
+    /// Checks the configuration can send messages.
+    pub fn validate(&self) -> Result<()> {
+        if self.messages_per_second == 0 {
+            // raise error
+            return Err(ErrorKind::InvalidConfig(
+                "messages_per_second".into(),
+                "must be at least 1".into(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_second: 5,
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Result of trying to send a queued message
+#[derive(Debug, Clone)]
+pub enum QueueOutcome {
+    /// the message was sent
+    Sent {
+        /// queue id of the message
+        id: u64,
+        /// the `send_message` response
+        response: json::Value,
+    },
+    /// sending failed and the message will be retried
+    Retrying {
+        /// queue id of the message
+        id: u64,
+        /// why sending failed
+        error: String,
+    },
+    /// sending failed too many times and the message was dropped
+    Failed {
+        /// queue id of the message
+        id: u64,
+        /// why sending failed
+        error: String,
+        /// the message that could not be sent
+        message: SMSMessage,
+    },
+}
+
+/// Scheduled, throttled SMS send queue
+#[derive(Debug)]
+pub struct SendQueue {
+    store: Box<dyn QueueStore>,
+    config: QueueConfig,
+    pending: Vec<QueuedMessage>,
+    next_id: u64,
+    last_sent: Option<Instant>,
+}
+
+impl SendQueue {
+    /// Opens a queue, loading any messages left pending in `store`.
+    ///
+    /// Fails with `ErrorKind::InvalidConfig` if the configuration is invalid.
+    pub fn open(store: Box<dyn QueueStore>, config: QueueConfig) -> Result<Self> {
+        config.validate()?;
+        let pending = store.load()?;
+        let next_id = pending.iter().map(|m| m.id + 1).max().unwrap_or(1);
+
+        Ok(Self {
+            store,
+            config,
+            pending,
+            next_id,
+            last_sent: None,
+        })
+    }
+
+    /// Adds a message to send at `send_at`, or as soon as possible,
+    /// returning its queue id.
+    pub fn enqueue(&mut self, message: SMSMessage, send_at: Option<SystemTime>) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(QueuedMessage {
+            id,
+            message,
+            send_at: send_at.map(unix_secs).unwrap_or(0),
+            attempts: 0,
+            last_error: None,
+        });
+        self.store.save(&self.pending)?;
+
+        Ok(id)
+    }
+
+    /// Removes a pending message, returning it.
+    pub fn cancel(&mut self, id: u64) -> Result<Option<SMSMessage>> {
+        let idx = match self.pending.iter().position(|m| m.id == id) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let removed = self.pending.remove(idx);
+        self.store.save(&self.pending)?;
+
+        Ok(Some(removed.message))
+    }
+
+    /// messages waiting to be sent
+    pub fn pending(&self) -> &[QueuedMessage] {
+        &self.pending
+    }
+
+    /// when the next message is due, `None` if the queue is empty
+    pub fn next_due(&self) -> Option<SystemTime> {
+        self.pending
+            .iter()
+            .map(|m| m.send_at)
+            .min()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Sends every due message through `send_message`, at most
+    /// `messages_per_second`, returning what happened to each.
+    ///
+    /// The queue is saved after every message. If saving fails, draining
+    /// stops and the error is chained onto `ErrorKind::QueueNotSaved` with
+    /// the outcomes of the messages already processed.
+    pub fn drain(&mut self, gateway: &AfricasTalkingGateway) -> Result<Vec<QueueOutcome>> {
+        self.drain_with(SystemTime::now(), |msg| gateway.send_message(msg.clone()))
+    }
+
+    fn drain_with<F>(&mut self, now: SystemTime, mut send: F) -> Result<Vec<QueueOutcome>>
+    where
+        F: FnMut(&SMSMessage) -> Result<json::Value>,
+    {
+        let now = unix_secs(now);
+        let mut due: Vec<(u64, u64)> = self
+            .pending
+            .iter()
+            .filter(|m| m.send_at <= now)
+            .map(|m| (m.send_at, m.id))
+            .collect();
+        due.sort();
+
+        let mut outcomes = Vec::new();
+        for (_, id) in due {
+            self.throttle();
+            let idx = match self.pending.iter().position(|m| m.id == id) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let outcome = match send(&self.pending[idx].message) {
+                Ok(response) => {
+                    self.pending.remove(idx);
+                    QueueOutcome::Sent { id, response }
+                }
+                Err(e) => {
+                    let error = e.to_string();
+                    let queued = &mut self.pending[idx];
+                    queued.attempts += 1;
+                    queued.last_error = Some(error.clone());
+                    if queued.attempts >= self.config.max_attempts {
+                        let message = self.pending.remove(idx).message;
+                        QueueOutcome::Failed { id, error, message }
+                    } else {
+                        queued.send_at = now + self.config.retry_delay.as_secs();
+                        QueueOutcome::Retrying { id, error }
+                    }
+                }
+            };
+            outcomes.push(outcome);
+            if let Err(e) = self.store.save(&self.pending) {
+                // raise error
+                return Err(e).chain_err(|| ErrorKind::QueueNotSaved(outcomes));
+            }
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Waits until the next send is allowed by `messages_per_second`.
+    fn throttle(&mut self) {
+        let rate = u64::from(self.config.messages_per_second);
+        let interval = Duration::from_nanos(1_000_000_000 / rate);
+        if let Some(last) = self.last_sent {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                thread::sleep(interval - elapsed);
+            }
+        }
+        self.last_sent = Some(Instant::now());
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    const CONFIG: QueueConfig = QueueConfig {
+        messages_per_second: 1000,
+        max_attempts: 2,
+        retry_delay: Duration::from_secs(60),
+    };
+
+    fn message(to: &str) -> SMSMessage {
+        SMSMessage::new("sandbox", to, "Hello", None, None, None, None, None, None)
+    }
+
+    fn queue() -> SendQueue {
+        SendQueue::open(Box::new(MemoryQueueStore::default()), CONFIG).unwrap()
+    }
+
+    fn sent(_: &SMSMessage) -> Result<json::Value> {
+        Ok(json!({"SMSMessageData": {"Recipients": []}}))
+    }
+
+    fn timeout(_: &SMSMessage) -> Result<json::Value> {
+        Err(ErrorKind::GatewayError("timeout".into()).into())
+    }
+
+    #[test]
+    fn sends_due_messages_only() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(3600);
+        let mut queue = queue();
+        queue.enqueue(message("+254711000001"), None).unwrap();
+        queue.enqueue(message("+254711000002"), None).unwrap();
+        let scheduled = queue
+            .enqueue(message("+254711000003"), Some(later))
+            .unwrap();
+
+        let outcomes = queue.drain_with(now, sent).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(matches!(outcomes[0], QueueOutcome::Sent { id: 1, .. }));
+        assert!(matches!(outcomes[1], QueueOutcome::Sent { id: 2, .. }));
+        assert_eq!(queue.pending().len(), 1);
+        assert_eq!(queue.pending()[0].id, scheduled);
+        assert_eq!(unix_secs(queue.next_due().unwrap()), unix_secs(later));
+    }
+
+    #[test]
+    fn retries_after_delay_then_fails() {
+        let now = SystemTime::now();
+        let mut queue = queue();
+        queue.enqueue(message("+254711000001"), None).unwrap();
+
+        let outcomes = queue.drain_with(now, timeout).unwrap();
+        assert!(matches!(outcomes[0], QueueOutcome::Retrying { id: 1, .. }));
+        assert_eq!(queue.pending()[0].last_error.as_deref(), Some("timeout"));
+        assert!(queue.drain_with(now, timeout).unwrap().is_empty());
+
+        let outcomes = queue
+            .drain_with(now + Duration::from_secs(61), timeout)
+            .unwrap();
+        match outcomes[0] {
+            QueueOutcome::Failed { ref message, .. } => {
+                assert_eq!(message.to, "+254711000001")
+            }
+            ref other => panic!("unexpected outcome {:?}", other),
+        }
+        assert!(queue.pending().is_empty());
+    }
+
+    /// Saves `saves` times, then fails.
+    #[derive(Debug)]
+    struct FailingStore {
+        saves: Mutex<u32>,
+    }
+
+    impl QueueStore for FailingStore {
+        fn load(&self) -> Result<Vec<QueuedMessage>> {
+            Ok(Vec::new())
+        }
+
+        fn save(&self, _: &[QueuedMessage]) -> Result<()> {
+            let mut saves = self.saves.lock().unwrap();
+            if *saves == 0 {
+                return Err(ErrorKind::GatewayError("disk full".into()).into());
+            }
+            *saves -= 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejects_zero_rate() {
+        let config = QueueConfig {
+            messages_per_second: 0,
+            ..CONFIG
+        };
+        match SendQueue::open(Box::new(MemoryQueueStore::default()), config) {
+            Err(Error(ErrorKind::InvalidConfig(key, _), _)) => {
+                assert_eq!(key, "messages_per_second")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn returns_outcomes_when_save_fails() {
+        let store = FailingStore {
+            saves: Mutex::new(2),
+        };
+        let mut queue = SendQueue::open(Box::new(store), CONFIG).unwrap();
+        queue.enqueue(message("+254711000001"), None).unwrap();
+        queue.enqueue(message("+254711000002"), None).unwrap();
+
+        match queue.drain_with(SystemTime::now(), sent) {
+            Err(Error(ErrorKind::QueueNotSaved(outcomes), _)) => {
+                assert_eq!(outcomes.len(), 1);
+                assert!(matches!(outcomes[0], QueueOutcome::Sent { id: 1, .. }));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(queue.pending().len(), 1);
+    }
+
+    #[test]
+    fn cancels_pending_message() {
+        let mut queue = queue();
+        let id = queue.enqueue(message("+254711000001"), None).unwrap();
+        assert_eq!(queue.cancel(id).unwrap().unwrap().to, "+254711000001");
+        assert!(queue.cancel(id).unwrap().is_none());
+        assert!(queue.next_due().is_none());
+    }
+
+    #[test]
+    fn reopening_picks_up_pending_messages() {
+        let path = ::std::env::temp_dir().join(format!("at-queue-{}.json", ::std::process::id()));
+        let open = || SendQueue::open(Box::new(FileQueueStore::new(path.clone())), CONFIG);
+        let mut queue = open().unwrap();
+        queue.enqueue(message("+254711000001"), None).unwrap();
+        queue.enqueue(message("+254711000002"), None).unwrap();
+        queue.drain_with(SystemTime::now(), sent).unwrap();
+        queue.enqueue(message("+254711000003"), None).unwrap();
+
+        let mut queue = open().unwrap();
+        assert_eq!(queue.pending().len(), 1);
+        assert_eq!(queue.enqueue(message("+254711000004"), None).unwrap(), 4);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn drain_retries_on_error_response() {
+        let (url, requests) = ::tests::serve(&[(500, "Internal Server Error")]);
+        let mut queue = queue();
+        queue.enqueue(message("+254711000001"), None).unwrap();
+
+        let outcomes = queue.drain(&::tests::gateway(&url)).unwrap();
+        assert!(matches!(outcomes[0], QueueOutcome::Retrying { id: 1, .. }));
+        assert!(requests.recv().unwrap().contains("254711000001"));
+        assert_eq!(queue.pending()[0].attempts, 1);
+    }
+}
@@ -0,0 +1,406 @@
+//! Client-side rate limiting per API.
+//!
+//! A `RateLimiter` keeps a token bucket for each limited API. Requests
+//! take a token first, either waiting for one or failing with
+//! `ErrorKind::RateLimited`. Throttled responses (`429`, or `503` with a
+//! `Retry-After` header) pause the API for the time the server asks. In
+//! waiting mode a `429` is retried, since the server turned the request
+//! away unprocessed; a `503` is only retried for requests that are safe to
+//! repeat, i.e. `GET`s and requests with an idempotency key. Other requests
+//! return the `503` response as it came.
+//!
+//! The limiter is shared by every gateway it is given to and by clones of
+//! those gateways, so workers using one account stay within its limits.
+//!
+//! ```rust,ignore
+//! let limiter = RateLimiter::new(OnLimit::Wait)
+//!     .limit(Api::Sms, RateLimit::per_second(10.0)?.with_burst(20))
+//!     .limit(Api::Airtime, RateLimit::per_second(2.0)?);
+//! let gway = AfricasTalkingGateway::new(&username, &api_key, "sandbox")
+//!     .with_rate_limiter(limiter);
+//!
+//! for worker in 0..4 {
+//!     let gway = gway.clone();
+//!     thread::spawn(move || gway.send_message(msg(worker)));
+//! }
+//! ```
+use std::collections::HashMap;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use metrics::Api;
+
+/// Attempts made for a request the server keeps throttling
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Pause after a throttled response without a `Retry-After` header
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// A token bucket's size and refill rate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// requests allowed per second on average
+    per_second: f64,
+
+    /// requests allowed at once after a quiet period
+    burst: u32,
+}
+
+impl RateLimit {
+    /// `per_second` requests a second, with bursts of up to a second's
+    /// worth. Fails with `ErrorKind::InvalidConfig` unless `per_second` is a
+    /// positive, finite number.
+    pub fn per_second(per_second: f64) -> Result<Self> {
+        if !per_second.is_finite() || per_second <= 0.0 {
+            // raise error
+            return Err(ErrorKind::InvalidConfig(
+                "per_second".into(),
+                format!("expected a positive number, got {}", per_second),
+            )
+            .into());
+        }
+
+        Ok(Self {
+            per_second,
+            burst: (per_second.ceil() as u32).max(1),
+        })
+    }
+
+    /// requests allowed per second on average
+    pub fn rate(&self) -> f64 {
+        self.per_second
+    }
+
+    /// requests allowed at once after a quiet period
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// allows bursts of `burst` requests
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+}
+
+/// What to do when a request is over the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnLimit {
+    /// block until the request is allowed
+    Wait,
+    /// fail with `ErrorKind::RateLimited`
+    Fail,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buckets: HashMap<Api, Bucket>,
+    paused_until: HashMap<Api, Instant>,
+}
+
+/// Token bucket rate limiter, shared by its clones
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    on_limit: OnLimit,
+    limits: HashMap<Api, RateLimit>,
+    state: Arc<Mutex<State>>,
+}
+
+impl RateLimiter {
+    /// creates a limiter with no limits
+    pub fn new(on_limit: OnLimit) -> Self {
+        Self {
+            on_limit,
+            limits: HashMap::new(),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// limits requests to `api`
+    pub fn limit(mut self, api: Api, limit: RateLimit) -> Self {
+        self.limits.insert(api, limit);
+        self
+    }
+
+    /// what happens to requests over the limit
+    pub fn on_limit(&self) -> OnLimit {
+        self.on_limit
+    }
+
+    /// Takes a token for a request to `api`, waiting for one or failing
+    /// with `ErrorKind::RateLimited` as configured.
+    pub fn acquire(&self, api: Api) -> Result<()> {
+        loop {
+            let wait = match self.try_acquire_at(api, Instant::now()) {
+                None => return Ok(()),
+                Some(wait) => wait,
+            };
+            match self.on_limit {
+                OnLimit::Wait => thread::sleep(wait),
+                OnLimit::Fail => return Err(ErrorKind::RateLimited(api.to_string(), wait).into()),
+            }
+        }
+    }
+
+    /// Pauses requests to `api` for `delay`, e.g. as asked by a server's
+    /// `Retry-After` header.
+    pub fn pause(&self, api: Api, delay: Duration) {
+        self.pause_at(api, delay, Instant::now());
+    }
+
+    /// Takes a token if one is available, otherwise returns how long until
+    /// one will be.
+    fn try_acquire_at(&self, api: Api, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(&until) = state.paused_until.get(&api) {
+            if now < until {
+                return Some(until - now);
+            }
+            state.paused_until.remove(&api);
+        }
+        let limit = match self.limits.get(&api) {
+            Some(&limit) => limit,
+            None => return None,
+        };
+        let bucket = state.buckets.entry(api).or_insert_with(|| Bucket {
+            limit,
+            tokens: f64::from(limit.burst),
+            refilled: now,
+        });
+        if now > bucket.refilled {
+            let elapsed = (now - bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * bucket.limit.per_second)
+                .min(f64::from(bucket.limit.burst));
+            bucket.refilled = now;
+        }
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            let secs = (1.0 - bucket.tokens) / bucket.limit.per_second;
+            Some(Duration::from_secs_f64(secs))
+        }
+    }
+
+    fn pause_at(&self, api: Api, delay: Duration, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let until = now + delay;
+        let paused = state.paused_until.entry(api).or_insert(until);
+        if *paused < until {
+            *paused = until;
+        }
+    }
+}
+
+/// How long a throttled response asks to wait, `None` if it wasn't
+/// throttled. Only the delay-seconds form of `Retry-After` is read.
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let header = resp
+        .headers()
+        .get_raw("Retry-After")
+        .and_then(|raw| raw.one())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    match resp.status().as_u16() {
+        429 => Some(header.unwrap_or(DEFAULT_RETRY_AFTER)),
+        503 => header,
+        _ => None,
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Limits the rate of requests with `limiter`.
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Sends a request within the rate limits, retrying throttled requests
+    /// when the limiter waits. A `503` is only retried when `idempotent`,
+    /// since the server may have acted on the request before failing.
+    pub(crate) fn send_limited<F>(
+        &self,
+        method: &str,
+        url: &str,
+        idempotent: bool,
+        mut send: F,
+    ) -> Result<reqwest::Response>
+    where
+        F: FnMut() -> Result<reqwest::Response>,
+    {
+        let limiter = match self.rate_limiter {
+            Some(ref limiter) => limiter,
+            None => return self.instrumented(method, url, 1, send),
+        };
+        let api = Api::from_url(url);
+        let mut attempt = 1;
+        loop {
+            limiter.acquire(api)?;
+            let resp = self.instrumented(method, url, attempt, &mut send)?;
+            let delay = match retry_after(&resp) {
+                Some(delay) => delay,
+                None => return Ok(resp),
+            };
+            limiter.pause(api, delay);
+            if resp.status().as_u16() != 429 && !idempotent {
+                return Ok(resp);
+            }
+            if limiter.on_limit() == OnLimit::Fail || attempt >= MAX_ATTEMPTS {
+                // raise error
+                return Err(ErrorKind::RateLimited(api.to_string(), delay).into());
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use airtime::AirtimeRecipient;
+    use money::Money;
+    use Error;
+
+    const AIRTIME_SENT: &str = r#"{"numSent":1,"errorMessage":"None","responses":[
+        {"phoneNumber":"+254711XXXYYY","status":"Sent","requestId":"ATQid_1"}]}"#;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(OnLimit::Fail).limit(Api::Sms, RateLimit::per_second(2.0).unwrap())
+    }
+
+    fn send_airtime(url: &str, idempotency_key: Option<&str>) -> Result<usize> {
+        let recipients = [AirtimeRecipient::new(
+            "+254711XXXYYY",
+            Money::whole("KES", 10),
+        )];
+        let gway = ::tests::gateway(url).with_rate_limiter(RateLimiter::new(OnLimit::Wait));
+        let resp = gway.send_airtime(&recipients, None, idempotency_key)?;
+        Ok(resp.responses.len())
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for &rate in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            match RateLimit::per_second(rate) {
+                Err(Error(ErrorKind::InvalidConfig(key, _), _)) => assert_eq!(key, "per_second"),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(RateLimit::per_second(0.5).unwrap().burst(), 1);
+        assert_eq!(RateLimit::per_second(2.5).unwrap().burst(), 3);
+    }
+
+    #[test]
+    fn waits_for_tokens() {
+        let limiter = limiter();
+        let start = Instant::now();
+        assert_eq!(limiter.try_acquire_at(Api::Sms, start), None);
+        assert_eq!(limiter.try_acquire_at(Api::Sms, start), None);
+        assert_eq!(
+            limiter.try_acquire_at(Api::Sms, start),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            limiter.try_acquire_at(Api::Sms, start + Duration::from_millis(500)),
+            None
+        );
+    }
+
+    #[test]
+    fn shares_buckets_between_clones() {
+        let limiter = limiter();
+        let shared = limiter.clone();
+        let start = Instant::now();
+        assert_eq!(limiter.try_acquire_at(Api::Sms, start), None);
+        assert_eq!(shared.try_acquire_at(Api::Sms, start), None);
+        assert!(limiter.try_acquire_at(Api::Sms, start).is_some());
+    }
+
+    #[test]
+    fn leaves_other_apis_unlimited() {
+        let limiter = limiter();
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.try_acquire_at(Api::Voice, start), None);
+        }
+    }
+
+    #[test]
+    fn pauses_api() {
+        let limiter = limiter();
+        let start = Instant::now();
+        limiter
+            .clone()
+            .pause_at(Api::Voice, Duration::from_secs(30), start);
+        assert_eq!(
+            limiter.try_acquire_at(Api::Voice, start + Duration::from_secs(10)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            limiter.try_acquire_at(Api::Voice, start + Duration::from_secs(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn retries_too_many_requests() {
+        let (url, requests) = ::tests::serve_with_headers(&[
+            (429, "Retry-After: 0\r\n", "Too Many Requests"),
+            (201, "", AIRTIME_SENT),
+        ]);
+        assert_eq!(send_airtime(&url, None).unwrap(), 1);
+        assert_eq!(requests.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn fails_when_throttled_too_often() {
+        let (url, requests) = ::tests::serve_with_headers(&[
+            (429, "Retry-After: 0\r\n", "Too Many Requests"),
+            (429, "Retry-After: 0\r\n", "Too Many Requests"),
+            (429, "Retry-After: 0\r\n", "Too Many Requests"),
+        ]);
+        match send_airtime(&url, None) {
+            Err(Error(ErrorKind::RateLimited(api, _), _)) => {
+                assert_eq!(api, Api::Airtime.to_string())
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(requests.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn does_not_resend_unavailable_post() {
+        let (url, requests) = ::tests::serve_with_headers(&[
+            (503, "Retry-After: 0\r\n", "Service Unavailable"),
+            (201, "", AIRTIME_SENT),
+        ]);
+        let err = send_airtime(&url, None).unwrap_err();
+        assert_eq!(err.to_string(), "Service Unavailable");
+        assert_eq!(requests.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn resends_unavailable_post_with_idempotency_key() {
+        let (url, requests) = ::tests::serve_with_headers(&[
+            (503, "Retry-After: 0\r\n", "Service Unavailable"),
+            (201, "", AIRTIME_SENT),
+        ]);
+        assert_eq!(send_airtime(&url, Some("order-42")).unwrap(), 1);
+        let requests: Vec<String> = requests.try_iter().collect();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("order-42"));
+    }
+}
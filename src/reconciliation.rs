@@ -0,0 +1,521 @@
+//! Payment reconciliation.
+//!
+//! Matches the payments initiated through the gateway (mobile checkout, B2C
+//! and B2B requests) against payment notifications and transaction query
+//! results, and reports which transactions matched, went missing, were
+//! notified more than once, didn't succeed or settled for a different
+//! amount.
+//!
+//! ```rust,ignore
+//! let mut rec = Reconciler::new();
+//! rec.add_initiated(InitiatedPayment::new(
+//!     PaymentKind::Checkout, "ATPid_1", "+254711XXXYYY", Money::whole("KES", 100),
+//! ));
+//! rec.add_notification(json::from_str(&callback_body)?);
+//! rec.add_transactions(gway.fetch_product_transactions("My Online Store", &filter)?);
+//!
+//! let report = rec.report();
+//! println!("{}", report.to_csv());
+//! ```
+use std::collections::{HashMap, HashSet};
+
+use json;
+
+use super::Result;
+use money::Money;
+use payments::Transaction;
+
+/// Kind of payment initiated through the gateway
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentKind {
+    /// mobile checkout, see `init_mobile_payment_checkout`
+    Checkout,
+    /// business to consumer payment, see `mobile_payment_b2c_request`
+    B2C,
+    /// business to business payment, see `mobile_payment_b2b_request`
+    B2B,
+}
+
+/// Outcome of reconciling a single transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// initiated and observed once, for the expected amount
+    Matched,
+    /// initiated but never notified nor returned by a transaction query
+    Missing,
+    /// notified more than once
+    Duplicated,
+    /// observed with a status other than `Success`, e.g. `Failed` or
+    /// `InvalidRequest`
+    StatusMismatch,
+    /// observed with a different currency or amount than initiated
+    AmountMismatch,
+    /// observed but never initiated through the reconciler
+    Unexpected,
+}
+
+/// A payment initiated through the gateway
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InitiatedPayment {
+    /// payment kind
+    pub kind: PaymentKind,
+    /// transaction id returned when the payment was initiated
+    pub transaction_id: String,
+    /// phone number or business account the payment was made with
+    pub counterparty: String,
+    /// payment amount
+    pub amount: Money,
+}
+
+impl InitiatedPayment {
+    /// creates a new initiated payment record
+    pub fn new(kind: PaymentKind, transaction_id: &str, counterparty: &str, amount: Money) -> Self {
+        Self {
+            kind,
+            transaction_id: transaction_id.into(),
+            counterparty: counterparty.into(),
+            amount,
+        }
+    }
+
+    /// Builds records from the `entries` returned by `mobile_payment_b2c_request`.
+    ///
+    /// Entries without a transaction id (i.e. rejected recipients) are skipped.
+    pub fn from_b2c_entries(entries: &json::Value) -> Vec<Self> {
+        responses(entries)
+            .into_iter()
+            .filter_map(|entry| {
+                let transaction_id = entry["transactionId"].as_str()?;
+                let amount = entry["value"].as_str()?.parse().ok()?;
+                Some(Self {
+                    kind: PaymentKind::B2C,
+                    transaction_id: transaction_id.into(),
+                    counterparty: entry["phoneNumber"].as_str().unwrap_or_default().into(),
+                    amount,
+                })
+            })
+            .collect()
+    }
+
+    /// Builds records from what `init_mobile_payment_checkout` returned for
+    /// a checkout of `amount` from `phone_number`.
+    ///
+    /// The response doesn't repeat the amount, so it is taken from the
+    /// request. Responses without a transaction id are skipped.
+    pub fn from_checkout_response(
+        response: &json::Value,
+        phone_number: &str,
+        amount: &Money,
+    ) -> Vec<Self> {
+        from_response(PaymentKind::Checkout, response, phone_number, amount)
+    }
+
+    /// Builds records from what `mobile_payment_b2b_request` returned for a
+    /// payment of `amount` to `destination_account`.
+    ///
+    /// The response doesn't repeat the amount, so it is taken from the
+    /// request. Responses without a transaction id are skipped.
+    pub fn from_b2b_response(
+        response: &json::Value,
+        destination_account: &str,
+        amount: &Money,
+    ) -> Vec<Self> {
+        from_response(PaymentKind::B2B, response, destination_account, amount)
+    }
+}
+
+/// Records for the transactions in a checkout or B2B response.
+fn from_response(
+    kind: PaymentKind,
+    response: &json::Value,
+    counterparty: &str,
+    amount: &Money,
+) -> Vec<InitiatedPayment> {
+    responses(response)
+        .into_iter()
+        .filter_map(|entry| entry["transactionId"].as_str())
+        .filter(|id| !id.is_empty())
+        .map(|id| InitiatedPayment::new(kind, id, counterparty, amount.clone()))
+        .collect()
+}
+
+/// The objects in a response, which is either one object or a list of
+/// entries.
+fn responses(value: &json::Value) -> Vec<&json::Value> {
+    match value.as_array() {
+        Some(entries) => entries.iter().collect(),
+        None if value.is_object() => vec![value],
+        None => Vec::new(),
+    }
+}
+
+/// A single line of a reconciliation report
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReconciliationEntry {
+    /// transaction id
+    pub transaction_id: String,
+    /// payment kind, `None` for unexpected transactions
+    pub kind: Option<PaymentKind>,
+    /// reconciliation outcome
+    pub outcome: Outcome,
+    /// phone number or account the payment was made with
+    pub counterparty: Option<String>,
+    /// amount initiated
+    pub expected_amount: Option<Money>,
+    /// amount reported by the API
+    pub observed_amount: Option<Money>,
+    /// last status reported by the API e.g. `Success`, `Failed`
+    pub status: Option<String>,
+    /// number of notifications received
+    pub notifications: usize,
+}
+
+/// Reconciliation report
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReconciliationReport {
+    /// one entry per transaction, initiated transactions first
+    pub entries: Vec<ReconciliationEntry>,
+}
+
+impl ReconciliationReport {
+    /// entries with the given outcome
+    pub fn with_outcome(&self, outcome: Outcome) -> Vec<&ReconciliationEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.outcome == outcome)
+            .collect()
+    }
+
+    /// true if every initiated transaction matched and nothing unexpected was seen
+    pub fn is_clean(&self) -> bool {
+        self.entries.iter().all(|e| e.outcome == Outcome::Matched)
+    }
+
+    /// Exports the report as CSV, with a header row.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from(
+            "transaction_id,kind,outcome,counterparty,currency_code,\
+             expected_amount,observed_amount,status,notifications\n",
+        );
+        for e in &self.entries {
+            let currency = e.expected_amount.as_ref().or(e.observed_amount.as_ref());
+            let amount = |m: &Option<Money>| m.as_ref().map(Money::amount).unwrap_or_default();
+            let row = [
+                e.transaction_id.clone(),
+                e.kind.map(|k| format!("{:?}", k)).unwrap_or_default(),
+                format!("{:?}", e.outcome),
+                e.counterparty.clone().unwrap_or_default(),
+                currency.map(|m| m.currency.clone()).unwrap_or_default(),
+                amount(&e.expected_amount),
+                amount(&e.observed_amount),
+                e.status.clone().unwrap_or_default(),
+                e.notifications.to_string(),
+            ];
+            let row: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+            out.push_str(&row.join(","));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Exports the report as JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(json::to_string_pretty(self)?)
+    }
+}
+
+/// Matches initiated payments against what the API reports
+#[derive(Debug, Default)]
+pub struct Reconciler {
+    initiated: Vec<InitiatedPayment>,
+    notifications: HashMap<String, Vec<Transaction>>,
+    queried: HashMap<String, Transaction>,
+    /// observed transaction ids, in the order first seen
+    seen: Vec<String>,
+}
+
+impl Reconciler {
+    /// creates an empty reconciler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a payment initiated through the gateway.
+    pub fn add_initiated(&mut self, payment: InitiatedPayment) {
+        self.initiated.push(payment);
+    }
+
+    /// Records a payment notification received on the payments callback URL.
+    ///
+    /// Every notification is kept, so the same transaction notified twice is
+    /// reported as `Duplicated`.
+    pub fn add_notification(&mut self, notification: Transaction) {
+        self.observe(&notification.transactionId);
+        self.notifications
+            .entry(notification.transactionId.clone())
+            .or_default()
+            .push(notification);
+    }
+
+    /// Records transactions fetched with `find_transaction`,
+    /// `fetch_product_transactions` or `product_transactions`.
+    ///
+    /// Query results are keyed by transaction id, so fetching overlapping
+    /// pages more than once is harmless.
+    pub fn add_transactions<I: IntoIterator<Item = Transaction>>(&mut self, transactions: I) {
+        for transaction in transactions {
+            self.observe(&transaction.transactionId);
+            self.queried
+                .insert(transaction.transactionId.clone(), transaction);
+        }
+    }
+
+    /// Produces the reconciliation report.
+    pub fn report(&self) -> ReconciliationReport {
+        let mut entries = Vec::new();
+        let mut initiated_ids = HashSet::new();
+
+        for payment in &self.initiated {
+            initiated_ids.insert(payment.transaction_id.as_str());
+            let notifications = self.notifications
+                .get(&payment.transaction_id)
+                .map_or(0, |n| n.len());
+            let latest = self.latest(&payment.transaction_id);
+            let observed = latest.and_then(observed_amount);
+            let status = latest.and_then(|t| t.status.as_ref());
+
+            let outcome = if latest.is_none() {
+                Outcome::Missing
+            } else if notifications > 1 {
+                Outcome::Duplicated
+            } else if status.map(String::as_str) != Some("Success") {
+                Outcome::StatusMismatch
+            } else if observed.as_ref() == Some(&payment.amount) {
+                Outcome::Matched
+            } else {
+                Outcome::AmountMismatch
+            };
+
+            entries.push(ReconciliationEntry {
+                transaction_id: payment.transaction_id.clone(),
+                kind: Some(payment.kind),
+                outcome,
+                counterparty: Some(payment.counterparty.clone()),
+                expected_amount: Some(payment.amount.clone()),
+                observed_amount: observed,
+                status: latest.and_then(|t| t.status.clone()),
+                notifications,
+            });
+        }
+
+        for id in &self.seen {
+            if initiated_ids.contains(&id.as_str()) {
+                continue;
+            }
+            let latest = self.latest(id);
+            entries.push(ReconciliationEntry {
+                transaction_id: id.clone(),
+                kind: None,
+                outcome: Outcome::Unexpected,
+                counterparty: latest.and_then(|t| t.destination.clone()),
+                expected_amount: None,
+                observed_amount: latest.and_then(observed_amount),
+                status: latest.and_then(|t| t.status.clone()),
+                notifications: self.notifications.get(id).map_or(0, |n| n.len()),
+            });
+        }
+
+        ReconciliationReport { entries }
+    }
+
+    fn observe(&mut self, transaction_id: &str) {
+        let seen = self.notifications.contains_key(transaction_id)
+            || self.queried.contains_key(transaction_id);
+        if !seen {
+            self.seen.push(transaction_id.into());
+        }
+    }
+
+    /// Query results are preferred over notifications since they reflect
+    /// the transaction's final state.
+    fn latest(&self, transaction_id: &str) -> Option<&Transaction> {
+        self.queried.get(transaction_id).or_else(|| {
+            self.notifications
+                .get(transaction_id)
+                .and_then(|n| n.last())
+        })
+    }
+}
+
+/// The transaction's value, such as `"KES 1,234.5000"`, if it parses.
+fn observed_amount(transaction: &Transaction) -> Option<Money> {
+    transaction.value.as_ref()?.parse().ok()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(id: &str, value: &str) -> Transaction {
+        Transaction {
+            transactionId: id.into(),
+            value: Some(value.into()),
+            status: Some("Success".into()),
+            ..Default::default()
+        }
+    }
+
+    fn checkout(id: &str) -> InitiatedPayment {
+        InitiatedPayment::new(
+            PaymentKind::Checkout,
+            id,
+            "+254711XXXYYY",
+            Money::whole("KES", 100),
+        )
+    }
+
+    fn outcomes(rec: &Reconciler) -> Vec<Outcome> {
+        rec.report().entries.iter().map(|e| e.outcome).collect()
+    }
+
+    #[test]
+    fn matches_notified_payment() {
+        let mut rec = Reconciler::new();
+        rec.add_initiated(checkout("ATPid_1"));
+        rec.add_notification(transaction("ATPid_1", "KES 100.0000"));
+        assert_eq!(outcomes(&rec), vec![Outcome::Matched]);
+        assert!(rec.report().is_clean());
+    }
+
+    #[test]
+    fn flags_duplicate_notifications() {
+        let mut rec = Reconciler::new();
+        rec.add_initiated(checkout("ATPid_2"));
+        rec.add_notification(transaction("ATPid_2", "KES 100.0000"));
+        rec.add_notification(transaction("ATPid_2", "KES 100.0000"));
+        assert_eq!(outcomes(&rec), vec![Outcome::Duplicated]);
+        assert_eq!(rec.report().entries[0].notifications, 2);
+    }
+
+    #[test]
+    fn flags_amount_mismatch() {
+        let mut rec = Reconciler::new();
+        rec.add_initiated(checkout("ATPid_3"));
+        rec.add_transactions(vec![transaction("ATPid_3", "KES 1,000.0000")]);
+        let report = rec.report();
+        assert_eq!(report.entries[0].outcome, Outcome::AmountMismatch);
+        assert_eq!(
+            report.entries[0].observed_amount,
+            Some(Money::whole("KES", 1000))
+        );
+    }
+
+    #[test]
+    fn flags_unsuccessful_transactions() {
+        let mut rec = Reconciler::new();
+        rec.add_initiated(checkout("ATPid_5"));
+        rec.add_initiated(checkout("ATPid_6"));
+        let failed = Transaction {
+            status: Some("Failed".into()),
+            ..transaction("ATPid_5", "KES 100.0000")
+        };
+        let invalid = Transaction {
+            status: Some("InvalidRequest".into()),
+            ..transaction("ATPid_6", "KES 100.0000")
+        };
+        rec.add_notification(failed);
+        rec.add_transactions(vec![invalid]);
+        assert_eq!(
+            outcomes(&rec),
+            vec![Outcome::StatusMismatch, Outcome::StatusMismatch]
+        );
+        assert!(!rec.report().is_clean());
+    }
+
+    #[test]
+    fn compares_amounts_exactly() {
+        let mut rec = Reconciler::new();
+        rec.add_initiated(checkout("ATPid_7"));
+        rec.add_notification(transaction("ATPid_7", "KES 100.0001"));
+        assert_eq!(outcomes(&rec), vec![Outcome::AmountMismatch]);
+    }
+
+    #[test]
+    fn reports_missing_and_unexpected() {
+        let mut rec = Reconciler::new();
+        rec.add_initiated(checkout("ATPid_4"));
+        rec.add_transactions(vec![transaction("ATPid_9", "KES 5.0000")]);
+        let report = rec.report();
+        assert_eq!(outcomes(&rec), vec![Outcome::Missing, Outcome::Unexpected]);
+        assert_eq!(report.with_outcome(Outcome::Missing)[0].transaction_id, "ATPid_4");
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn parses_b2c_entries() {
+        let entries = json!([
+            {
+                "phoneNumber": "+254711XXXYYY",
+                "status": "Queued",
+                "transactionId": "ATPid_1",
+                "value": "KES 5000.1000"
+            },
+            {
+                "phoneNumber": "+254733YYYZZZ",
+                "status": "InvalidRequest",
+                "errorMessage": "Insufficient Credit"
+            }
+        ]);
+        let payments = InitiatedPayment::from_b2c_entries(&entries);
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].amount, Money::from_units("KES", 50_001_000));
+    }
+
+    #[test]
+    fn parses_checkout_response() {
+        let response = json!({
+            "description": "Waiting for user input",
+            "providerChannel": "525900",
+            "status": "PendingConfirmation",
+            "transactionId": "ATPid_1"
+        });
+        let amount = Money::whole("KES", 100);
+        let payments =
+            InitiatedPayment::from_checkout_response(&response, "+254711XXXYYY", &amount);
+        assert_eq!(payments, vec![checkout("ATPid_1")]);
+
+        let failed = json!({"status": "InvalidRequest", "description": "Invalid phone number"});
+        assert!(InitiatedPayment::from_checkout_response(&failed, "+2547", &amount).is_empty());
+    }
+
+    #[test]
+    fn parses_b2b_response() {
+        let response = json!({
+            "providerChannel": "mychannel",
+            "status": "Queued",
+            "transactionFee": "KES 1.0000",
+            "transactionId": "ATPid_2"
+        });
+        let amount = Money::whole("KES", 2500);
+        let payments = InitiatedPayment::from_b2b_response(&response, "MyAccount", &amount);
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].kind, PaymentKind::B2B);
+        assert_eq!(payments[0].counterparty, "MyAccount");
+        assert_eq!(payments[0].amount, amount);
+    }
+
+    #[test]
+    fn escapes_csv_fields() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
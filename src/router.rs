@@ -0,0 +1,426 @@
+//! Two-way SMS: routes inbound messages to handlers and sends their replies.
+//!
+//! Handlers are tried in the order they were registered; the first whose
+//! route matches handles the message. A handler gets the sender's
+//! conversation, which is saved after it runs, and may return a reply. The
+//! reply is sent from the number the message was sent to; replies to
+//! premium shortcode messages carry the message's `linkId` and the keyword
+//! their route matched, with `bulkSMSMode=0`, so they are billed as
+//! on-demand premium messages.
+//!
+//! ```rust,ignore
+//! let mut router = Router::new(Box::new(MemoryContextStore::default()))
+//!     .keyword("BAL", |_msg, _conversation| Ok(Some("Your balance is KES 200".into())))
+//!     .pattern(r"^\d{4}$", |msg, conversation| {
+//!         conversation.state = Some("verified".into());
+//!         Ok(Some(format!("Code {} accepted", msg.text)))
+//!     })?
+//!     .fallback(|_msg, _conversation| Ok(Some("Reply BAL for your balance".into())));
+//!
+//! // in the incoming message callback handler:
+//! router.handle(&gway, &InboundMessage::from_form(&body)?)?;
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use json;
+use regex::Regex;
+use serde_urlencoded;
+
+use super::{AfricasTalkingGateway, Result, SMSMessage};
+
+/// Inbound Message Struct
+///
+/// Posted as form data to the application's incoming messages callback URL.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct InboundMessage {
+    /// sender's phone number
+    pub from: String,
+
+    /// shortcode or number the message was sent to
+    pub to: String,
+
+    /// message text
+    #[serde(default)]
+    pub text: String,
+
+    /// when the message was received
+    #[serde(default)]
+    pub date: String,
+
+    /// message id
+    #[serde(default)]
+    pub id: String,
+
+    /// link id, sent for messages to premium shortcodes
+    #[serde(default)]
+    pub linkId: Option<String>,
+
+    /// mobile network code of the sender
+    #[serde(default)]
+    pub networkCode: Option<String>,
+}
+
+impl InboundMessage {
+    /// Parses the form encoded body of an incoming message callback.
+    pub fn from_form(body: &str) -> Result<Self> {
+        Ok(serde_urlencoded::from_str(body)?)
+    }
+
+    /// the first word of the message, upper-cased
+    pub fn keyword(&self) -> String {
+        self.text
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase()
+    }
+}
+
+/// State kept for a phone number between messages
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Conversation {
+    /// where the conversation is, e.g. the question last asked
+    pub state: Option<String>,
+
+    /// values collected so far
+    pub data: HashMap<String, String>,
+}
+
+impl Conversation {
+    /// true if the conversation holds no state
+    pub fn is_empty(&self) -> bool {
+        self.state.is_none() && self.data.is_empty()
+    }
+}
+
+/// Storage for conversations, keyed by phone number.
+///
+/// Implement this to keep conversations in a database;
+/// `MemoryContextStore` keeps them in memory.
+pub trait ContextStore: fmt::Debug + Send {
+    /// the conversation with `phone_number`, if any
+    fn get(&self, phone_number: &str) -> Result<Option<Conversation>>;
+
+    /// saves the conversation with `phone_number`
+    fn set(&self, phone_number: &str, conversation: Conversation) -> Result<()>;
+
+    /// forgets the conversation with `phone_number`
+    fn remove(&self, phone_number: &str) -> Result<()>;
+}
+
+/// Keeps conversations in memory
+#[derive(Debug, Default)]
+pub struct MemoryContextStore {
+    conversations: Mutex<HashMap<String, Conversation>>,
+}
+
+impl ContextStore for MemoryContextStore {
+    fn get(&self, phone_number: &str) -> Result<Option<Conversation>> {
+        let conversations = self.conversations.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(conversations.get(phone_number).cloned())
+    }
+
+    fn set(&self, phone_number: &str, conversation: Conversation) -> Result<()> {
+        let mut conversations = self.conversations.lock().unwrap_or_else(|e| e.into_inner());
+        conversations.insert(phone_number.into(), conversation);
+        Ok(())
+    }
+
+    fn remove(&self, phone_number: &str) -> Result<()> {
+        let mut conversations = self.conversations.lock().unwrap_or_else(|e| e.into_inner());
+        conversations.remove(phone_number);
+        Ok(())
+    }
+}
+
+/// Handles an inbound message, optionally returning a reply
+pub type Handler =
+    Box<dyn FnMut(&InboundMessage, &mut Conversation) -> Result<Option<String>> + Send>;
+
+/// Which inbound messages a handler receives
+#[derive(Debug, Clone)]
+pub enum Route {
+    /// messages whose first word is the keyword, ignoring case
+    Keyword(String),
+    /// messages whose text matches the pattern
+    Pattern(Regex),
+    /// messages sent to the shortcode
+    Shortcode(String),
+}
+
+impl Route {
+    /// true if `msg` takes this route
+    pub fn matches(&self, msg: &InboundMessage) -> bool {
+        match *self {
+            Route::Keyword(ref keyword) => msg.keyword() == keyword.to_uppercase(),
+            Route::Pattern(ref pattern) => pattern.is_match(msg.text.trim()),
+            Route::Shortcode(ref code) => msg.to == *code,
+        }
+    }
+}
+
+/// Routes inbound messages to handlers
+pub struct Router {
+    routes: Vec<(Route, Handler)>,
+    fallback: Option<Handler>,
+    store: Box<dyn ContextStore>,
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let routes: Vec<&Route> = self.routes.iter().map(|(route, _)| route).collect();
+        f.debug_struct("Router")
+            .field("routes", &routes)
+            .field("fallback", &self.fallback.is_some())
+            .field("store", &self.store)
+            .finish()
+    }
+}
+
+impl Router {
+    /// creates a router with no handlers, keeping conversations in `store`
+    pub fn new(store: Box<dyn ContextStore>) -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback: None,
+            store,
+        }
+    }
+
+    /// adds a handler for `route`
+    pub fn route<F>(mut self, route: Route, handler: F) -> Self
+    where
+        F: FnMut(&InboundMessage, &mut Conversation) -> Result<Option<String>> + Send + 'static,
+    {
+        self.routes.push((route, Box::new(handler)));
+        self
+    }
+
+    /// adds a handler for messages starting with `keyword`
+    pub fn keyword<F>(self, keyword: &str, handler: F) -> Self
+    where
+        F: FnMut(&InboundMessage, &mut Conversation) -> Result<Option<String>> + Send + 'static,
+    {
+        self.route(Route::Keyword(keyword.into()), handler)
+    }
+
+    /// adds a handler for messages matching the regular expression `pattern`
+    pub fn pattern<F>(self, pattern: &str, handler: F) -> Result<Self>
+    where
+        F: FnMut(&InboundMessage, &mut Conversation) -> Result<Option<String>> + Send + 'static,
+    {
+        Ok(self.route(Route::Pattern(Regex::new(pattern)?), handler))
+    }
+
+    /// adds a handler for messages sent to `shortcode`
+    pub fn shortcode<F>(self, shortcode: &str, handler: F) -> Self
+    where
+        F: FnMut(&InboundMessage, &mut Conversation) -> Result<Option<String>> + Send + 'static,
+    {
+        self.route(Route::Shortcode(shortcode.into()), handler)
+    }
+
+    /// handles messages no other handler takes
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(&InboundMessage, &mut Conversation) -> Result<Option<String>> + Send + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Runs the handler for `msg` with the sender's conversation, saving
+    /// the conversation afterwards, and returns the handler's reply.
+    /// Messages no handler takes are ignored.
+    pub fn dispatch(&mut self, msg: &InboundMessage) -> Result<Option<String>> {
+        Ok(self.dispatch_route(msg)?.0)
+    }
+
+    /// Like `dispatch`, also returning the keyword of the route that took
+    /// the message, if it was a keyword route.
+    fn dispatch_route(&mut self, msg: &InboundMessage) -> Result<(Option<String>, Option<String>)> {
+        let (handler, keyword) = match self
+            .routes
+            .iter_mut()
+            .find(|&&mut (ref route, _)| route.matches(msg))
+        {
+            Some(&mut (ref route, ref mut handler)) => {
+                let keyword = match *route {
+                    Route::Keyword(ref keyword) => Some(keyword.clone()),
+                    _ => None,
+                };
+                (handler, keyword)
+            }
+            None => match self.fallback {
+                Some(ref mut handler) => (handler, None),
+                None => return Ok((None, None)),
+            },
+        };
+        let mut conversation = self.store.get(&msg.from)?.unwrap_or_default();
+        let reply = handler(msg, &mut conversation)?;
+        if conversation.is_empty() {
+            self.store.remove(&msg.from)?;
+        } else {
+            self.store.set(&msg.from, conversation)?;
+        }
+
+        Ok((reply, keyword))
+    }
+
+    /// Dispatches `msg` and sends the handler's reply, if any, returning
+    /// the `send_message` response.
+    pub fn handle(
+        &mut self,
+        gateway: &AfricasTalkingGateway,
+        msg: &InboundMessage,
+    ) -> Result<Option<json::Value>> {
+        match self.dispatch_route(msg)? {
+            (Some(reply), keyword) => {
+                let reply = reply_to(&gateway.username, msg, keyword.as_deref(), &reply);
+                Ok(Some(gateway.send_message(reply)?))
+            }
+            (None, _) => Ok(None),
+        }
+    }
+}
+
+/// A reply to `msg`, from the number it was sent to. Premium messages,
+/// those with a non-blank link id, are answered in on-demand mode: with the
+/// link id, the `keyword` their route matched and `bulkSMSMode=0`.
+fn reply_to(username: &str, msg: &InboundMessage, keyword: Option<&str>, text: &str) -> SMSMessage {
+    let link_id = msg.linkId.clone().filter(|id| !id.trim().is_empty());
+    let (bulk_sms_mode, keyword) = match link_id {
+        Some(_) => (Some(0), keyword.map(String::from)),
+        None => (None, None),
+    };
+    SMSMessage::new(
+        username,
+        &msg.from,
+        text,
+        bulk_sms_mode,
+        Some(msg.to.clone()),
+        None,
+        keyword,
+        link_id,
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inbound(to: &str, text: &str) -> InboundMessage {
+        InboundMessage {
+            from: "+254711000001".into(),
+            to: to.into(),
+            text: text.into(),
+            linkId: Some("SampleLinkId123".into()),
+            ..Default::default()
+        }
+    }
+
+    fn router() -> Router {
+        Router::new(Box::new(MemoryContextStore::default()))
+            .keyword("join", |_, conversation| {
+                conversation.state = Some("name".into());
+                Ok(Some("What is your name?".into()))
+            })
+            .pattern(r"^\d{4}$", |msg, _| Ok(Some(format!("code {}", msg.text))))
+            .unwrap()
+            .shortcode("20880", |_, _| Ok(None))
+            .fallback(|msg, conversation| match conversation.state.take() {
+                Some(_) => Ok(Some(format!("Welcome {}", msg.text))),
+                None => Ok(Some("Send JOIN to register".into())),
+            })
+    }
+
+    fn reply(router: &mut Router, to: &str, text: &str) -> Option<String> {
+        router.dispatch(&inbound(to, text)).unwrap()
+    }
+
+    #[test]
+    fn routes_keywords_ignoring_case() {
+        let mut router = router();
+        let reply = reply(&mut router, "12345", "Join now");
+        assert_eq!(reply.as_deref(), Some("What is your name?"));
+    }
+
+    #[test]
+    fn routes_patterns_and_shortcodes() {
+        let mut router = router();
+        let code = reply(&mut router, "12345", "1234");
+        assert_eq!(code.as_deref(), Some("code 1234"));
+        assert_eq!(reply(&mut router, "20880", "hello"), None);
+    }
+
+    #[test]
+    fn keeps_conversation_state() {
+        let mut router = router();
+        reply(&mut router, "12345", "join");
+        let welcome = reply(&mut router, "12345", "Akinyi");
+        assert_eq!(welcome.as_deref(), Some("Welcome Akinyi"));
+        let fallback = reply(&mut router, "12345", "Akinyi");
+        assert_eq!(fallback.as_deref(), Some("Send JOIN to register"));
+    }
+
+    #[test]
+    fn replies_to_premium_messages_on_demand() {
+        let msg = inbound("12345", "NEWS today");
+        let reply = reply_to("sandbox", &msg, Some("news"), "hello");
+        assert_eq!(reply.from.as_deref(), Some("12345"));
+        assert_eq!(reply.linkId.as_deref(), Some("SampleLinkId123"));
+        assert_eq!(reply.keyword.as_deref(), Some("news"));
+        assert_eq!(reply.bulkSMSMode, Some(0));
+    }
+
+    #[test]
+    fn replies_to_standard_messages_without_premium_fields() {
+        let msg = InboundMessage {
+            linkId: None,
+            ..inbound("12345", "news today")
+        };
+        let reply = reply_to("sandbox", &msg, Some("news"), "hello");
+        assert_eq!(reply.keyword, None);
+        assert_eq!(reply.bulkSMSMode, None);
+
+        let blank = InboundMessage {
+            linkId: Some(" ".into()),
+            ..inbound("12345", "news today")
+        };
+        let reply = reply_to("sandbox", &blank, Some("news"), "hello");
+        assert_eq!(reply.linkId, None);
+        assert_eq!(reply.keyword, None);
+        assert_eq!(reply.bulkSMSMode, None);
+    }
+
+    #[test]
+    fn handle_sends_reply_with_link_id() {
+        let response = r#"{"SMSMessageData":{"Message":"Sent to 1/1","Recipients":[]}}"#;
+        let (url, requests) = ::tests::serve(&[(201, response)]);
+        let mut router = router();
+
+        let sent = router.handle(&::tests::gateway(&url), &inbound("12345", "JOIN now"));
+        assert!(sent.unwrap().is_some());
+        let request = requests.recv().unwrap();
+        assert!(request.contains("linkId=SampleLinkId123"));
+        assert!(request.contains("bulkSMSMode=0"));
+        assert!(request.contains("keyword=join"));
+    }
+
+    #[test]
+    fn handle_sends_no_keyword_for_other_routes() {
+        let response = r#"{"SMSMessageData":{"Message":"Sent to 1/1","Recipients":[]}}"#;
+        let (url, requests) = ::tests::serve(&[(201, response)]);
+        let mut router = router();
+
+        let sent = router.handle(&::tests::gateway(&url), &inbound("12345", "1234"));
+        assert!(sent.unwrap().is_some());
+        let request = requests.recv().unwrap();
+        assert!(request.contains("linkId=SampleLinkId123"));
+        assert!(!request.contains("keyword="));
+    }
+}
@@ -0,0 +1,236 @@
+//! Keeping credentials and personal data out of logs.
+//!
+//! `SecretString` holds the API key and auth tokens: it prints as
+//! `[REDACTED]` and its memory is zeroed when it is dropped. `redact` masks
+//! phone numbers, card numbers and credential fields in request and
+//! response bodies before they are logged or put in error messages.
+//!
+//! ```rust,ignore
+//! let key = SecretString::new("atsk_0123456789");
+//! assert_eq!(format!("{:?}", key), "SecretString([REDACTED])");
+//!
+//! assert_eq!(
+//!     redact(r#"{"phoneNumber":"+254711000123","cardNumber":"4111111111111111"}"#),
+//!     r#"{"phoneNumber":"+*********123","cardNumber":"[REDACTED]"}"#
+//! );
+//! ```
+use std::fmt;
+use std::sync::OnceLock;
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Deserializer};
+use zeroize::Zeroize;
+
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+/// Body fields whose values are always hidden, compared ignoring case. Keys
+/// ending in one of these, such as `checkoutToken`, are hidden too.
+const SECRET_FIELDS: &[&str] = &[
+    "apikey",
+    "authtoken",
+    "token",
+    "password",
+    "pin",
+    "cardnumber",
+    "cvvnumber",
+    "cvv",
+    "expirymonth",
+    "expiryyear",
+];
+
+/// A string that is never printed and is zeroed on drop
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// wraps `secret`
+    pub fn new(secret: &str) -> Self {
+        SecretString(secret.into())
+    }
+
+    /// the secret itself; keep the borrow short and don't log it
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// true if the secret is empty or whitespace
+    pub fn is_blank(&self) -> bool {
+        self.0.trim().is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString(secret)
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretString({})", REDACTED)
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretString)
+    }
+}
+
+/// Patterns used by `redact`, compiled once
+struct Patterns {
+    fields: Regex,
+    cards: Regex,
+    phones: Regex,
+}
+
+fn patterns() -> &'static Patterns {
+    static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+    PATTERNS.get_or_init(|| Patterns {
+        // a key starts the body or follows a quote, `&`, `?` or separator
+        fields: Regex::new(&format!(
+            r#"(?i)((?:^|["&?{{,\s])\w*(?:{})"?\s*[:=]\s*"?)([^"&,}}\s]*)"#,
+            SECRET_FIELDS.join("|")
+        ))
+        .unwrap(),
+        cards: Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap(),
+        phones: Regex::new(r"\+?\b\d{9,15}\b").unwrap(),
+    })
+}
+
+/// Masks personal and credential data in a JSON or form encoded body.
+///
+/// Values of credential and card fields (`apiKey`, `cardNumber`,
+/// `cvvNumber`, ...) are replaced with `[REDACTED]`, card numbers anywhere
+/// keep only their last 4 digits and phone numbers their last 3.
+pub fn redact(body: &str) -> String {
+    let patterns = patterns();
+    let body = patterns.fields.replace_all(body, |caps: &Captures| {
+        if caps[2].is_empty() {
+            caps[0].to_string()
+        } else {
+            format!("{}{}", &caps[1], REDACTED)
+        }
+    });
+    let body = patterns.cards.replace_all(&body, |caps: &Captures| {
+        let digits: Vec<u32> = caps[0].chars().filter_map(|c| c.to_digit(10)).collect();
+        if luhn(&digits) {
+            mask(&caps[0], 4)
+        } else {
+            caps[0].to_string()
+        }
+    });
+    let body = patterns
+        .phones
+        .replace_all(&body, |caps: &Captures| mask(&caps[0], 3));
+
+    body.into_owned()
+}
+
+/// Replaces every digit but the last `keep` with `*`.
+fn mask(text: &str, keep: usize) -> String {
+    let total = text.chars().filter(char::is_ascii_digit).count();
+    let mut seen = 0;
+    text.chars()
+        .map(|c| {
+            if !c.is_ascii_digit() {
+                return c;
+            }
+            seen += 1;
+            if seen + keep > total {
+                c
+            } else {
+                '*'
+            }
+        })
+        .collect()
+}
+
+/// Luhn checksum, which every card number passes.
+fn luhn(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2, d * 2) {
+            (0, _) => d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hides_secrets() {
+        let key = SecretString::new("atsk_0123456789");
+        assert_eq!(format!("{:?}", key), "SecretString([REDACTED])");
+        assert_eq!(key.to_string(), "[REDACTED]");
+        assert_eq!(key.expose(), "atsk_0123456789");
+    }
+
+    #[test]
+    fn masks_phone_and_card_numbers_in_json() {
+        assert_eq!(
+            redact(
+                r#"{"phoneNumber":"+254711000123","cardNumber":"4111111111111111","amount":100}"#
+            ),
+            r#"{"phoneNumber":"+*********123","cardNumber":"[REDACTED]","amount":100}"#
+        );
+    }
+
+    #[test]
+    fn redacts_form_fields() {
+        assert_eq!(
+            redact("username=sandbox&to=254711000123&apiKey=atsk_01"),
+            "username=sandbox&to=*********123&apiKey=[REDACTED]"
+        );
+        let query = redact("?token=abc&pin=1234");
+        assert_eq!(query, "?token=[REDACTED]&pin=[REDACTED]");
+    }
+
+    #[test]
+    fn redacts_camel_case_keys() {
+        assert_eq!(
+            redact(r#"{"checkoutToken":"CkTkn_01","authToken":"ATtkn_01"}"#),
+            r#"{"checkoutToken":"[REDACTED]","authToken":"[REDACTED]"}"#
+        );
+        assert_eq!(
+            redact("username=sandbox&checkoutToken=CkTkn_01"),
+            "username=sandbox&checkoutToken=[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn masks_card_numbers_in_text() {
+        assert_eq!(
+            redact("card 4111 1111 1111 1111 declined"),
+            "card **** **** **** 1111 declined"
+        );
+    }
+
+    #[test]
+    fn keeps_other_values() {
+        assert_eq!(
+            redact(r#"{"transactionId":"ATPid_1234","value":"KES 1000.00"}"#),
+            r#"{"transactionId":"ATPid_1234","value":"KES 1000.00"}"#
+        );
+        assert_eq!(redact(r#"{"tokens":"3"}"#), r#"{"tokens":"3"}"#);
+    }
+}
@@ -0,0 +1,374 @@
+//! Sender IDs and shortcodes, routed by the recipient's country.
+//!
+//! Once a registry is set with `with_sender_registry`, messages sent with
+//! no `from` go out from the sender registered for each recipient's
+//! country, split into one request per sender.
+//!
+//! ```rust,ignore
+//! let senders = SenderRegistry::new()
+//!     .sender(Country::Kenya, Sender::alphanumeric("ACME")?)
+//!     .sender(Country::Nigeria, Sender::shortcode("32145")?);
+//! let gway = AfricasTalkingGateway::new(&username, &apikey, "sandbox")
+//!     .with_sender_registry(senders);
+//! gway.send_bulk_message(&["+254711000001", "+2348030000001"], "Hello", None)?;
+//! ```
+use std::collections::HashMap;
+
+use json;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+
+/// Longest alphanumeric sender id networks accept.
+pub const MAX_ALPHANUMERIC_LEN: usize = 11;
+
+/// Countries messages are routed for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Country {
+    /// Kenya, `+254`
+    Kenya,
+    /// Uganda, `+256`
+    Uganda,
+    /// Tanzania, `+255`
+    Tanzania,
+    /// Nigeria, `+234`
+    Nigeria,
+}
+
+impl Country {
+    /// every supported country
+    pub const ALL: [Country; 4] = [
+        Country::Kenya,
+        Country::Uganda,
+        Country::Tanzania,
+        Country::Nigeria,
+    ];
+
+    /// international dialing code, without the `+`
+    pub fn dialing_code(self) -> &'static str {
+        match self {
+            Country::Kenya => "254",
+            Country::Uganda => "256",
+            Country::Tanzania => "255",
+            Country::Nigeria => "234",
+        }
+    }
+
+    /// 2-letter ISO country code
+    pub fn iso_code(self) -> &'static str {
+        match self {
+            Country::Kenya => "KE",
+            Country::Uganda => "UG",
+            Country::Tanzania => "TZ",
+            Country::Nigeria => "NG",
+        }
+    }
+
+    /// Country of a phone number in international format, e.g. `+254711...`.
+    pub fn from_phone_number(phone_number: &str) -> Option<Self> {
+        let digits = phone_number.trim().trim_start_matches('+');
+        Country::ALL
+            .iter()
+            .cloned()
+            .find(|c| digits.starts_with(c.dialing_code()))
+    }
+}
+
+/// Kind of sender
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SenderKind {
+    /// alphanumeric sender id e.g. `ACME`
+    Alphanumeric,
+    /// numeric shortcode e.g. `20880`
+    Shortcode,
+}
+
+/// An approved sender, built with `Sender::alphanumeric` or
+/// `Sender::shortcode` so it is always valid
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Sender {
+    kind: SenderKind,
+    id: String,
+}
+
+impl Sender {
+    /// Validates an alphanumeric sender id: 1 to 11 letters, digits or
+    /// spaces.
+    pub fn alphanumeric(id: &str) -> Result<Self> {
+        let valid_chars = id.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ');
+        if id.trim().is_empty() || !valid_chars {
+            return Err(invalid(id, "must be letters, digits or spaces"));
+        }
+        if id.chars().count() > MAX_ALPHANUMERIC_LEN {
+            return Err(invalid(
+                id,
+                &format!("must be at most {} characters", MAX_ALPHANUMERIC_LEN),
+            ));
+        }
+
+        Ok(Sender {
+            kind: SenderKind::Alphanumeric,
+            id: id.into(),
+        })
+    }
+
+    /// Validates a shortcode: digits only.
+    pub fn shortcode(code: &str) -> Result<Self> {
+        if code.is_empty() || !code.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid(code, "shortcodes must be digits only"));
+        }
+
+        Ok(Sender {
+            kind: SenderKind::Shortcode,
+            id: code.into(),
+        })
+    }
+
+    /// whether this is a sender id or a shortcode
+    pub fn kind(&self) -> SenderKind {
+        self.kind
+    }
+
+    /// the value sent as the message's `from`
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+}
+
+fn invalid(sender: &str, reason: &str) -> super::Error {
+    ErrorKind::InvalidSender(sender.into(), reason.into()).into()
+}
+
+/// Recipients that share a sender
+#[derive(Debug, Clone, PartialEq)]
+pub struct SenderBatch {
+    /// the sender to use
+    pub sender: Sender,
+
+    /// recipients' phone numbers
+    pub recipients: Vec<String>,
+}
+
+/// Approved senders per country
+#[derive(Debug, Clone, Default)]
+pub struct SenderRegistry {
+    senders: HashMap<Country, Sender>,
+    fallback: Option<Sender>,
+}
+
+impl SenderRegistry {
+    /// creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// uses `sender` for recipients in `country`
+    pub fn sender(mut self, country: Country, sender: Sender) -> Self {
+        self.senders.insert(country, sender);
+        self
+    }
+
+    /// uses `sender` for recipients in countries with no sender registered
+    pub fn fallback(mut self, sender: Sender) -> Self {
+        self.fallback = Some(sender);
+        self
+    }
+
+    /// The sender for a recipient, `None` if their country has no sender
+    /// and there is no fallback.
+    pub fn sender_for(&self, phone_number: &str) -> Option<&Sender> {
+        Country::from_phone_number(phone_number)
+            .and_then(|c| self.senders.get(&c))
+            .or(self.fallback.as_ref())
+    }
+
+    /// Splits recipients into one batch per sender, in order of each
+    /// sender's first recipient. Fails with `ErrorKind::UnroutableNumber`
+    /// if any recipient has no sender.
+    pub fn batches(&self, phone_numbers: &[&str]) -> Result<Vec<SenderBatch>> {
+        let mut batches: Vec<SenderBatch> = Vec::new();
+        for &number in phone_numbers {
+            let sender = self
+                .sender_for(number)
+                .ok_or_else(|| ErrorKind::UnroutableNumber(number.into()))?;
+            match batches.iter_mut().position(|b| &b.sender == sender) {
+                Some(i) => batches[i].recipients.push(number.into()),
+                None => batches.push(SenderBatch {
+                    sender: sender.clone(),
+                    recipients: vec![number.into()],
+                }),
+            }
+        }
+
+        Ok(batches)
+    }
+}
+
+/// Merges the responses of per-sender sends into one response.
+pub(crate) fn merge_responses(responses: Vec<json::Value>) -> json::Value {
+    let mut messages = Vec::new();
+    let mut recipients = Vec::new();
+    for mut response in responses {
+        let data = response["SMSMessageData"].take();
+        if let Some(message) = data["Message"].as_str() {
+            messages.push(message.to_string());
+        }
+        if let json::Value::Array(entries) = data["Recipients"].clone() {
+            recipients.extend(entries);
+        }
+    }
+    json!({
+        "SMSMessageData": {
+            "Message": messages.join("; "),
+            "Recipients": recipients
+        }
+    })
+}
+
+impl AfricasTalkingGateway {
+    /// Picks each message's sender from `registry` when no `from` is given.
+    pub fn with_sender_registry(mut self, registry: SenderRegistry) -> Self {
+        self.senders = Some(registry);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    #[test]
+    fn validates_senders() {
+        assert!(Sender::alphanumeric("ACME Ltd").is_ok());
+        assert!(Sender::alphanumeric("ACMECORPORATION").is_err());
+        assert!(Sender::alphanumeric("ACME-LTD").is_err());
+        assert!(Sender::alphanumeric("").is_err());
+        assert!(Sender::shortcode("20880").is_ok());
+        assert!(Sender::shortcode("2088O").is_err());
+        assert_eq!(
+            Sender::alphanumeric("20880").unwrap().kind(),
+            SenderKind::Alphanumeric
+        );
+        assert_eq!(
+            Sender::shortcode("20880").unwrap().kind(),
+            SenderKind::Shortcode
+        );
+    }
+
+    #[test]
+    fn splits_recipients_per_sender() {
+        let acme = Sender::alphanumeric("ACME").unwrap();
+        let short = Sender::shortcode("32145").unwrap();
+        let registry = SenderRegistry::new()
+            .sender(Country::Kenya, acme.clone())
+            .sender(Country::Uganda, acme.clone())
+            .sender(Country::Nigeria, short.clone());
+
+        let batches = registry
+            .batches(&[
+                "+2348030000001",
+                "+254711000001",
+                "256700000001",
+                "+2348030000002",
+            ])
+            .unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].sender, short);
+        assert_eq!(
+            batches[0].recipients,
+            vec!["+2348030000001", "+2348030000002"]
+        );
+        assert_eq!(batches[1].sender, acme);
+        assert_eq!(batches[1].recipients, vec!["+254711000001", "256700000001"]);
+    }
+
+    #[test]
+    fn rejects_number_without_sender() {
+        let acme = Sender::alphanumeric("ACME").unwrap();
+        let registry = SenderRegistry::new().sender(Country::Kenya, acme);
+        assert!(registry.batches(&["+255700000001"]).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_default_sender() {
+        let acme = Sender::alphanumeric("ACME").unwrap();
+        let registry = SenderRegistry::new().fallback(acme.clone());
+        assert_eq!(registry.sender_for("+255700000001"), Some(&acme));
+        let batches = registry.batches(&["+255700000001"]).unwrap();
+        assert_eq!(batches[0].sender, acme);
+    }
+
+    fn sent(number: &str) -> String {
+        json!({
+            "SMSMessageData": {
+                "Message": "Sent to 1/1 Total Cost: KES 0.8000",
+                "Recipients": [{
+                    "number": number,
+                    "status": "Success",
+                    "statusCode": 101,
+                    "messageId": "ATXid_1",
+                    "cost": "KES 0.8000"
+                }]
+            }
+        })
+        .to_string()
+    }
+
+    fn registry() -> SenderRegistry {
+        SenderRegistry::new()
+            .sender(Country::Kenya, Sender::alphanumeric("ACME").unwrap())
+            .sender(Country::Nigeria, Sender::shortcode("32145").unwrap())
+    }
+
+    #[test]
+    fn sends_one_request_per_sender() {
+        let (first, second) = (sent("+254711000001"), sent("+2348030000001"));
+        let (url, requests) = ::tests::serve(&[(201, &first), (201, &second)]);
+        let gway = ::tests::gateway(&url).with_sender_registry(registry());
+
+        let response = gway
+            .send_bulk_message(&["+254711000001", "+2348030000001"], "Hi", None)
+            .unwrap();
+        let recipients = &response["SMSMessageData"]["Recipients"];
+        assert_eq!(recipients.as_array().unwrap().len(), 2);
+        assert!(requests.recv().unwrap().contains("from=ACME"));
+        assert!(requests.recv().unwrap().contains("from=32145"));
+    }
+
+    #[test]
+    fn ignores_empty_recipients() {
+        let response = sent("+254711000001");
+        let (url, requests) = ::tests::serve(&[(201, &response)]);
+        let gway = ::tests::gateway(&url).with_sender_registry(registry());
+
+        let to = ["+254711000001", " "];
+        assert!(gway.send_bulk_message(&to, "Hi", None).is_ok());
+        assert!(requests.recv().unwrap().contains("to=%2B254711000001&"));
+    }
+
+    #[test]
+    fn returns_sent_responses_when_a_later_batch_fails() {
+        let response = sent("+254711000001");
+        let (url, _) = ::tests::serve(&[(201, &response), (500, "Internal Server Error")]);
+        let gway = ::tests::gateway(&url).with_sender_registry(registry());
+
+        match gway.send_bulk_message(&["+254711000001", "+2348030000001"], "Hi", None) {
+            Err(Error(ErrorKind::PartiallySent(responses), _)) => {
+                assert_eq!(responses.len(), 1);
+                assert_eq!(responses[0].to_string(), response);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn first_batch_error_is_returned_unchanged() {
+        let (url, _) = ::tests::serve(&[(500, "Internal Server Error")]);
+        let gway = ::tests::gateway(&url).with_sender_registry(registry());
+
+        match gway.send_bulk_message(&["+254711000001", "+2348030000001"], "Hi", None) {
+            Err(Error(ErrorKind::PartiallySent(_), _)) | Ok(_) => panic!("expected a send error"),
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Premium SMS subscriptions: typed results, paging and syncing.
+use std::collections::{HashSet, VecDeque};
+
+use reqwest;
+
+use super::{AfricasTalkingGateway, Error, ErrorKind, Result};
+use secret::redact;
+
+/// Subscription Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[allow(non_snake_case)]
+pub struct Subscription {
+    /// subscription id, used as `lastReceivedId` when paging
+    pub id: i64,
+
+    /// subscriber's phone number
+    pub phoneNumber: String,
+
+    /// date the subscription was created
+    #[serde(default)]
+    pub date: String,
+}
+
+/// Subscription Response Struct
+///
+/// Returned when creating or deleting a subscription.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct SubscriptionResponse {
+    /// request status
+    pub status: String,
+
+    /// detailed description of the request status
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Checks the status of a create or delete subscription request.
+pub(crate) fn subscription_response(resp: &mut reqwest::Response) -> Result<SubscriptionResponse> {
+    let code = resp.status().as_u16();
+    if code == 200 || code == 201 {
+        let sub: SubscriptionResponse = resp.json()?;
+        if sub.status == "Success" {
+            Ok(sub)
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(sub.description).into())
+        }
+    } else {
+        // raise error
+        Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+    }
+}
+
+/// Iterator over every subscription to a short code and keyword.
+///
+/// Pages are fetched lazily using the id of the last subscription seen as
+/// `lastReceivedId`; iteration stops after an empty page or an error.
+#[derive(Debug)]
+pub struct Subscriptions<'a> {
+    gateway: &'a AfricasTalkingGateway,
+    short_code: String,
+    keyword: String,
+    last_received_id: i64,
+    buffer: VecDeque<Subscription>,
+    done: bool,
+}
+
+impl<'a> Iterator for Subscriptions<'a> {
+    type Item = Result<Subscription>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(sub) = self.buffer.pop_front() {
+                self.last_received_id = sub.id;
+                return Some(Ok(sub));
+            }
+            if self.done {
+                return None;
+            }
+            match self.gateway.fetch_subscriptions(
+                &self.short_code,
+                &self.keyword,
+                self.last_received_id,
+            ) {
+                Ok(ref page) if page.is_empty() => self.done = true,
+                Ok(page) => self.buffer.extend(page),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Differences between a local subscriber list and the remote one
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SubscriptionDiff {
+    /// local phone numbers with no remote subscription
+    pub to_create: Vec<String>,
+
+    /// remote subscriptions with no local phone number
+    pub to_delete: Vec<String>,
+}
+
+impl SubscriptionDiff {
+    /// Compares local phone numbers against remote subscriptions.
+    pub fn new(local: &[String], remote: &[Subscription]) -> Self {
+        let local_set: HashSet<&str> = local.iter().map(|p| p.trim()).collect();
+        let remote_set: HashSet<&str> = remote.iter().map(|s| s.phoneNumber.trim()).collect();
+        let mut diff = Self::default();
+        let mut queued = HashSet::new();
+
+        for phone in local.iter().map(|p| p.trim()) {
+            if !remote_set.contains(phone) && queued.insert(phone) {
+                diff.to_create.push(phone.to_string());
+            }
+        }
+        for phone in remote.iter().map(|s| s.phoneNumber.trim()) {
+            if !local_set.contains(phone) && queued.insert(phone) {
+                diff.to_delete.push(phone.to_string());
+            }
+        }
+        diff
+    }
+
+    /// true if local and remote lists agree
+    pub fn is_empty(&self) -> bool {
+        self.to_create.is_empty() && self.to_delete.is_empty()
+    }
+}
+
+/// Outcome of syncing subscriptions
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// phone numbers subscribed
+    pub created: Vec<String>,
+
+    /// phone numbers unsubscribed
+    pub deleted: Vec<String>,
+
+    /// phone numbers that could not be synced, with the error
+    pub failed: Vec<(String, Error)>,
+}
+
+impl AfricasTalkingGateway {
+    /// Returns an iterator over every subscription to a short code and
+    /// keyword, starting after `last_received_id` (0 for all).
+    pub fn subscriptions<'a>(
+        &'a self,
+        short_code: &str,
+        keyword: &str,
+        last_received_id: i64,
+    ) -> Subscriptions<'a> {
+        Subscriptions {
+            gateway: self,
+            short_code: short_code.into(),
+            keyword: keyword.into(),
+            last_received_id,
+            buffer: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Makes the remote subscriptions to a short code and keyword match
+    /// `local`, creating missing subscriptions and deleting extra ones.
+    ///
+    /// Failures for individual numbers are collected in the report rather
+    /// than aborting the sync; only a failure to fetch the remote list is
+    /// returned as an error.
+    pub fn sync_subscriptions(
+        &self,
+        short_code: &str,
+        keyword: &str,
+        local: &[String],
+    ) -> Result<SyncReport> {
+        let remote = self
+            .subscriptions(short_code, keyword, 0)
+            .collect::<Result<Vec<Subscription>>>()?;
+        let diff = SubscriptionDiff::new(local, &remote);
+        let mut report = SyncReport::default();
+
+        for phone in diff.to_create {
+            match self.create_subscription(&phone, short_code, keyword, None) {
+                Ok(_) => report.created.push(phone),
+                Err(e) => report.failed.push((phone, e)),
+            }
+        }
+        for phone in diff.to_delete {
+            match self.delete_subscription(&phone, short_code, keyword) {
+                Ok(_) => report.deleted.push(phone),
+                Err(e) => report.failed.push((phone, e)),
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub(id: i64, phone: &str) -> Subscription {
+        Subscription {
+            id,
+            phoneNumber: phone.into(),
+            date: "2018-04-01 10:00:00".into(),
+        }
+    }
+
+    const PAGE: &str = r#"{"responses":[
+        {"id":1,"phoneNumber":"+254711000002","date":"2018-04-01 10:00:00"},
+        {"id":2,"phoneNumber":"+254711000003","date":"2018-04-01 10:00:00"},
+        {"id":3,"phoneNumber":"+254711000004","date":"2018-04-01 10:00:00"}]}"#;
+
+    const EMPTY_PAGE: &str = r#"{"responses":[]}"#;
+
+    #[test]
+    fn pages_by_last_received_id() {
+        let (url, requests) = ::tests::serve(&[(200, PAGE), (200, EMPTY_PAGE)]);
+        let gway = ::tests::gateway(&url);
+        let subs = gway
+            .subscriptions("12345", "news", 0)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(subs.len(), 3);
+        assert!(requests.recv().unwrap().contains("lastReceivedId=0"));
+        assert!(requests.recv().unwrap().contains("lastReceivedId=3"));
+    }
+
+    #[test]
+    fn paging_stops_after_error_response() {
+        let (url, _) = ::tests::serve(&[(500, "Internal Server Error")]);
+        let gway = ::tests::gateway(&url);
+        let mut subs = gway.subscriptions("12345", "news", 0);
+        assert_eq!(
+            subs.next().unwrap().unwrap_err().to_string(),
+            "Internal Server Error"
+        );
+        assert!(subs.next().is_none());
+    }
+
+    #[test]
+    fn sync_reports_failed_numbers() {
+        let (url, requests) = ::tests::serve(&[
+            (200, PAGE),
+            (200, EMPTY_PAGE),
+            (200, r#"{"status":"Success","description":"Succeeded"}"#),
+            (
+                200,
+                r#"{"status":"Failed","description":"Subscription not found"}"#,
+            ),
+        ]);
+        let local = vec!["+254711000002".to_string()];
+        let report = ::tests::gateway(&url)
+            .sync_subscriptions("12345", "news", &local)
+            .unwrap();
+        assert_eq!(report.deleted, vec!["+254711000003".to_string()]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "+254711000004");
+        assert_eq!(report.failed[0].1.to_string(), "Subscription not found");
+        assert!(requests.iter().nth(2).unwrap().contains("/delete"));
+    }
+
+    #[test]
+    fn sync_fails_when_remote_list_unavailable() {
+        let (url, requests) = ::tests::serve(&[(401, "The supplied authentication is invalid")]);
+        let local = vec!["+254711000001".to_string()];
+        let err = ::tests::gateway(&url)
+            .sync_subscriptions("12345", "news", &local)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "The supplied authentication is invalid");
+        assert_eq!(requests.iter().count(), 1);
+    }
+
+    #[test]
+    fn diffs_local_against_remote() {
+        let local = vec![
+            "+254711000001".to_string(),
+            "+254711000002".to_string(),
+            "+254711000002".to_string(),
+        ];
+        let remote = vec![sub(1, "+254711000002"), sub(2, "+254711000003")];
+        let diff = SubscriptionDiff::new(&local, &remote);
+        assert_eq!(diff.to_create, vec!["+254711000001".to_string()]);
+        assert_eq!(diff.to_delete, vec!["+254711000003".to_string()]);
+        assert!(!diff.is_empty());
+    }
+}
@@ -0,0 +1,338 @@
+//! SMS message templates with per-recipient variables.
+//!
+//! Every recipient is rendered before anything is sent, so a missing
+//! variable fails the whole send. Recipients whose messages render to the
+//! same text share a single bulk request.
+//!
+//! ```rust,ignore
+//! let template = Template::parse("Hi {name}, your balance is {amount}")?;
+//! let recipients = vec![
+//!     TemplateRecipient::new("+254711000001").var("name", "Wanjiru").var("amount", "KES 200"),
+//!     TemplateRecipient::new("+254711000002").var("name", "Otieno").var("amount", "KES 50"),
+//! ];
+//! for sent in gway.send_template(&template, &recipients, None)? {
+//!     println!("{} segment(s) to {:?}", sent.batch.segments, sent.batch.recipients);
+//! }
+//! ```
+use std::collections::HashMap;
+
+use json;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result, ResultExt};
+
+/// Characters of the GSM 7-bit default alphabet.
+const GSM_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+                         ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+
+/// Characters of the GSM 7-bit extension table, sent as two characters.
+const GSM_EXTENDED: &str = "^{}\\[~]|€\u{c}";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Variable(String),
+}
+
+/// A message template with `{name}` placeholders; `{{` and `}}` are literal
+/// braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses a template, failing on unclosed or empty placeholders.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    let name = name.trim();
+                    if !closed || name.is_empty() || name.contains('{') {
+                        return Err(invalid(text));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Text(literal.split_off(0)));
+                    }
+                    parts.push(Part::Variable(name.into()));
+                }
+                '}' => return Err(invalid(text)),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// names of the template's placeholders, in order of first use
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for part in &self.parts {
+            if let Part::Variable(ref name) = *part {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Renders the template for one recipient.
+    pub fn render(&self, recipient: &TemplateRecipient) -> Result<String> {
+        let mut text = String::new();
+        for part in &self.parts {
+            match *part {
+                Part::Text(ref literal) => text.push_str(literal),
+                Part::Variable(ref name) => match recipient.variables.get(name) {
+                    Some(value) => text.push_str(value),
+                    None => {
+                        return Err(ErrorKind::MissingTemplateVariable(
+                            recipient.phone_number.clone(),
+                            name.clone(),
+                        )
+                        .into())
+                    }
+                },
+            }
+        }
+
+        Ok(text)
+    }
+
+    /// Renders every recipient, grouping those with identical text in
+    /// order of first appearance. Fails on the first missing variable.
+    pub fn render_all(&self, recipients: &[TemplateRecipient]) -> Result<Vec<RenderedBatch>> {
+        let mut batches: Vec<RenderedBatch> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for recipient in recipients {
+            let text = self.render(recipient)?;
+            match index.get(&text) {
+                Some(&i) => batches[i].recipients.push(recipient.phone_number.clone()),
+                None => {
+                    index.insert(text.clone(), batches.len());
+                    batches.push(RenderedBatch {
+                        segments: segments(&text),
+                        text,
+                        recipients: vec![recipient.phone_number.clone()],
+                    });
+                }
+            }
+        }
+
+        Ok(batches)
+    }
+}
+
+fn invalid(text: &str) -> super::Error {
+    ErrorKind::InvalidTemplate(text.into()).into()
+}
+
+/// A recipient and the values for their template variables
+#[derive(Debug, Clone, Default)]
+pub struct TemplateRecipient {
+    /// recipient's phone number
+    pub phone_number: String,
+
+    /// variable values, by name
+    pub variables: HashMap<String, String>,
+}
+
+impl TemplateRecipient {
+    /// creates a recipient with no variables
+    pub fn new(phone_number: &str) -> Self {
+        Self {
+            phone_number: phone_number.into(),
+            variables: HashMap::new(),
+        }
+    }
+
+    /// sets a variable
+    pub fn var(mut self, name: &str, value: &str) -> Self {
+        self.variables.insert(name.into(), value.into());
+        self
+    }
+}
+
+/// Recipients that share the same rendered text
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedBatch {
+    /// the rendered message
+    pub text: String,
+
+    /// recipients' phone numbers
+    pub recipients: Vec<String>,
+
+    /// SMS segments each recipient is sent
+    pub segments: usize,
+}
+
+/// A rendered batch and the response to sending it
+#[derive(Debug, Clone)]
+pub struct TemplateSend {
+    /// the batch sent
+    pub batch: RenderedBatch,
+
+    /// the `send_message` response
+    pub response: json::Value,
+}
+
+/// Number of SMS segments needed to send `text`.
+///
+/// Text in the GSM 7-bit alphabet fits 160 characters in one segment or
+/// 153 per segment when split; anything else is sent as UCS-2, 70
+/// characters in one segment or 67 per segment.
+pub fn segments(text: &str) -> usize {
+    let gsm_len = text.chars().try_fold(0, |len, c| {
+        if GSM_BASIC.contains(c) {
+            Some(len + 1)
+        } else if GSM_EXTENDED.contains(c) {
+            Some(len + 2)
+        } else {
+            None
+        }
+    });
+    let (len, single, multi) = match gsm_len {
+        Some(len) => (len, 160, 153),
+        None => (text.encode_utf16().count(), 70, 67),
+    };
+    if len <= single {
+        1
+    } else {
+        (len + multi - 1) / multi
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Renders `template` for every recipient and sends each distinct text
+    /// in one bulk request. Nothing is sent if any recipient is missing a
+    /// variable; if a later request fails, the error is chained onto
+    /// `ErrorKind::PartiallySent` with the responses of the requests already
+    /// sent.
+    pub fn send_template(
+        &self,
+        template: &Template,
+        recipients: &[TemplateRecipient],
+        from: Option<&str>,
+    ) -> Result<Vec<TemplateSend>> {
+        let mut sent = Vec::new();
+        for batch in template.render_all(recipients)? {
+            let to: Vec<&str> = batch.recipients.iter().map(|r| r.as_str()).collect();
+            match self.send_bulk_message(&to, &batch.text, from) {
+                Ok(response) => sent.push(TemplateSend { batch, response }),
+                Err(e) if sent.is_empty() => return Err(e),
+                Err(e) => {
+                    let responses = sent.into_iter().map(|s| s.response).collect();
+                    // raise error
+                    return Err(e).chain_err(|| ErrorKind::PartiallySent(responses));
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    fn recipient(phone_number: &str, name: &str, amount: &str) -> TemplateRecipient {
+        TemplateRecipient::new(phone_number)
+            .var("name", name)
+            .var("amount", amount)
+    }
+
+    fn template() -> Template {
+        Template::parse("Hi {name}, your balance is {amount} {{ok}}").unwrap()
+    }
+
+    #[test]
+    fn parses_variables_and_escapes() {
+        let template = template();
+        assert_eq!(template.variables(), vec!["name", "amount"]);
+        let text = template.render(&recipient("+254711000001", "Amina", "KES 50"));
+        assert_eq!(text.unwrap(), "Hi Amina, your balance is KES 50 {ok}");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for text in &["Hi {name", "Hi {}", "Hi name}"] {
+            match Template::parse(text) {
+                Err(Error(ErrorKind::InvalidTemplate(t), _)) => assert_eq!(&t, text),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn groups_recipients_with_identical_text() {
+        let recipients = vec![
+            recipient("+254711000001", "Amina", "KES 50"),
+            recipient("+254711000002", "Amina", "KES 50"),
+            recipient("+254711000003", "Baraka", "KES 75"),
+        ];
+        let batches = template().render_all(&recipients).unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].recipients.len(), 2);
+        assert_eq!(batches[1].segments, 1);
+    }
+
+    #[test]
+    fn fails_on_missing_variable() {
+        let missing = vec![TemplateRecipient::new("+254711000004").var("name", "Chebet")];
+        match template().render_all(&missing) {
+            Err(Error(ErrorKind::MissingTemplateVariable(_, name), _)) => {
+                assert_eq!(name, "amount")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn returns_sent_responses_when_a_later_send_fails() {
+        let response = r#"{"SMSMessageData":{"Message":"Sent to 1/1","Recipients":[]}}"#;
+        let (url, _) = ::tests::serve(&[(201, response), (500, "Internal Server Error")]);
+        let recipients = vec![
+            recipient("+254711000001", "Amina", "KES 50"),
+            recipient("+254711000003", "Baraka", "KES 75"),
+        ];
+
+        match ::tests::gateway(&url).send_template(&template(), &recipients, None) {
+            Err(Error(ErrorKind::PartiallySent(responses), _)) => assert_eq!(responses.len(), 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn counts_segments() {
+        assert_eq!(segments(&"a".repeat(160)), 1);
+        assert_eq!(segments(&"a".repeat(161)), 2);
+        assert_eq!(segments(&"€".repeat(80)), 1);
+        assert_eq!(segments(&"€".repeat(81)), 2);
+        assert_eq!(segments(&"ş".repeat(70)), 1);
+        assert_eq!(segments(&"ş".repeat(71)), 2);
+    }
+}
@@ -0,0 +1,184 @@
+//! Token API: checkout tokens and auth tokens.
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::{Accept, Headers};
+use reqwest;
+
+use super::{AfricasTalkingGateway, Apikey, ErrorKind, Result};
+use secret::{redact, SecretString, REDACTED};
+
+/// Auth tokens are refreshed this long before they expire.
+const REFRESH_MARGIN_SECS: u64 = 60;
+
+/// Checkout Token Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct CheckoutToken {
+    /// request description, `"Success"` when a token was created
+    pub description: String,
+
+    /// the checkout token
+    #[serde(default)]
+    pub token: String,
+}
+
+/// Auth Token Struct
+///
+/// Its `Debug` output hides the token.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct AuthToken {
+    /// the auth token, sent in place of the API key
+    pub token: String,
+
+    /// how long the token is valid for
+    pub lifetimeInSeconds: u64,
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuthToken")
+            .field("token", &format_args!("{}", REDACTED))
+            .field("lifetimeInSeconds", &self.lifetimeInSeconds)
+            .finish()
+    }
+}
+
+/// Caches the current auth token for a gateway.
+#[derive(Debug, Default)]
+pub(crate) struct TokenCache {
+    current: Mutex<Option<(SecretString, Instant)>>,
+}
+
+impl TokenCache {
+    /// Returns the cached token, generating a new one if there is none or
+    /// it is about to expire.
+    pub(crate) fn token(&self, gateway: &AfricasTalkingGateway) -> Result<SecretString> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((ref token, expires)) = *current {
+            if Instant::now() + Duration::from_secs(REFRESH_MARGIN_SECS) < expires {
+                return Ok(token.clone());
+            }
+        }
+        let auth = gateway.generate_auth_token()?;
+        let expires = Instant::now() + Duration::from_secs(auth.lifetimeInSeconds);
+        let token = SecretString::from(auth.token);
+        *current = Some((token.clone(), expires));
+
+        Ok(token)
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Creates a checkout token for a phone number, as required when
+    /// creating premium SMS subscriptions.
+    /// [read more..](http://docs.africastalking.com/tokens/checkout)
+    pub fn create_checkout_token(&self, phone_number: &str) -> Result<CheckoutToken> {
+        let params = json!({
+            "phoneNumber": phone_number
+        });
+        let mut resp = self.send_form_data(&self.checkout_token_url, params)?;
+        let code = resp.status().as_u16();
+        if code == 200 || code == 201 {
+            let token: CheckoutToken = resp.json()?;
+            if token.description == "Success" && !token.token.is_empty() {
+                Ok(token)
+            } else {
+                // raise error
+                Err(ErrorKind::GatewayError(token.description).into())
+            }
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+        }
+    }
+
+    /// Generates a short-lived auth token that can be used in place of the
+    /// API key. [read more..](http://docs.africastalking.com/tokens/auth)
+    ///
+    /// This always authenticates with the API key, even on a gateway
+    /// created `with_auth_token`.
+    pub fn generate_auth_token(&self) -> Result<AuthToken> {
+        let params = json!({
+            "username": self.username
+        });
+        let mut headers = Headers::new();
+        headers.set(Accept::json());
+        headers.set(Apikey(self.api_key.clone()));
+        let client = reqwest::Client::new();
+        let mut resp = client
+            .post(&self.auth_token_url)
+            .json(&params)
+            .headers(headers)
+            .send()?;
+        let code = resp.status().as_u16();
+        if code == 200 || code == 201 {
+            let token: AuthToken = resp.json()?;
+            Ok(token)
+        } else {
+            // raise error
+            Err(ErrorKind::GatewayError(redact(&resp.text()?)).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hides_auth_token_in_debug() {
+        let token = AuthToken {
+            token: "ATtkn_0123456789".into(),
+            lifetimeInSeconds: 3600,
+        };
+        assert_eq!(
+            format!("{:?}", token),
+            "AuthToken { token: [REDACTED], lifetimeInSeconds: 3600 }"
+        );
+    }
+
+    #[test]
+    fn caches_auth_token() {
+        let response = r#"{"token":"ATtkn_0123456789","lifetimeInSeconds":3600}"#;
+        let (url, requests) = ::tests::serve(&[(201, response)]);
+        let gway = ::tests::gateway(&url);
+        let cache = TokenCache::default();
+
+        assert_eq!(cache.token(&gway).unwrap().expose(), "ATtkn_0123456789");
+        assert_eq!(cache.token(&gway).unwrap().expose(), "ATtkn_0123456789");
+        assert!(requests.recv().unwrap().contains("apikey: key"));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn sends_auth_token_instead_of_api_key() {
+        let token = r#"{"token":"ATtkn_0123456789","lifetimeInSeconds":3600}"#;
+        let (url, requests) = ::tests::serve(&[(201, token), (200, r#"{"UserData":{}}"#)]);
+        let gway = ::tests::gateway(&url).with_auth_token();
+
+        gway.get_user_data().unwrap();
+        requests.recv().unwrap();
+        let request = requests.recv().unwrap().to_lowercase();
+        assert!(request.contains("authtoken: attkn_0123456789"));
+        assert!(!request.contains("apikey"));
+    }
+
+    #[test]
+    fn auth_token_fails_on_error_response() {
+        let (url, _) = ::tests::serve(&[(401, "The supplied authentication is invalid")]);
+        assert!(::tests::gateway(&url).generate_auth_token().is_err());
+    }
+
+    #[test]
+    fn checkout_token_fails_unless_created() {
+        let response = r#"{"description":"Failure","token":""}"#;
+        let (url, _) = ::tests::serve(&[(201, response)]);
+        let err = ::tests::gateway(&url)
+            .create_checkout_token("+254711000001")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Failure");
+    }
+}
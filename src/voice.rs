@@ -0,0 +1,452 @@
+//! Voice API types, call transfers, voice callbacks and XML responses.
+use std::fmt;
+
+use json;
+use serde_urlencoded;
+
+use super::{AfricasTalkingGateway, ErrorKind, Result};
+use media::MediaRegistry;
+use money::Money;
+use secret::redact;
+
+/// Call Entry Struct
+///
+/// One entry is returned per destination of an outbound call.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct CallEntry {
+    /// destination phone number
+    pub phoneNumber: String,
+
+    /// call status e.g. `Queued`, `InvalidPhoneNumber`
+    pub status: String,
+
+    /// session id of the call, used to match voice callbacks
+    #[serde(default)]
+    pub sessionId: Option<String>,
+}
+
+impl CallEntry {
+    /// true if the call was accepted for dialing
+    pub fn is_queued(&self) -> bool {
+        self.status == "Queued"
+    }
+}
+
+/// Queue Status Struct
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct QueueStatus {
+    /// the virtual number callers are queued on
+    pub phoneNumber: String,
+
+    /// queue name, if the number has named queues
+    #[serde(default)]
+    pub queueName: Option<String>,
+
+    /// number of calls waiting in the queue
+    #[serde(default)]
+    pub numCalls: i32,
+}
+
+/// Call leg to transfer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallLeg {
+    /// the party that placed the call
+    Caller,
+    /// the party that received the call
+    Callee,
+}
+
+/// Voice Notification Struct
+///
+/// Posted as form data to the application's voice callback URL while a
+/// call is in progress and once more, with `isActive` set to `0`, when it
+/// ends.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[allow(non_snake_case)]
+pub struct VoiceNotification {
+    /// `1` while the call is in progress, `0` once it has ended
+    pub isActive: String,
+
+    /// session id of the call
+    pub sessionId: String,
+
+    /// `Inbound` or `Outbound`
+    #[serde(default)]
+    pub direction: String,
+
+    /// the calling phone number
+    #[serde(default)]
+    pub callerNumber: String,
+
+    /// the called phone number
+    #[serde(default)]
+    pub destinationNumber: String,
+
+    /// digits pressed in response to a `GetDigits` action
+    #[serde(default)]
+    pub dtmfDigits: Option<String>,
+
+    /// URL of the call recording, if recorded
+    #[serde(default)]
+    pub recordingUrl: Option<String>,
+
+    /// call duration, sent when the call ends
+    #[serde(default)]
+    pub durationInSeconds: Option<String>,
+
+    /// currency the call was charged in, sent when the call ends
+    #[serde(default)]
+    pub currencyCode: Option<String>,
+
+    /// call cost, sent when the call ends
+    #[serde(default)]
+    pub amount: Option<String>,
+
+    /// why the call ended e.g. `NORMAL_CLEARING`, `NO_ANSWER`, `USER_BUSY`
+    #[serde(default)]
+    pub hangupCause: Option<String>,
+
+    /// final call state e.g. `Completed`
+    #[serde(default)]
+    pub callSessionState: Option<String>,
+
+    /// client request id given when the call was made
+    #[serde(default)]
+    pub clientRequestId: Option<String>,
+}
+
+impl VoiceNotification {
+    /// Parses the form encoded body of a voice callback.
+    pub fn from_form(body: &str) -> Result<Self> {
+        Ok(serde_urlencoded::from_str(body)?)
+    }
+
+    /// true while the call is in progress
+    pub fn is_active(&self) -> bool {
+        self.isActive == "1"
+    }
+
+    /// call duration in seconds, once the call has ended
+    pub fn duration(&self) -> Option<u32> {
+        self.durationInSeconds.as_ref()?.parse().ok()
+    }
+
+    /// call cost, once the call has ended
+    pub fn cost(&self) -> Option<Money> {
+        let currency = self.currencyCode.as_ref()?;
+        Money::parse_amount(currency, self.amount.as_ref()?).ok()
+    }
+}
+
+/// Voice XML action
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// plays the audio file at `url`
+    Play {
+        /// URL of an uploaded media file
+        url: String,
+    },
+    /// reads `text` out with text-to-speech
+    Say {
+        /// the text to read
+        text: String,
+        /// voice to use, `man` or `woman`
+        voice: Option<String>,
+    },
+}
+
+/// Voice XML Response
+///
+/// Returned from the voice callback to tell the API what to do with a call.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VoiceResponse {
+    /// actions, performed in order
+    pub actions: Vec<Action>,
+}
+
+impl VoiceResponse {
+    /// creates an empty response
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a `Play` action for the media file at `url`
+    pub fn play(mut self, url: &str) -> Self {
+        self.actions.push(Action::Play { url: url.into() });
+        self
+    }
+
+    /// adds a `Say` action reading out `text`
+    pub fn say(mut self, text: &str) -> Self {
+        self.actions.push(Action::Say {
+            text: text.into(),
+            voice: None,
+        });
+        self
+    }
+
+    /// URLs of the media files the response plays
+    pub fn media_urls(&self) -> Vec<&str> {
+        self.actions
+            .iter()
+            .filter_map(|action| match *action {
+                Action::Play { ref url } => Some(url.as_str()),
+                Action::Say { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Media files played by the response that are not in `registry`.
+    pub fn unregistered_media(&self, registry: &MediaRegistry) -> Vec<String> {
+        self.media_urls()
+            .into_iter()
+            .filter(|url| !registry.contains(url))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Fails with `ErrorKind::UnregisteredMedia` if the response plays
+    /// files that have not been uploaded.
+    pub fn check_media(&self, registry: &MediaRegistry) -> Result<()> {
+        let urls = self.unregistered_media(registry);
+        if urls.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorKind::UnregisteredMedia(urls).into())
+        }
+    }
+
+    /// the response as voice XML
+    pub fn to_xml(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for VoiceResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response>")?;
+        for action in &self.actions {
+            match *action {
+                Action::Play { ref url } => write!(f, "<Play url=\"{}\"/>", escape_xml(url))?,
+                Action::Say {
+                    ref text,
+                    ref voice,
+                } => match *voice {
+                    Some(ref voice) => write!(
+                        f,
+                        "<Say voice=\"{}\">{}</Say>",
+                        escape_xml(voice),
+                        escape_xml(text)
+                    )?,
+                    None => write!(f, "<Say>{}</Say>", escape_xml(text))?,
+                },
+            }
+        }
+        write!(f, "</Response>")
+    }
+}
+
+/// Escapes text for use in XML content and attribute values.
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Fails with the response's `errorMessage` unless it is missing or `"None"`.
+pub(crate) fn check_voice_error(jsn: &json::Value) -> Result<()> {
+    match jsn["errorMessage"].as_str() {
+        None | Some("None") => Ok(()),
+        Some(e) => Err(ErrorKind::GatewayError(e.to_string()).into()),
+    }
+}
+
+impl AfricasTalkingGateway {
+    /// Transfers an active call to another phone number.
+    /// [read more..](http://docs.africastalking.com/voice/calltransfer)
+    ///
+    /// `call_leg` defaults to the callee, and `hold_music_url` is played to
+    /// the other party while the transfer connects.
+    pub fn transfer_call(
+        &self,
+        session_id: &str,
+        phone_number: &str,
+        call_leg: Option<CallLeg>,
+        hold_music_url: Option<&str>,
+    ) -> Result<()> {
+        let mut params = json!({
+            "username": self.username,
+            "sessionId": session_id,
+            "phoneNumber": phone_number
+        });
+        if let Some(leg) = call_leg {
+            params["callLeg"] = json::to_value(leg)?;
+        }
+        if let Some(url) = hold_music_url {
+            params["holdMusicUrl"] = json!(url);
+        }
+        let url = format!("{}/callTransfer", self.voice_url);
+        let mut resp = self.send_form_data(&url, params)?;
+        let jsn: json::Value = resp.json()?;
+        check_voice_error(&jsn)?;
+        if jsn["status"] == "Success" {
+            Ok(())
+        } else {
+            // raise error
+            let message = jsn["description"]
+                .as_str()
+                .or_else(|| jsn["status"].as_str())
+                .unwrap_or_default();
+            Err(ErrorKind::GatewayError(redact(message)).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_call_entries() {
+        let jsn = json!({
+            "entries": [
+                {"phoneNumber": "+254711XXXYYY", "status": "Queued", "sessionId": "ATVId_1"},
+                {"phoneNumber": "+2547", "status": "InvalidPhoneNumber"}
+            ],
+            "errorMessage": "None"
+        });
+        assert!(check_voice_error(&jsn).is_ok());
+        let entries: Vec<CallEntry> = json::from_value(jsn["entries"].clone()).unwrap();
+        assert!(entries[0].is_queued());
+        assert_eq!(entries[1].sessionId, None);
+    }
+
+    #[test]
+    fn reports_voice_error_message() {
+        let err = check_voice_error(&json!({"errorMessage": "Invalid callerId"})).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid callerId");
+    }
+
+    #[test]
+    fn call_sends_destinations_and_request_id() {
+        let (url, requests) = ::tests::serve(&[(
+            200,
+            r#"{"entries":[
+                {"phoneNumber":"+254711000001","status":"Queued","sessionId":"ATVId_1"},
+                {"phoneNumber":"+254711000002","status":"Queued","sessionId":"ATVId_2"}],
+                "errorMessage":"None"}"#,
+        )]);
+        let to = ["+254711000001", "+254711000002"];
+        let entries = ::tests::gateway(&url)
+            .call("+254711000000", &to, Some("order-42"))
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        let request = requests.recv().unwrap();
+        assert!(request.contains("to=%2B254711000001%2C%2B254711000002"));
+        assert!(request.contains("clientRequestId=order-42"));
+    }
+
+    #[test]
+    fn call_fails_on_error_message() {
+        let (url, _) =
+            ::tests::serve(&[(200, r#"{"entries":[],"errorMessage":"Invalid callerId"}"#)]);
+        let err = ::tests::gateway(&url)
+            .call("+254711000000", &["+254711000001"], None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid callerId");
+    }
+
+    #[test]
+    fn transfer_sends_call_leg_and_hold_music() {
+        let (url, requests) = ::tests::serve(&[(200, r#"{"status":"Success"}"#)]);
+        ::tests::gateway(&url)
+            .transfer_call(
+                "ATVId_1",
+                "+254711000002",
+                Some(CallLeg::Caller),
+                Some("https://example.com/hold.mp3"),
+            )
+            .unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.contains("/callTransfer"));
+        assert!(request.contains("callLeg=caller"));
+        assert!(request.contains("holdMusicUrl=https%3A%2F%2Fexample.com%2Fhold.mp3"));
+    }
+
+    #[test]
+    fn transfer_fails_unless_successful() {
+        let (url, _) = ::tests::serve(&[(200, r#"{"status":"Aborted"}"#)]);
+        let result = ::tests::gateway(&url).transfer_call("ATVId_1", "+254711000002", None, None);
+        assert_eq!(result.unwrap_err().to_string(), "Aborted");
+
+        let (url, _) = ::tests::serve(&[(
+            200,
+            r#"{"status":"Aborted","description":"Call not active"}"#,
+        )]);
+        let result = ::tests::gateway(&url).transfer_call("ATVId_1", "+254711000002", None, None);
+        assert_eq!(result.unwrap_err().to_string(), "Call not active");
+
+        let (url, _) = ::tests::serve(&[(200, r#"{"errorMessage":"Invalid sessionId"}"#)]);
+        let result = ::tests::gateway(&url).transfer_call("ATVId_1", "+254711000002", None, None);
+        assert_eq!(result.unwrap_err().to_string(), "Invalid sessionId");
+    }
+
+    #[test]
+    fn parses_queued_calls() {
+        let (url, _) = ::tests::serve(&[(
+            200,
+            r#"{"entries":[{"phoneNumber":"+254711000000","queueName":"support",
+                "numCalls":3}],"errorMessage":"None"}"#,
+        )]);
+        let queues = ::tests::gateway(&url)
+            .get_queued_calls("+254711000000", Some("support"))
+            .unwrap();
+        assert_eq!(queues[0].queueName.as_deref(), Some("support"));
+        assert_eq!(queues[0].numCalls, 3);
+    }
+
+    #[test]
+    fn parses_call_ended_notification() {
+        let body = "isActive=0&sessionId=ATVId_1&direction=Outbound\
+                    &callerNumber=%2B254711000000&destinationNumber=%2B254711XXXYYY\
+                    &durationInSeconds=42&currencyCode=KES&amount=1.5\
+                    &hangupCause=NORMAL_CLEARING&callSessionState=Completed";
+        let notification = VoiceNotification::from_form(body).unwrap();
+        assert!(!notification.is_active());
+        assert_eq!(notification.duration(), Some(42));
+        assert_eq!(notification.cost(), Some("KES 1.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn flags_unregistered_media() {
+        let mut registry = MediaRegistry::new();
+        registry.insert("https://example.com/welcome.mp3");
+        let response = VoiceResponse::new()
+            .play("https://example.com/welcome.mp3")
+            .play("https://example.com/menu.mp3?a=1&b=2");
+
+        assert_eq!(
+            response.to_xml(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response>\
+             <Play url=\"https://example.com/welcome.mp3\"/>\
+             <Play url=\"https://example.com/menu.mp3?a=1&amp;b=2\"/></Response>"
+        );
+        assert_eq!(
+            response.unregistered_media(&registry),
+            vec!["https://example.com/menu.mp3?a=1&b=2".to_string()]
+        );
+        assert!(response.check_media(&registry).is_err());
+    }
+}